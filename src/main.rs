@@ -1,39 +1,286 @@
-use nix::sys::signal::{SigHandler, Signal, signal};
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, FdFlag, OFlag, fcntl, open};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::resource::{UsageWho, getrusage};
+use nix::sys::signal::{
+    SaFlags, SigAction, SigHandler, SigSet, Signal, kill, killpg, raise, sigaction, signal,
+};
+use nix::sys::stat::Mode;
+use nix::sys::termios::{
+    LocalFlags, SetArg, SpecialCharacterIndices, Termios, tcgetattr, tcsetattr,
+};
 use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
 use nix::unistd::{
-    ForkResult, Pid, chdir, execvp, fork, getcwd, getpid, setpgid, tcsetpgrp, write,
+    AccessFlags, ForkResult, Pid, User, access, chdir, execve, fork, getcwd, getpid, getuid,
+    isatty, pipe2, read, setpgid, tcgetpgrp, tcsetpgrp, write,
 };
 use regex::Regex;
+use std::cell::{Cell, OnceCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::CString;
-use std::io::{BufRead, Write};
-use std::path::PathBuf;
+use std::fmt;
+use std::io::{Read, Seek, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 enum Command {
     Builtin(BuiltinCommand),
     External(ExternalCommand),
+    Function(Rc<Node>, Vec<String>),
+}
+
+impl Command {
+    fn from_args(args: Vec<String>) -> Self {
+        match BuiltinCommand::from_args(args) {
+            Ok(builtin) => Command::Builtin(builtin),
+            Err(args) => Command::External(ExternalCommand::new(args[0].clone(), args)),
+        }
+    }
 }
 
 enum BuiltinCommand {
-    Exit,
+    Exit(Vec<String>),
     Cd(Vec<String>),
+    Wait(Vec<String>),
+    Jobs,
+    Conditional(Vec<String>),
+    Export(Vec<String>),
+    Readonly(Vec<String>),
+    Echo(Vec<String>),
+    Printf(Vec<String>),
+    Builtin(Vec<String>),
+    Source(Vec<String>),
+    Set(Vec<String>),
+    Shift(Vec<String>),
+    Local(Vec<String>),
+    Return(Vec<String>),
+    Help(Vec<String>),
+    Trap(Vec<String>),
+    Read(Vec<String>),
+    Exec(Vec<String>),
+    Declare(Vec<String>),
+    Command(Vec<String>),
+    Enable(Vec<String>),
+    Fg(Vec<String>),
+    Bg(Vec<String>),
+    Kill(Vec<String>),
+    History,
+    Hash(Vec<String>),
+    Alias(Vec<String>),
+    Unalias(Vec<String>),
+    Disown(Vec<String>),
+    Caller(Vec<String>),
+    Shopt(Vec<String>),
+    Colon,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl BuiltinCommand {
+    // the builtin table, hands the arguments back if the name isn't a builtin
+    fn from_args(args: Vec<String>) -> Result<Self, Vec<String>> {
+        Ok(match args[0].as_str() {
+            "exit" | "logout" => BuiltinCommand::Exit(args),
+            "cd" => BuiltinCommand::Cd(args),
+            "wait" => BuiltinCommand::Wait(args),
+            "jobs" => BuiltinCommand::Jobs,
+            "[[" => BuiltinCommand::Conditional(args),
+            "export" => BuiltinCommand::Export(args),
+            "readonly" => BuiltinCommand::Readonly(args),
+            "echo" => BuiltinCommand::Echo(args),
+            "printf" => BuiltinCommand::Printf(args),
+            "builtin" => BuiltinCommand::Builtin(args),
+            "source" | "." => BuiltinCommand::Source(args),
+            "set" => BuiltinCommand::Set(args),
+            "shift" => BuiltinCommand::Shift(args),
+            "local" => BuiltinCommand::Local(args),
+            "return" => BuiltinCommand::Return(args),
+            "help" => BuiltinCommand::Help(args),
+            "trap" => BuiltinCommand::Trap(args),
+            "read" => BuiltinCommand::Read(args),
+            "exec" => BuiltinCommand::Exec(args),
+            "declare" => BuiltinCommand::Declare(args),
+            "command" => BuiltinCommand::Command(args),
+            "enable" => BuiltinCommand::Enable(args),
+            "fg" => BuiltinCommand::Fg(args),
+            "bg" => BuiltinCommand::Bg(args),
+            "kill" => BuiltinCommand::Kill(args),
+            "history" => BuiltinCommand::History,
+            "hash" => BuiltinCommand::Hash(args),
+            "alias" => BuiltinCommand::Alias(args),
+            "unalias" => BuiltinCommand::Unalias(args),
+            "disown" => BuiltinCommand::Disown(args),
+            "caller" => BuiltinCommand::Caller(args),
+            "shopt" => BuiltinCommand::Shopt(args),
+            ":" => BuiltinCommand::Colon,
+            _ => return Err(args),
+        })
+    }
+
+    // every name the table above knows, offered by completion
+    const NAMES: [&str; 35] = [
+        "exit", "logout", "cd", "wait", "jobs", "fg", "bg", "kill", "[[", "export", "readonly",
+        "echo", "printf", "builtin", "source", ".", "set", "shift", "local", "return", "help",
+        "trap", "read", "exec", "declare", "command", "enable", "history", "hash", "alias",
+        "unalias", "disown", "caller", "shopt", ":",
+    ];
+
+    // the special builtins of POSIX: assignments in front of them stay in the shell, in
+    // front of the regular ones they only last for the command like for any other
+    fn is_special(&self) -> bool {
+        match self {
+            BuiltinCommand::Colon
+            | BuiltinCommand::Export(_)
+            | BuiltinCommand::Readonly(_)
+            | BuiltinCommand::Return(_)
+            | BuiltinCommand::Set(_)
+            | BuiltinCommand::Shift(_)
+            | BuiltinCommand::Trap(_)
+            | BuiltinCommand::Exec(_) => true,
+            BuiltinCommand::Exit(args) => args[0] == "exit",
+            BuiltinCommand::Source(args) => args[0] == ".",
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Quoting {
     Unquoted,
     SingleQuoted,
     DoubleQuoted,
+    // the list of a <(...) or >(...) process substitution
+    ProcessIn,
+    ProcessOut,
+    // the list of a $(...) command substitution, and of one inside double quotes
+    Command,
+    QuotedCommand,
+    // the output of an unquoted $(...), split into fields but not expanded again
+    Expanded,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Operator {
     And,
     Or,
     Pipe,
     Andpercent,
     Semicolon,
+    RedirectIn,
+    RedirectOut,
+    RedirectAppend,
+    RedirectAll,
+    RedirectAppendAll,
+    DuplicateIn,
+    DuplicateOut,
+    HereDoc,
+    HereDocStrip,
+    HereString,
+    LeftParen,
+    RightParen,
+    CaseBreak,
+    CaseFallthrough,
+    CaseContinue,
+}
+
+impl Operator {
+    const ALL: [Operator; 20] = [
+        Operator::And,
+        Operator::Or,
+        Operator::Pipe,
+        Operator::Andpercent,
+        Operator::Semicolon,
+        Operator::RedirectIn,
+        Operator::RedirectOut,
+        Operator::RedirectAppend,
+        Operator::RedirectAll,
+        Operator::RedirectAppendAll,
+        Operator::DuplicateIn,
+        Operator::DuplicateOut,
+        Operator::HereDoc,
+        Operator::HereDocStrip,
+        Operator::HereString,
+        Operator::LeftParen,
+        Operator::RightParen,
+        Operator::CaseBreak,
+        Operator::CaseFallthrough,
+        Operator::CaseContinue,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operator::And => "&&",
+            Operator::Or => "||",
+            Operator::Pipe => "|",
+            Operator::Andpercent => "&",
+            Operator::Semicolon => ";",
+            Operator::RedirectIn => "<",
+            Operator::RedirectOut => ">",
+            Operator::RedirectAppend => ">>",
+            Operator::RedirectAll => "&>",
+            Operator::RedirectAppendAll => "&>>",
+            Operator::DuplicateIn => "<&",
+            Operator::DuplicateOut => ">&",
+            Operator::HereDoc => "<<",
+            Operator::HereDocStrip => "<<-",
+            Operator::HereString => "<<<",
+            Operator::LeftParen => "(",
+            Operator::RightParen => ")",
+            Operator::CaseBreak => ";;",
+            Operator::CaseFallthrough => ";&",
+            Operator::CaseContinue => ";;&",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Operator::And => "run the next pipeline only if the previous one succeeded",
+            Operator::Or => "run the next pipeline only if the previous one failed",
+            Operator::Pipe => "connect stdout of the left command to stdin of the right one",
+            Operator::Andpercent => "run the preceding list in the background",
+            Operator::Semicolon => "run commands one after another",
+            Operator::RedirectIn => "read stdin (or fd N in N<) from a file",
+            Operator::RedirectOut => "write stdout (or fd N in N>) to a file, truncating it",
+            Operator::RedirectAppend => "append stdout (or fd N in N>>) to a file",
+            Operator::RedirectAll => "write stdout and stderr to a file, truncating it",
+            Operator::RedirectAppendAll => "append stdout and stderr to a file",
+            Operator::DuplicateIn => {
+                "make stdin (or fd N) a copy of fd M with <&M, close it with <&-"
+            }
+            Operator::DuplicateOut => {
+                "make stdout (or fd N) a copy of fd M with >&M, close it with >&-"
+            }
+            Operator::HereDoc => "read stdin from the following lines up to the delimiter line",
+            Operator::HereDocStrip => "like << but with leading tabs removed from each line",
+            Operator::HereString => "read stdin from the following word and a newline",
+            Operator::LeftParen => "start a subshell, or the () of a function definition",
+            Operator::RightParen => "end a subshell, or the patterns of a case clause",
+            Operator::CaseBreak => "end a case clause",
+            Operator::CaseFallthrough => "end a case clause, running the next clause's body too",
+            Operator::CaseContinue => "end a case clause, testing the following clauses too",
+        }
+    }
+
+    fn is_redirect(&self) -> bool {
+        matches!(
+            self,
+            Operator::RedirectIn
+                | Operator::RedirectOut
+                | Operator::RedirectAppend
+                | Operator::RedirectAll
+                | Operator::RedirectAppendAll
+                | Operator::DuplicateIn
+                | Operator::DuplicateOut
+                | Operator::HereDoc
+                | Operator::HereDocStrip
+                | Operator::HereString
+        )
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -41,24 +288,355 @@ enum Token {
     Word(String, Quoting),
     Operator(Operator),
     Whitespace,
+    // with how many lines of the input have ended, here-doc bodies and newlines in quotes
+    // included
+    Newline(usize),
+    // the input ended inside a here-doc body or right after a line continuation
+    Unterminated,
+}
+
+// a shell word made of adjacent tokens, e.g. 'hello'"world" is a single word
+struct Word {
+    parts: Vec<(String, Quoting)>,
+}
+
+impl Word {
+    // the text of a word made of a single unquoted part
+    fn literal(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [(part, Quoting::Unquoted)] => Some(part),
+            _ => None,
+        }
+    }
+
+    fn is_literal(&self, text: &str) -> bool {
+        self.literal() == Some(text)
+    }
+
+    fn has_substitution(&self) -> bool {
+        self.parts.iter().any(|(_, quoting)| {
+            matches!(
+                quoting,
+                Quoting::ProcessIn
+                    | Quoting::ProcessOut
+                    | Quoting::Command
+                    | Quoting::QuotedCommand
+            )
+        })
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (text, quoting) in &self.parts {
+            match quoting {
+                Quoting::Unquoted => write!(f, "{}", text)?,
+                Quoting::SingleQuoted => write!(f, "'{}'", text)?,
+                Quoting::DoubleQuoted => write!(f, "\"{}\"", text)?,
+                Quoting::ProcessIn => write!(f, "<({})", text)?,
+                Quoting::ProcessOut => write!(f, ">({})", text)?,
+                Quoting::Command => write!(f, "$({})", text)?,
+                Quoting::QuotedCommand => write!(f, "\"$({})\"", text)?,
+                Quoting::Expanded => write!(f, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// a piece of an expanded word on its way to becoming fields
+enum Piece {
+    // text that goes into the field as it is, and whether it was quoted
+    Text(String, bool),
+    // the value of an unquoted expansion, split into fields at the characters of $IFS
+    Split(String),
+    // where one parameter of a quoted "$@" ends and the next one's field begins
+    Break,
+}
+
+struct Redirect {
+    fd: i32,
+    operator: Operator,
+    target: Word,
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operator {
+            Operator::RedirectIn | Operator::DuplicateIn if self.fd == 0 => {}
+            Operator::RedirectOut | Operator::RedirectAppend | Operator::DuplicateOut
+                if self.fd == 1 => {}
+            Operator::RedirectAll | Operator::RedirectAppendAll => {}
+            _ => write!(f, "{}", self.fd)?,
+        }
+        write!(f, "{}{}", self.operator.as_str(), self.target)
+    }
+}
+
+#[derive(Default)]
+struct SimpleCommand {
+    words: Vec<Word>,
+    redirects: Vec<Redirect>,
+    // the line of the script the command starts on, for $LINENO
+    line: usize,
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words: Vec<String> = self
+            .words
+            .iter()
+            .map(|word| word.to_string())
+            .chain(self.redirects.iter().map(|redirect| redirect.to_string()))
+            .collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+// a stage of a pipeline
+enum Node {
+    Simple(SimpleCommand),
+    // if/elif branches as (condition, body) pairs and the else body
+    If {
+        branches: Vec<(Vec<AndOrList>, Vec<AndOrList>)>,
+        otherwise: Option<Vec<AndOrList>>,
+    },
+    // runs the body while the condition succeeds, or fails for until
+    While {
+        condition: Vec<AndOrList>,
+        body: Vec<AndOrList>,
+        until: bool,
+    },
+    // clauses as (patterns, body, terminator), the terminator is one of ;; ;& and ;;&
+    Case {
+        subject: Word,
+        clauses: Vec<(Vec<Word>, Vec<AndOrList>, Operator)>,
+    },
+    // { list; } runs in the shell itself, ( list ) in a forked subshell
+    Group(Vec<AndOrList>),
+    Subshell(Vec<AndOrList>),
+    // defining a function stores the body, it's shared with every call
+    Function(String, Rc<Node>),
+    // a compound command followed by redirections, e.g. `while ...; done > file`
+    Redirected(Box<Node>, Vec<Redirect>),
+}
+
+fn format_lists(lists: &[AndOrList]) -> String {
+    let lists: Vec<String> = lists
+        .iter()
+        .map(|list| {
+            let terminator = if list.background { " &" } else { ";" };
+            format!("{}{}", list, terminator)
+        })
+        .collect();
+    lists.join(" ")
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Simple(command) => write!(f, "{}", command),
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                for (index, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if index == 0 { "if" } else { "elif" };
+                    write!(
+                        f,
+                        "{} {} then {} ",
+                        keyword,
+                        format_lists(condition),
+                        format_lists(body)
+                    )?;
+                }
+                if let Some(body) = otherwise {
+                    write!(f, "else {} ", format_lists(body))?;
+                }
+                write!(f, "fi")
+            }
+            Node::While {
+                condition,
+                body,
+                until,
+            } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(
+                    f,
+                    "{} {} do {} done",
+                    keyword,
+                    format_lists(condition),
+                    format_lists(body)
+                )
+            }
+            Node::Case { subject, clauses } => {
+                write!(f, "case {} in ", subject)?;
+                for (patterns, body, terminator) in clauses {
+                    let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+                    write!(f, "{}) ", patterns.join(" | "))?;
+                    if !body.is_empty() {
+                        let body = format_lists(body);
+                        write!(f, "{} ", body.trim_end_matches(';'))?;
+                    }
+                    write!(f, "{} ", terminator.as_str())?;
+                }
+                write!(f, "esac")
+            }
+            Node::Group(lists) => write!(f, "{{ {} }}", format_lists(lists)),
+            Node::Subshell(lists) => {
+                let lists = format_lists(lists);
+                write!(f, "( {} )", lists.trim_end_matches(';'))
+            }
+            Node::Function(name, body) => write!(f, "{}() {}", name, body),
+            Node::Redirected(node, redirects) => {
+                write!(f, "{}", node)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// commands connected by |, every stage runs in its own process
+struct Pipeline {
+    commands: Vec<Node>,
+    // a leading ! inverts the status
+    negated: bool,
+    // a leading time reports how long the pipeline took
+    time: Option<TimeFormat>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TimeFormat {
+    // real, user and sys in minutes and seconds, each on a line of its own
+    Default,
+    // time -p, the same lines with plain seconds
+    Posix,
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let commands: Vec<String> = self.commands.iter().map(|c| c.to_string()).collect();
+        match self.time {
+            Some(TimeFormat::Default) => write!(f, "time ")?,
+            Some(TimeFormat::Posix) => write!(f, "time -p ")?,
+            None => {}
+        }
+        if self.negated {
+            write!(f, "! ")?;
+        }
+        write!(f, "{}", commands.join(" | "))
+    }
+}
+
+// pipelines joined by && and ||, optionally sent to the background with a trailing &
+struct AndOrList {
+    first: Pipeline,
+    rest: Vec<(Operator, Pipeline)>,
+    background: bool,
+}
+
+impl fmt::Display for AndOrList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (operator, command) in &self.rest {
+            write!(f, " {} {}", operator.as_str(), command)?;
+        }
+        Ok(())
+    }
+}
+
+// move the finished word out of the buffer, leaving room for a similarly sized next word
+// so the buffer doesn't have to grow from scratch for every word
+fn take_word(current: &mut String) -> String {
+    let word = std::mem::take(current);
+    current.reserve(word.len());
+    word
 }
 
 struct Parser {
     variable_regex: Regex,
 }
 
+// a parameter expansion as found by Parser::parameters, the word is what follows a :- or :
+// operator and text is the whole expansion
+struct Parameter<'t> {
+    caps: regex::Captures<'t>,
+    word: &'t str,
+    text: &'t str,
+}
+
 impl Parser {
     fn new() -> Self {
-        let variable_regex = Regex::new(r"\$([a-zA-Z0-9_]+|\$|!)").unwrap();
+        // $name, ${name}, ${!name} for indirection, ${!prefix*} for the names with a prefix,
+        // ${name[key]} for array elements, ${name@op} to transform the value, ${name:-word}
+        // for a default and ${name:offset:length} for a substring. the match ends at the
+        // operator of the last two, parameters finds where their word ends
+        let variable_regex = Regex::new(concat!(
+            r"\$(?:([a-zA-Z_][a-zA-Z0-9_]*|[0-9]|[$!?#@*])",
+            r"|\{(!)?([a-zA-Z_][a-zA-Z0-9_]*|[0-9]+|[$!?#@*])(?:\[([^\]]*)\])?([*@])?",
+            r"(?:@([QULu])\}|(:-?)|\}))"
+        ))
+        .unwrap();
         Self { variable_regex }
     }
 
+    // the parameter expansions in text. the word after :- or : runs to the matching close
+    // brace, so it may hold expansions of its own as in ${name:-${other}}
+    fn parameters<'t>(&self, text: &'t str) -> Vec<Parameter<'t>> {
+        let mut parameters = Vec::new();
+        let mut position = 0;
+        while let Some(caps) = self.variable_regex.captures_at(text, position) {
+            let start = caps.get(0).unwrap().start();
+            let mut end = caps.get(0).unwrap().end();
+            let mut word = "";
+            if caps.get(7).is_some() {
+                let mut depth = 1;
+                let close = text[end..].find(|c| {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                });
+                // without a close brace it isn't an expansion at all
+                let Some(close) = close else {
+                    position = start + 1;
+                    continue;
+                };
+                word = &text[end..end + close];
+                end += close + 1;
+            }
+            parameters.push(Parameter {
+                caps,
+                word,
+                text: &text[start..end],
+            });
+            position = end;
+        }
+        parameters
+    }
+
     fn tokenize(&self, input: &str) -> Vec<Token> {
         let mut single_quotes = false;
         let mut double_quotes = false;
-        let mut chars = input.chars().peekable();
+        let lines = Cell::new(0);
+        let mut chars = input
+            .chars()
+            .inspect(|&c| {
+                if c == '\n' {
+                    lines.set(lines.get() + 1);
+                }
+            })
+            .peekable();
         let mut tokens: Vec<Token> = Vec::new();
         let mut current = String::new();
+        // here-docs whose bodies start after the current line: where the delimiter word
+        // starts in the tokens and whether tabs are stripped
+        let mut heredocs = Vec::new();
 
         while let Some(current_char) = chars.next() {
             // remove preceding whitespace
@@ -67,10 +645,21 @@ impl Parser {
             }
 
             match current_char {
+                '\n' if !single_quotes && !double_quotes => {
+                    if !current.trim().is_empty() {
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
+                    }
+                    current.clear();
+                    if !read_heredoc_bodies(&mut tokens, &mut chars, std::mem::take(&mut heredocs))
+                    {
+                        tokens.push(Token::Unterminated);
+                        return tokens;
+                    }
+                    tokens.push(Token::Newline(lines.get()));
+                }
                 _ if current_char.is_whitespace() && !single_quotes && !double_quotes => {
                     if !current.trim().is_empty() {
-                        tokens.push(Token::Word(current.clone(), Quoting::Unquoted));
-                        current.clear();
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
                     }
                     // only push if last token is not whitespace
                     match tokens.last() {
@@ -82,50 +671,150 @@ impl Parser {
                     }
                 }
                 '\'' if !double_quotes => {
-                    if !current.is_empty() {
+                    // a closing quote ends a part even if it's empty, as in ''
+                    if single_quotes || !current.is_empty() {
                         let quoting = if single_quotes {
                             Quoting::SingleQuoted
                         } else {
                             Quoting::Unquoted
                         };
-                        tokens.push(Token::Word(current.clone(), quoting));
-                        current.clear();
+                        tokens.push(Token::Word(take_word(&mut current), quoting));
                     }
                     single_quotes = !single_quotes;
                 }
                 '"' if !single_quotes => {
-                    if !current.is_empty() {
+                    if double_quotes || !current.is_empty() {
                         let quoting = if double_quotes {
                             Quoting::DoubleQuoted
                         } else {
                             Quoting::Unquoted
                         };
-                        tokens.push(Token::Word(current.clone(), quoting));
-                        current.clear();
+                        tokens.push(Token::Word(take_word(&mut current), quoting));
                     }
                     double_quotes = !double_quotes;
                 }
                 '&' if !single_quotes && !double_quotes => {
                     if !current.trim().is_empty() {
-                        tokens.push(Token::Word(current.clone(), Quoting::Unquoted));
-                        current.clear();
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
                     }
-                    if let Some(&ch) = chars.peek() {
-                        if ch == '&' {
+                    match chars.peek() {
+                        Some('&') => {
                             chars.next();
                             tokens.push(Token::Operator(Operator::And));
+                        }
+                        Some('>') => {
+                            chars.next();
+                            if chars.peek() == Some(&'>') {
+                                chars.next();
+                                tokens.push(Token::Operator(Operator::RedirectAppendAll));
+                            } else {
+                                tokens.push(Token::Operator(Operator::RedirectAll));
+                            }
+                        }
+                        _ => tokens.push(Token::Operator(Operator::Andpercent)),
+                    }
+                    current.clear();
+                }
+                // a braced parameter is one word even with blanks inside, as in ${name: -3}
+                '$' if !single_quotes && !double_quotes && chars.peek() == Some(&'{') => {
+                    current.push('$');
+                    let mut depth = 0;
+                    for c in chars.by_ref() {
+                        current.push(c);
+                        match c {
+                            '{' => depth += 1,
+                            '}' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                // a command substitution is a part of its own, inside double quotes as well
+                '$' if !single_quotes && chars.peek() == Some(&'(') => {
+                    if !current.is_empty() {
+                        let quoting = if double_quotes {
+                            Quoting::DoubleQuoted
                         } else {
-                            tokens.push(Token::Operator(Operator::Andpercent));
+                            Quoting::Unquoted
+                        };
+                        tokens.push(Token::Word(take_word(&mut current), quoting));
+                    }
+                    chars.next();
+                    let Some(list) = read_parenthesized(&mut chars) else {
+                        tokens.push(Token::Unterminated);
+                        return tokens;
+                    };
+                    let quoting = match double_quotes {
+                        true => Quoting::QuotedCommand,
+                        false => Quoting::Command,
+                    };
+                    tokens.push(Token::Word(list, quoting));
+                }
+                '<' | '>' if !single_quotes && !double_quotes && chars.peek() == Some(&'(') => {
+                    if !current.trim().is_empty() {
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
+                    }
+                    current.clear();
+                    chars.next();
+                    let Some(list) = read_parenthesized(&mut chars) else {
+                        tokens.push(Token::Unterminated);
+                        return tokens;
+                    };
+                    let quoting = if current_char == '<' {
+                        Quoting::ProcessIn
+                    } else {
+                        Quoting::ProcessOut
+                    };
+                    tokens.push(Token::Word(list, quoting));
+                }
+                '>' if !single_quotes && !double_quotes => {
+                    if !current.trim().is_empty() {
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
+                    }
+                    match chars.peek() {
+                        Some('>') => {
+                            chars.next();
+                            tokens.push(Token::Operator(Operator::RedirectAppend));
+                        }
+                        Some('&') => {
+                            chars.next();
+                            tokens.push(Token::Operator(Operator::DuplicateOut));
+                        }
+                        _ => tokens.push(Token::Operator(Operator::RedirectOut)),
+                    }
+                    current.clear();
+                }
+                '<' if !single_quotes && !double_quotes => {
+                    if !current.trim().is_empty() {
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
+                    }
+                    if chars.next_if_eq(&'&').is_some() {
+                        tokens.push(Token::Operator(Operator::DuplicateIn));
+                    } else if chars.next_if_eq(&'<').is_some() {
+                        if chars.next_if_eq(&'<').is_some() {
+                            tokens.push(Token::Operator(Operator::HereString));
+                        } else {
+                            let strip = chars.next_if_eq(&'-').is_some();
+                            let operator = if strip {
+                                Operator::HereDocStrip
+                            } else {
+                                Operator::HereDoc
+                            };
+                            tokens.push(Token::Operator(operator));
+                            heredocs.push((tokens.len(), strip));
                         }
                     } else {
-                        tokens.push(Token::Operator(Operator::Andpercent));
+                        tokens.push(Token::Operator(Operator::RedirectIn));
                     }
                     current.clear();
                 }
                 '|' if !single_quotes && !double_quotes => {
                     if !current.trim().is_empty() {
-                        tokens.push(Token::Word(current.clone(), Quoting::Unquoted));
-                        current.clear();
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
                     }
                     if let Some(&ch) = chars.peek() {
                         if ch == '|' {
@@ -141,13 +830,44 @@ impl Parser {
                 }
                 ';' if !single_quotes && !double_quotes => {
                     if !current.trim().is_empty() {
-                        tokens.push(Token::Word(current.clone(), Quoting::Unquoted));
-                        current.clear();
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
+                    }
+                    let operator = if chars.next_if_eq(&';').is_some() {
+                        if chars.next_if_eq(&'&').is_some() {
+                            Operator::CaseContinue
+                        } else {
+                            Operator::CaseBreak
+                        }
+                    } else if chars.next_if_eq(&'&').is_some() {
+                        Operator::CaseFallthrough
+                    } else {
+                        Operator::Semicolon
+                    };
+                    tokens.push(Token::Operator(operator));
+                    current.clear();
+                }
+                '(' | ')' if !single_quotes && !double_quotes => {
+                    if !current.trim().is_empty() {
+                        tokens.push(Token::Word(take_word(&mut current), Quoting::Unquoted));
                     }
-                    tokens.push(Token::Operator(Operator::Semicolon));
+                    let operator = if current_char == '(' {
+                        Operator::LeftParen
+                    } else {
+                        Operator::RightParen
+                    };
+                    tokens.push(Token::Operator(operator));
                     current.clear();
                 }
-                '\\' => {
+                // a comment runs until the end of the line, but only at the start of a word
+                '#' if !single_quotes
+                    && !double_quotes
+                    && current.is_empty()
+                    && !matches!(tokens.last(), Some(Token::Word(..))) =>
+                {
+                    while chars.next_if(|&ch| ch != '\n').is_some() {}
+                }
+                // backslashes have no special meaning inside single quotes
+                '\\' if !single_quotes => {
                     if let Some(&ch) = chars.peek() {
                         chars.next();
                         match ch {
@@ -155,6 +875,25 @@ impl Parser {
                             't' => current.push('\t'),
                             'r' => current.push('\r'),
                             '0' => current.push('\0'),
+                            // a backslash at the end of a line joins it with the next one
+                            '\n' if chars.peek().is_none() => {
+                                tokens.push(Token::Unterminated);
+                                return tokens;
+                            }
+                            '\n' => {}
+                            // an escaped metacharacter becomes a quoted part of its own so
+                            // expansion and globbing still know it was escaped
+                            '*' | '?' | '[' | '$' | '~' | '{' | '}' | ',' => {
+                                if !current.is_empty() {
+                                    let quoting = if double_quotes {
+                                        Quoting::DoubleQuoted
+                                    } else {
+                                        Quoting::Unquoted
+                                    };
+                                    tokens.push(Token::Word(take_word(&mut current), quoting));
+                                }
+                                tokens.push(Token::Word(ch.to_string(), Quoting::SingleQuoted));
+                            }
                             ch => current.push(ch),
                         };
                     }
@@ -163,458 +902,9303 @@ impl Parser {
             }
         }
 
-        // for now if a quote is opened and not closed the whole content is just discarded
-        if !current.trim().is_empty() && !single_quotes && !double_quotes {
+        // a quote still open at the end continues on the next line, newline included
+        if single_quotes || double_quotes {
+            tokens.push(Token::Unterminated);
+            return tokens;
+        }
+        if !current.trim().is_empty() {
             tokens.push(Token::Word(current, Quoting::Unquoted));
         }
+        if !heredocs.is_empty() {
+            tokens.push(Token::Unterminated);
+        }
 
         tokens
     }
 
-    fn parse(&self, tokens: Vec<Token>) -> Option<Command> {
-        if tokens.is_empty() {
-            None
-        } else {
-            let args: Vec<String> = tokens
-                .into_iter()
-                .filter_map(|token| match token {
-                    Token::Word(word, Quoting::SingleQuoted) => Some(word),
-                    Token::Word(word, _) => Some(
-                        self.variable_regex
-                            .replace_all(word.as_str(), |caps: &regex::Captures| {
-                                let k = &caps[1];
-                                env::var(k).unwrap_or_default()
-                            })
-                            .into_owned(),
-                    ),
-                    _ => None,
-                })
-                .collect();
+    fn parse(
+        &self,
+        tokens: Vec<Token>,
+        aliases: Option<&HashMap<String, String>>,
+        first_line: usize,
+    ) -> Result<Vec<AndOrList>, ParseError> {
+        let lexemes = Lexer::split(self, tokens, aliases)?;
+        let mut parser = ListParser {
+            lexemes: lexemes.into_iter().peekable(),
+            first_line,
+            line: first_line,
+            ended: false,
+        };
+        let result = parser
+            .list(&[])
+            .and_then(|lists| match parser.lexemes.peek() {
+                Some(_) => Err(parser.unexpected()),
+                None => Ok(lists),
+            });
+        result.map_err(|token| match parser.ended {
+            true => ParseError::Incomplete,
+            false => ParseError::Unexpected(token),
+        })
+    }
+}
 
-            match args[0].as_str() {
-                "exit" => Some(Command::Builtin(BuiltinCommand::Exit)),
-                "cd" => Some(Command::Builtin(BuiltinCommand::Cd(args))),
-                command => {
-                    let external_command = ExternalCommand::new(command.to_string(), args);
-                    Some(Command::External(external_command))
-                }
+// the text up to the parenthesis closing one just read, None if the input ends before it
+fn read_parenthesized(
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
+) -> Option<String> {
+    let mut text = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (None | Some('"'), '\\') => {
+                text.push(c);
+                text.push(chars.next()?);
+                continue;
             }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return Some(text),
+            (None, ')') => depth -= 1,
+            _ => {}
         }
+        text.push(c);
     }
+    None
 }
 
-struct Shell {
-    shell_pid: Pid,
-    last_status: i32,
-    stdin_handle: std::io::Stdin,
-    stdout_handle: std::io::Stdout,
-    // TODO: jobs table
+enum ParseError {
+    Unexpected(String),
+    // the input stopped in the middle of a command, more lines may complete it
+    Incomplete,
 }
 
-impl Shell {
-    fn new() -> nix::Result<Self> {
-        // ignore signals
-        unsafe {
-            // required when shell process is not foreground and uses tcsetpgrp
-            signal(Signal::SIGTTOU, SigHandler::SigIgn)?;
-            // required for ignoring ctrl-z
-            signal(Signal::SIGTSTP, SigHandler::SigIgn)?;
+// replace the delimiter word of each here-doc started on the line just finished with the
+// body read from the lines that follow, false if the input ends before a delimiter line
+fn read_heredoc_bodies(
+    tokens: &mut Vec<Token>,
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
+    heredocs: Vec<(usize, bool)>,
+) -> bool {
+    let mut bodies = Vec::new();
+    for (start, strip) in heredocs {
+        let first = (start..tokens.len())
+            .find(|&index| tokens[index] != Token::Whitespace)
+            .unwrap_or(tokens.len());
+        let end = (first..tokens.len())
+            .find(|&index| !matches!(tokens[index], Token::Word(..)))
+            .unwrap_or(tokens.len());
+        // without a delimiter the parser reports the missing word
+        if first == end {
+            continue;
         }
-        let shell_pid = getpid();
-        let stdin = std::io::stdin();
-        let stdout = std::io::stdout();
-        setpgid(shell_pid, shell_pid)?;
-        tcsetpgrp(&stdin, shell_pid)?;
-
-        Ok(Self {
-            last_status: 0,
-            shell_pid: shell_pid,
-            stdin_handle: stdin,
-            stdout_handle: stdout,
-        })
+        let mut delimiter = String::new();
+        let mut quoted = false;
+        for token in &tokens[first..end] {
+            if let Token::Word(text, quoting) = token {
+                delimiter.push_str(text);
+                quoted |= *quoting != Quoting::Unquoted;
+            }
+        }
+        let mut body = String::new();
+        loop {
+            if chars.peek().is_none() {
+                return false;
+            }
+            let line: String = std::iter::from_fn(|| chars.next_if(|&c| c != '\n')).collect();
+            chars.next();
+            let line = if strip {
+                line.trim_start_matches('\t')
+            } else {
+                &line
+            };
+            if line == delimiter {
+                break;
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        // a quoted delimiter leaves the body as it is, otherwise variables are expanded
+        let quoting = if quoted {
+            Quoting::SingleQuoted
+        } else {
+            Quoting::DoubleQuoted
+        };
+        bodies.push((first, end, Token::Word(body, quoting)));
     }
+    for (first, end, body) in bodies.into_iter().rev() {
+        tokens.splice(first..end, [body]);
+    }
+    true
+}
 
-    fn run(&mut self) -> nix::Result<()> {
-        let parser = Parser::new();
-        loop {
-            print!("\n$ ");
-            self.stdout_handle.flush().unwrap();
+// words that start or delimit a compound command when they appear in command position,
+// the descriptions are shown by help
+const RESERVED_WORDS: [(&str, &str); 17] = [
+    ("if", "run the then branch if the condition list succeeds"),
+    ("then", "start the branch of an if or elif"),
+    ("elif", "test another condition if the previous ones failed"),
+    ("else", "start the branch run when no condition succeeded"),
+    ("fi", "end an if command"),
+    (
+        "while",
+        "repeat the body as long as the condition list succeeds",
+    ),
+    (
+        "until",
+        "repeat the body as long as the condition list fails",
+    ),
+    ("do", "start the body of a loop"),
+    ("done", "end a loop"),
+    (
+        "case",
+        "run the body of the clause whose pattern matches a word",
+    ),
+    ("in", "separate the word of a case from its clauses"),
+    ("esac", "end a case command"),
+    ("!", "invert the status of a pipeline"),
+    ("{", "start a group of commands run in the current shell"),
+    ("}", "end a group of commands"),
+    ("function", "define a function, same as name() { ...; }"),
+    (
+        "time",
+        "report the real, user and sys time of a pipeline, time -p in POSIX format",
+    ),
+];
 
-            let mut input = String::new();
-            let bytes_read = self.stdin_handle.lock().read_line(&mut input).unwrap();
+fn reserved_word(word: &Word) -> Option<&'static str> {
+    RESERVED_WORDS
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| word.is_literal(name))
+}
 
-            if bytes_read == 0 {
-                println!("\nexit");
-                exit(0);
-            }
+fn is_reserved(word: &Word) -> bool {
+    reserved_word(word).is_some()
+}
+
+// tokens grouped into whole words, with redirect operators carrying their file descriptor
+enum Lexeme {
+    Word(Word),
+    Operator(Operator),
+    Redirect(i32, Operator),
+    Newline(usize),
+}
 
-            let tokens = parser.tokenize(input.as_str());
+struct Lexer<'a> {
+    parser: &'a Parser,
+    lexemes: Vec<Lexeme>,
+    parts: Vec<(String, Quoting)>,
+    command_start: bool,
+    conditional: bool,
+    // None while alias expansion is off
+    aliases: Option<&'a HashMap<String, String>>,
+    // aliases whose text is being split, they aren't expanded again inside it
+    expanding: Vec<String>,
+    // the last alias ended in a blank, so the word after it is an alias candidate too
+    alias_next: bool,
+}
 
-            if let Some(command) = parser.parse(tokens) {
-                self.execute(command)?;
-            }
+impl<'a> Lexer<'a> {
+    fn split(
+        parser: &'a Parser,
+        tokens: Vec<Token>,
+        aliases: Option<&'a HashMap<String, String>>,
+    ) -> Result<Vec<Lexeme>, ParseError> {
+        let mut lexer = Lexer {
+            parser,
+            lexemes: Vec::new(),
+            parts: Vec::new(),
+            command_start: true,
+            conditional: false,
+            aliases,
+            expanding: Vec::new(),
+            alias_next: false,
+        };
+        for token in tokens {
+            lexer.push_token(token)?;
         }
+        lexer.finish_word()?;
+        if lexer.conditional {
+            return Err(ParseError::Incomplete);
+        }
+        Ok(lexer.lexemes)
     }
 
-    fn execute(&mut self, command: Command) -> nix::Result<()> {
-        match command {
-            Command::Builtin(builtin) => {
-                let _ = self.handle_builtin(builtin);
-                Ok(())
+    fn push_token(&mut self, token: Token) -> Result<(), ParseError> {
+        match token {
+            Token::Word(text, quoting) => self.parts.push((text, quoting)),
+            Token::Whitespace => self.finish_word()?,
+            Token::Newline(lines) => {
+                self.finish_word()?;
+                if self.conditional {
+                    return Err(ParseError::Unexpected("newline".to_string()));
+                }
+                self.lexemes.push(Lexeme::Newline(lines));
+                self.command_start = true;
             }
-            Command::External(external) => {
-                let status = self.spawn_foreground(external)?;
-                if let WaitStatus::Exited(_, code) = status {
-                    self.last_status = code;
-                } else if let WaitStatus::Stopped(child_pid, signal) = status {
-                    // TODO: add to job table
-                    println!("\n{} suspended", child_pid);
+            Token::Unterminated => return Err(ParseError::Incomplete),
+            Token::Operator(operator) if operator.is_redirect() => {
+                // a number directly in front of the operator selects the file descriptor
+                let fd = match self.parts.as_slice() {
+                    [(text, Quoting::Unquoted)] if text.chars().all(|c| c.is_ascii_digit()) => {
+                        text.parse().ok()
+                    }
+                    _ => None,
+                };
+                if fd.is_some() {
+                    self.parts.clear();
+                } else {
+                    self.finish_word()?;
                 }
-                Ok(())
+                // inside [[ ]] < and > compare strings
+                if self.conditional && fd.is_none() {
+                    self.push_operator_word(operator);
+                    return Ok(());
+                }
+                let default_fd = match operator {
+                    Operator::RedirectIn
+                    | Operator::DuplicateIn
+                    | Operator::HereDoc
+                    | Operator::HereDocStrip
+                    | Operator::HereString => 0,
+                    _ => 1,
+                };
+                self.lexemes
+                    .push(Lexeme::Redirect(fd.unwrap_or(default_fd), operator));
+            }
+            Token::Operator(operator) => {
+                self.finish_word()?;
+                if self.conditional
+                    && matches!(
+                        operator,
+                        Operator::And | Operator::Or | Operator::LeftParen | Operator::RightParen
+                    )
+                {
+                    self.push_operator_word(operator);
+                    return Ok(());
+                }
+                self.lexemes.push(Lexeme::Operator(operator));
+                self.command_start = true;
             }
         }
+        Ok(())
     }
 
-    fn spawn_foreground(&self, command: ExternalCommand) -> nix::Result<WaitStatus> {
-        match unsafe { fork() } {
-            Ok(ForkResult::Parent { child, .. }) => {
-                let _ = setpgid(child, child);
-                let _ = tcsetpgrp(&std::io::stdin(), child);
-                // maybe WUNTRACED/WCONTINUED later for ctrl-z job controll
-                let status = waitpid(child, Some(WaitPidFlag::WUNTRACED))?;
-                let _ = tcsetpgrp(&std::io::stdin(), self.shell_pid);
-                Ok(status)
+    fn finish_word(&mut self) -> Result<(), ParseError> {
+        if self.parts.is_empty() {
+            return Ok(());
+        }
+        let word = Word {
+            parts: std::mem::take(&mut self.parts),
+        };
+        // an unquoted command word naming an alias is replaced by the alias text
+        let candidate = std::mem::take(&mut self.alias_next) || self.command_start;
+        if candidate
+            && !self.conditional
+            && let Some(aliases) = self.aliases
+            && let Some(name) = word.literal()
+            && !self.expanding.iter().any(|expanding| expanding == name)
+            && let Some(text) = aliases.get(name)
+        {
+            self.expanding.push(name.to_string());
+            for token in self.parser.tokenize(text) {
+                self.push_token(token)?;
             }
-            Ok(ForkResult::Child) => {
-                // reset signal handlers
-                unsafe {
-                    signal(Signal::SIGTSTP, SigHandler::SigDfl)?;
-                    signal(Signal::SIGTTOU, SigHandler::SigDfl)?;
-                }
-                let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
-                let _ = execvp(&command.cmd_as_cstring(), &command.args_as_cstring());
-                write(std::io::stdout(), b"command not found\n").ok();
-                unsafe { libc::_exit(127) };
+            self.finish_word()?;
+            self.expanding.pop();
+            self.alias_next = text.ends_with([' ', '\t']);
+            return Ok(());
+        }
+        if self.conditional && word.is_literal("]]") {
+            self.conditional = false;
+        } else if self.command_start && word.is_literal("[[") {
+            self.conditional = true;
+        }
+        // the command after a reserved word like `if` or `then` is again in command position,
+        // as is the one after time -p
+        let time_option = word.is_literal("-p")
+            && matches!(self.lexemes.last(), Some(Lexeme::Word(last)) if last.is_literal("time"));
+        self.command_start = self.command_start && (is_reserved(&word) || time_option);
+        self.lexemes.push(Lexeme::Word(word));
+        Ok(())
+    }
+
+    fn push_operator_word(&mut self, operator: Operator) {
+        self.lexemes.push(Lexeme::Word(Word {
+            parts: vec![(operator.as_str().to_string(), Quoting::Unquoted)],
+        }));
+    }
+}
+
+// recursive descent over the lexemes, errors carry the unexpected token
+struct ListParser {
+    lexemes: std::iter::Peekable<std::vec::IntoIter<Lexeme>>,
+    // the line the input starts on and the one the parser is at
+    first_line: usize,
+    line: usize,
+    // set when an error was hit at the end of the input rather than at a token
+    ended: bool,
+}
+
+impl ListParser {
+    fn peek_reserved(&mut self) -> Option<&'static str> {
+        match self.lexemes.peek() {
+            Some(Lexeme::Word(word)) => reserved_word(word),
+            _ => None,
+        }
+    }
+
+    fn unexpected(&mut self) -> String {
+        match self.lexemes.next() {
+            Some(Lexeme::Word(word)) => word.to_string(),
+            Some(Lexeme::Operator(operator) | Lexeme::Redirect(_, operator)) => {
+                operator.as_str().to_string()
             }
-            Err(_) => {
-                println!("Fork failed");
-                Err(nix::Error::EINVAL)
+            Some(Lexeme::Newline(_)) => "newline".to_string(),
+            None => {
+                self.ended = true;
+                "newline".to_string()
             }
         }
     }
 
-    fn handle_builtin(&self, builtin: BuiltinCommand) -> nix::Result<()> {
-        match builtin {
-            BuiltinCommand::Exit => {
-                println!("exit");
-                exit(0);
-            }
-            BuiltinCommand::Cd(args) => {
-                let target = match &args[1..] {
-                    [] => {
-                        match env::var("HOME") {
-                            Ok(home) => PathBuf::from(home),
-                            Err(_) => {
-                                eprintln!("cd: HOME is not set");
-                                return Err(nix::Error::EINVAL);
-                            }
-                        }
+    // newlines are allowed after operators like | and && and around compound lists
+    fn skip_newlines(&mut self) {
+        while let Some(Lexeme::Newline(lines)) = self
+            .lexemes
+            .next_if(|lexeme| matches!(lexeme, Lexeme::Newline(_)))
+        {
+            self.line = self.first_line + lines;
+        }
+    }
+
+    fn expect(&mut self, reserved: &str) -> Result<(), String> {
+        if self.peek_reserved() == Some(reserved) {
+            self.lexemes.next();
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn expect_operator(&mut self, operator: Operator) -> Result<(), String> {
+        match self.lexemes.peek() {
+            Some(Lexeme::Operator(next)) if *next == operator => {
+                self.lexemes.next();
+                Ok(())
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn function_body(&mut self, name: Word) -> Result<Node, String> {
+        let Some(name) = name.literal().filter(|name| !name.contains('=')) else {
+            return Err(name.to_string());
+        };
+        let name = name.to_string();
+        self.skip_newlines();
+        match self.command()? {
+            Node::Simple(command) => Err(command.to_string()),
+            body => Ok(Node::Function(name, Rc::new(body))),
+        }
+    }
+
+    // commands separated by ;, & or newlines, up to one of the terminators or the end of the
+    // input
+    fn list(&mut self, terminators: &[&str]) -> Result<Vec<AndOrList>, String> {
+        let mut lists = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.at_list_end()
+                || self
+                    .peek_reserved()
+                    .is_some_and(|reserved| terminators.contains(&reserved))
+            {
+                return Ok(lists);
+            }
+            let mut list = self.and_or()?;
+            match self.lexemes.peek() {
+                Some(Lexeme::Operator(Operator::Semicolon)) => {
+                    self.lexemes.next();
+                }
+                Some(Lexeme::Newline(_)) => self.skip_newlines(),
+                Some(Lexeme::Operator(Operator::Andpercent)) => {
+                    self.lexemes.next();
+                    list.background = true;
+                }
+                _ => {
+                    if !self.at_list_end() {
+                        return Err(self.unexpected());
                     }
-                    [_, dir] if dir == "-" => {
-                        match env::var("OLDPWD") {
-                            Ok(oldpwd) => {
-                                println!("{}", oldpwd);
-                                PathBuf::from(oldpwd)
-                            }
-                            Err(_) => {
-                                eprintln!("cd: OLDPWD is not set");
-                                return Err(nix::Error::EINVAL);
-                            }
-                        }
+                }
+            }
+            lists.push(list);
+        }
+    }
+
+    // the end of the input, of a subshell or of the body of a case clause
+    fn at_list_end(&mut self) -> bool {
+        matches!(
+            self.lexemes.peek(),
+            None | Some(Lexeme::Operator(
+                Operator::RightParen
+                    | Operator::CaseBreak
+                    | Operator::CaseFallthrough
+                    | Operator::CaseContinue
+            ))
+        )
+    }
+
+    // the list inside a compound command, which can't be empty
+    fn compound_list(&mut self, terminators: &[&str]) -> Result<Vec<AndOrList>, String> {
+        let lists = self.list(terminators)?;
+        if lists.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(lists)
+    }
+
+    fn and_or(&mut self) -> Result<AndOrList, String> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+        while let Some(Lexeme::Operator(operator)) = self
+            .lexemes
+            .next_if(|lexeme| matches!(lexeme, Lexeme::Operator(Operator::And | Operator::Or)))
+        {
+            self.skip_newlines();
+            rest.push((operator, self.pipeline()?));
+        }
+        Ok(AndOrList {
+            first,
+            rest,
+            background: false,
+        })
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline, String> {
+        let time = (self.peek_reserved() == Some("time")).then(|| {
+            self.lexemes.next();
+            match self
+                .lexemes
+                .next_if(|lexeme| matches!(lexeme, Lexeme::Word(word) if word.is_literal("-p")))
+            {
+                Some(_) => TimeFormat::Posix,
+                None => TimeFormat::Default,
+            }
+        });
+        let negated = self.peek_reserved() == Some("!");
+        if negated {
+            self.lexemes.next();
+        }
+        let mut commands = vec![self.command()?];
+        while let Some(Lexeme::Operator(Operator::Pipe)) = self.lexemes.peek() {
+            self.lexemes.next();
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline {
+            commands,
+            negated,
+            time,
+        })
+    }
+
+    fn command(&mut self) -> Result<Node, String> {
+        let node = match self.peek_reserved() {
+            Some("if") => self.if_clause()?,
+            Some("while" | "until") => self.while_clause()?,
+            Some("case") => self.case_clause()?,
+            Some("{") => {
+                self.lexemes.next();
+                let lists = self.compound_list(&["}"])?;
+                self.expect("}")?;
+                Node::Group(lists)
+            }
+            Some("function") => {
+                self.lexemes.next();
+                let Some(Lexeme::Word(name)) = self
+                    .lexemes
+                    .next_if(|lexeme| matches!(lexeme, Lexeme::Word(_)))
+                else {
+                    return Err(self.unexpected());
+                };
+                if let Some(Lexeme::Operator(Operator::LeftParen)) = self.lexemes.peek() {
+                    self.lexemes.next();
+                    self.expect_operator(Operator::RightParen)?;
+                }
+                return self.function_body(name);
+            }
+            Some(_) => return Err(self.unexpected()),
+            None if matches!(
+                self.lexemes.peek(),
+                Some(Lexeme::Operator(Operator::LeftParen))
+            ) =>
+            {
+                self.lexemes.next();
+                let lists = self.list(&[])?;
+                self.expect_operator(Operator::RightParen)?;
+                if lists.is_empty() {
+                    return Err(")".to_string());
+                }
+                Node::Subshell(lists)
+            }
+            None => {
+                let command = self.simple_command()?;
+                // name() starts a function definition
+                if let Some(Lexeme::Operator(Operator::LeftParen)) = self.lexemes.peek() {
+                    self.lexemes.next();
+                    let mut words = command.words;
+                    if words.len() != 1 || !command.redirects.is_empty() {
+                        return Err("(".to_string());
                     }
-                    [_, dir] => PathBuf::from(dir),
-                    _ => {
-                        eprintln!("cd: too many arguments");
-                        return Err(nix::Error::EINVAL);
+                    self.expect_operator(Operator::RightParen)?;
+                    return self.function_body(words.pop().unwrap());
+                }
+                return Ok(Node::Simple(command));
+            }
+        };
+        let mut redirects = Vec::new();
+        while let Some(Lexeme::Redirect(fd, operator)) = self
+            .lexemes
+            .next_if(|lexeme| matches!(lexeme, Lexeme::Redirect(..)))
+        {
+            redirects.push(self.redirect(fd, operator)?);
+        }
+        if redirects.is_empty() {
+            Ok(node)
+        } else {
+            Ok(Node::Redirected(Box::new(node), redirects))
+        }
+    }
+
+    fn while_clause(&mut self) -> Result<Node, String> {
+        let until = self.peek_reserved() == Some("until");
+        self.lexemes.next();
+        let condition = self.compound_list(&["do"])?;
+        self.expect("do")?;
+        let body = self.compound_list(&["done"])?;
+        self.expect("done")?;
+        Ok(Node::While {
+            condition,
+            body,
+            until,
+        })
+    }
+
+    fn case_clause(&mut self) -> Result<Node, String> {
+        self.lexemes.next();
+        let Some(Lexeme::Word(subject)) = self
+            .lexemes
+            .next_if(|lexeme| matches!(lexeme, Lexeme::Word(_)))
+        else {
+            return Err(self.unexpected());
+        };
+        self.skip_newlines();
+        self.expect("in")?;
+        let mut clauses = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_reserved() == Some("esac") {
+                break;
+            }
+            // patterns separated by | and ended by ), optionally starting with (
+            self.lexemes
+                .next_if(|lexeme| matches!(lexeme, Lexeme::Operator(Operator::LeftParen)));
+            let mut patterns = Vec::new();
+            loop {
+                match self
+                    .lexemes
+                    .next_if(|lexeme| matches!(lexeme, Lexeme::Word(_)))
+                {
+                    Some(Lexeme::Word(pattern)) => patterns.push(pattern),
+                    _ => return Err(self.unexpected()),
+                }
+                match self.lexemes.peek() {
+                    Some(Lexeme::Operator(Operator::Pipe)) => {
+                        self.lexemes.next();
                     }
-                };
+                    _ => break,
+                }
+            }
+            self.expect_operator(Operator::RightParen)?;
+            let body = self.list(&["esac"])?;
+            // the last clause doesn't need a terminator
+            let terminator = match self.lexemes.next_if(|lexeme| {
+                matches!(
+                    lexeme,
+                    Lexeme::Operator(
+                        Operator::CaseBreak | Operator::CaseFallthrough | Operator::CaseContinue
+                    )
+                )
+            }) {
+                Some(Lexeme::Operator(operator)) => operator,
+                _ if self.peek_reserved() == Some("esac") => Operator::CaseBreak,
+                _ => return Err(self.unexpected()),
+            };
+            clauses.push((patterns, body, terminator));
+        }
+        self.expect("esac")?;
+        Ok(Node::Case { subject, clauses })
+    }
 
-                let pwd = getcwd()?;
+    fn if_clause(&mut self) -> Result<Node, String> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        self.lexemes.next();
+        loop {
+            let condition = self.compound_list(&["then"])?;
+            self.expect("then")?;
+            let body = self.compound_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            match self.peek_reserved() {
+                Some("elif") => {
+                    self.lexemes.next();
+                }
+                Some("else") => {
+                    self.lexemes.next();
+                    otherwise = Some(self.compound_list(&["fi"])?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.expect("fi")?;
+        Ok(Node::If {
+            branches,
+            otherwise,
+        })
+    }
 
-                if let Err(e) = chdir(&target) {
-                    eprintln!("cd: {}", e);
-                } else {
-                    // update PWD and OLDPWD
-                    unsafe {
-                        let new_pwd = getcwd()?;
-                        env::set_var("OLDPWD", pwd.as_os_str());
-                        env::set_var("PWD", new_pwd.as_os_str());
+    fn simple_command(&mut self) -> Result<SimpleCommand, String> {
+        let mut command = SimpleCommand {
+            line: self.line,
+            ..Default::default()
+        };
+        loop {
+            match self.lexemes.peek() {
+                Some(Lexeme::Word(_)) => {
+                    if let Some(Lexeme::Word(word)) = self.lexemes.next() {
+                        command.words.push(word);
+                    }
+                }
+                Some(Lexeme::Redirect(..)) => {
+                    let Some(Lexeme::Redirect(fd, operator)) = self.lexemes.next() else {
+                        unreachable!()
                     };
+                    command.redirects.push(self.redirect(fd, operator)?);
+                }
+                _ => break,
+            }
+        }
+        if command.words.is_empty() && command.redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(command)
+    }
+
+    // the target word following a redirect operator
+    fn redirect(&mut self, fd: i32, operator: Operator) -> Result<Redirect, String> {
+        match self
+            .lexemes
+            .next_if(|lexeme| matches!(lexeme, Lexeme::Word(_)))
+        {
+            Some(Lexeme::Word(target)) => Ok(Redirect {
+                fd,
+                operator,
+                target,
+            }),
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+// a redirect with its target already opened, applied to the file descriptors in order
+enum Redirection {
+    File(i32, OwnedFd),
+    Duplicate { from: i32, to: i32 },
+    Close(i32),
+}
+
+// make `to` refer to the same file as `from`, keeping it open across exec
+fn duplicate_fd(from: RawFd, to: RawFd) -> nix::Result<()> {
+    if from == to {
+        let fd = unsafe { BorrowedFd::borrow_raw(to) };
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+    } else {
+        Errno::result(unsafe { libc::dup2(from, to) })?;
+    }
+    Ok(())
+}
+
+// set from the SIGCHLD handler, the prompt loop reaps jobs when it sees it
+static CHILD_STATUS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigchld(_: libc::c_int) {
+    CHILD_STATUS_CHANGED.store(true, Ordering::Relaxed);
+}
+
+fn install_sigchld_handler() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigchld),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGCHLD, &action) }?;
+    Ok(())
+}
+
+// set from the SIGWINCH handler, the line editor looks up the new width when it sees it
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    WINDOW_RESIZED.store(true, Ordering::Relaxed);
+}
+
+fn install_sigwinch_handler() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigwinch),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGWINCH, &action) }?;
+    Ok(())
+}
+
+// the width of the terminal on fd, 0 when it isn't known
+fn terminal_columns(fd: RawFd) -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+        0 => size.ws_col as usize,
+        _ => 0,
+    }
+}
+
+// one bit per signal that arrived since the traps last ran
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn handle_trapped_signal(signal: libc::c_int) {
+    PENDING_SIGNALS.fetch_or(1 << signal, Ordering::Relaxed);
+}
+
+// without SA_RESTART a blocking read returns so the trap can run right away
+fn catch_signal(signal: Signal) -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_trapped_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { sigaction(signal, &action) }?;
+    Ok(())
+}
+
+// EXIT or a signal name without the SIG prefix, from a name, SIGNAME or number
+fn trap_name(spec: &str) -> Option<String> {
+    if spec.eq_ignore_ascii_case("EXIT") || spec == "0" {
+        return Some("EXIT".to_string());
+    }
+    let signal = parse_signal(spec)?;
+    Some(signal.as_str().trim_start_matches("SIG").to_string())
+}
+
+// a signal by number or by name in any case, with or without SIG in front
+fn parse_signal(spec: &str) -> Option<Signal> {
+    match spec.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) => {
+            let upper = spec.to_ascii_uppercase();
+            format!("SIG{}", upper.trim_start_matches("SIG"))
+                .parse()
+                .ok()
+        }
+    }
+}
+
+// a waitpid that isn't cut short by a signal arriving for a trap
+fn wait_child(pid: Pid, flags: Option<WaitPidFlag>) -> nix::Result<WaitStatus> {
+    loop {
+        match waitpid(pid, flags) {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+// marks a descriptor closed with >&- in the table
+const CLOSED_FD: RawFd = -1;
+
+// file descriptors as seen by builtins, which run inside the shell and can't dup2 over its own
+#[derive(Default)]
+struct FdTable {
+    fds: HashMap<i32, RawFd>,
+    opened: Vec<OwnedFd>,
+}
+
+impl FdTable {
+    // a table for a nested builtin, starting out with the descriptors of the enclosing one
+    fn layer(&self, redirections: Vec<Redirection>) -> Self {
+        let mut table = Self {
+            fds: self.fds.clone(),
+            opened: Vec::new(),
+        };
+        for redirection in redirections {
+            match redirection {
+                Redirection::File(fd, file) => {
+                    table.fds.insert(fd, file.as_raw_fd());
+                    table.opened.push(file);
+                }
+                Redirection::Duplicate { from, to } => {
+                    table.fds.insert(to, table.get(from));
                 }
+                Redirection::Close(fd) => {
+                    table.fds.insert(fd, CLOSED_FD);
+                }
+            }
+        }
+        table
+    }
+
+    fn get(&self, fd: i32) -> RawFd {
+        self.fds.get(&fd).copied().unwrap_or(fd)
+    }
+
+    // make the real descriptors match the table, used in a child right before exec
+    fn install(&self) -> nix::Result<()> {
+        // copy the sources out of the way first so one mapping can't clobber another
+        let mut copies = Vec::new();
+        for (&fd, &raw) in self.fds.iter().filter(|(fd, raw)| fd != raw) {
+            if raw == CLOSED_FD {
+                copies.push((fd, None));
+                continue;
+            }
+            let source = unsafe { BorrowedFd::borrow_raw(raw) };
+            copies.push((fd, Some(fcntl(source, FcntlArg::F_DUPFD_CLOEXEC(10))?)));
+        }
+        for (fd, copy) in copies {
+            match copy {
+                Some(copy) => {
+                    duplicate_fd(copy, fd)?;
+                    unsafe { libc::close(copy) };
+                }
+                None => unsafe {
+                    libc::close(fd);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+// puts the terminal back the way it was however the builtin using it returns
+struct TerminalMode {
+    fd: RawFd,
+    saved: Termios,
+}
+
+impl TerminalMode {
+    // input arrives a key at a time instead of a line at a time, None when fd isn't a terminal
+    fn noncanonical(fd: RawFd) -> Option<Self> {
+        Self::clear(fd, LocalFlags::ICANON)
+    }
+
+    // for the line editor keys also aren't echoed and ctrl-c is read like any other key
+    fn editing(fd: RawFd) -> Option<Self> {
+        Self::clear(
+            fd,
+            LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN,
+        )
+    }
+
+    fn clear(fd: RawFd, flags: LocalFlags) -> Option<Self> {
+        let terminal = unsafe { BorrowedFd::borrow_raw(fd) };
+        let saved = tcgetattr(terminal).ok()?;
+        let mut termios = saved.clone();
+        termios.local_flags.remove(flags);
+        termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        tcsetattr(terminal, SetArg::TCSANOW, &termios).ok()?;
+        Some(Self { fd, saved })
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        let terminal = unsafe { BorrowedFd::borrow_raw(self.fd) };
+        let _ = tcsetattr(terminal, SetArg::TCSANOW, &self.saved);
+    }
+}
+
+// the line being edited at the interactive prompt, the cursor is a byte offset into it
+struct LineEditor {
+    // only the last line of the prompt is redrawn
+    prompt: String,
+    line: String,
+    cursor: usize,
+    // vi command mode, and a d waiting for the second d of dd
+    vi_command: bool,
+    vi_delete: bool,
+    // a key read while looking for an escape sequence that still has to be handled
+    unread: Option<u8>,
+    // the terminal width the line wraps at, 0 if unknown, and the row of the cursor below
+    // the one the prompt starts in
+    columns: usize,
+    cursor_row: usize,
+}
+
+impl LineEditor {
+    fn new(prompt: &str) -> Self {
+        Self {
+            prompt: prompt.rsplit('\n').next().unwrap_or_default().to_string(),
+            line: String::new(),
+            cursor: 0,
+            vi_command: false,
+            vi_delete: false,
+            unread: None,
+            columns: 0,
+            cursor_row: 0,
+        }
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.line.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn left(&mut self) {
+        if let Some(c) = self.line[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    fn right(&mut self) {
+        if let Some(c) = self.line[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.left();
+            self.line.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+        }
+    }
+
+    // to the start of the next word, words being separated by whitespace
+    fn word_forward(&mut self) {
+        let rest = &self.line[self.cursor..];
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let next = rest[word_end..]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(rest.len(), |start| word_end + start);
+        self.cursor += next;
+    }
+
+    // to the start of this word, or of the previous one when already there
+    fn word_backward(&mut self) {
+        let before = self.line[..self.cursor].trim_end();
+        self.cursor = before
+            .rfind(char::is_whitespace)
+            .map_or(0, |index| index + 1);
+    }
+
+    fn kill_to_start(&mut self) {
+        self.line.replace_range(..self.cursor, "");
+        self.cursor = 0;
+    }
+
+    fn kill_to_end(&mut self) {
+        self.line.truncate(self.cursor);
+    }
+
+    fn kill_word(&mut self) {
+        let end = self.cursor;
+        self.word_backward();
+        self.line.replace_range(self.cursor..end, "");
+    }
+
+    // in vi command mode the cursor sits on a character, never past the last one
+    fn clamp(&mut self) {
+        if self.vi_command && self.cursor == self.line.len() {
+            self.left();
+        }
+    }
+
+    fn vi_key(&mut self, key: char) {
+        if std::mem::take(&mut self.vi_delete) {
+            if key == 'd' {
+                self.line.clear();
+                self.cursor = 0;
+            }
+            return;
+        }
+        match key {
+            'h' => self.left(),
+            'l' => self.right(),
+            'w' => self.word_forward(),
+            'b' => self.word_backward(),
+            '0' => self.cursor = 0,
+            '$' => self.cursor = self.line.len(),
+            'x' => self.delete(),
+            'd' => self.vi_delete = true,
+            'i' => self.vi_command = false,
+            'a' => {
+                self.right();
+                self.vi_command = false;
+            }
+            'I' => {
+                self.cursor = 0;
+                self.vi_command = false;
+            }
+            'A' => {
+                self.cursor = self.line.len();
+                self.vi_command = false;
             }
+            _ => {}
         }
+        self.clamp();
+    }
+
+    // what to write to redraw the prompt line with the cursor in place
+    fn redraw(&mut self) -> String {
+        let mut output = String::new();
+        if self.cursor_row > 0 {
+            output.push_str(&format!("\x1b[{}A", self.cursor_row));
+        }
+        output.push_str(&format!("\r{}{}", self.prompt, self.line));
+        let after = self.line[self.cursor..].chars().count();
+        if self.columns == 0 {
+            output.push_str("\x1b[K");
+            if after > 0 {
+                output.push_str(&format!("\x1b[{}D", after));
+            }
+            return output;
+        }
+        // a line wrapping onto more rows is cleared below as well and the cursor is placed
+        // by row and column
+        let end = self.prompt.chars().count() + self.line.chars().count();
+        let position = end - after;
+        // at the right margin the terminal only wraps with the next character
+        if end > 0 && end.is_multiple_of(self.columns) {
+            output.push_str("\r\n");
+        }
+        output.push_str("\x1b[J");
+        let row = position / self.columns;
+        if end / self.columns > row {
+            output.push_str(&format!("\x1b[{}A", end / self.columns - row));
+        }
+        output.push('\r');
+        let column = position % self.columns;
+        if column > 0 {
+            output.push_str(&format!("\x1b[{}C", column));
+        }
+        self.cursor_row = row;
+        output
+    }
+}
+
+// the word a completion replaces in the text before the cursor: where it starts, its text
+// with backslashes removed, the words of the command before it and whether it's the target
+// of a redirection
+fn completion_context(line: &str) -> (usize, String, Vec<String>, bool) {
+    let mut words = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    // the next word is the target of a redirection, not an argument
+    let mut redirect = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                let (_, word) = current.get_or_insert_with(|| (index, String::new()));
+                word.extend(chars.next().map(|(_, c)| c));
+            }
+            // part of &>, >& or <&
+            '&' if chars.peek().is_some_and(|&(_, next)| next == '>')
+                || line[..index].ends_with(['<', '>']) => {}
+            ';' | '|' | '&' | '(' | ')' => {
+                current = None;
+                words.clear();
+                redirect = false;
+            }
+            '<' | '>' => {
+                // a number right in front of the operator is the file descriptor
+                if let Some((_, word)) = current.take()
+                    && !word.chars().all(|c| c.is_ascii_digit())
+                    && !redirect
+                {
+                    words.push(word);
+                }
+                redirect = true;
+            }
+            _ if c.is_whitespace() => {
+                if let Some((_, word)) = current.take()
+                    && !std::mem::take(&mut redirect)
+                {
+                    words.push(word);
+                }
+            }
+            _ => current
+                .get_or_insert_with(|| (index, String::new()))
+                .1
+                .push(c),
+        }
+    }
+    let (start, word) = current.unwrap_or((line.len(), String::new()));
+    (start, word, words, redirect)
+}
+
+// backslashes in front of the characters the tokenizer would otherwise act on
+fn escape_completion(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if c.is_whitespace() || "\\'\"$*?[;&|<>()#".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// entries of dir starting with prefix as (name, is a directory), symlinks are followed
+fn directory_entries(dir: &Path, prefix: &str) -> Vec<(String, bool)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        // hidden files are only offered once the dot is typed
+        .filter(|name| {
+            name.starts_with(prefix) && (!name.starts_with('.') || prefix.starts_with('.'))
+        })
+        .map(|name| {
+            let is_dir = dir.join(&name).is_dir();
+            (name, is_dir)
+        })
+        .collect()
+}
+
+// the user names in the password database starting with prefix, sorted
+fn user_names(prefix: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    unsafe {
+        libc::setpwent();
+        loop {
+            let entry = libc::getpwent();
+            if entry.is_null() {
+                break;
+            }
+            let name = std::ffi::CStr::from_ptr((*entry).pw_name).to_string_lossy();
+            if name.starts_with(prefix) {
+                names.push(name.into_owned());
+            }
+        }
+        libc::endpwent();
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+// an absolute path without . and .. components, .. removes the component before it
+// without looking at the file system
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+            _ => {}
+        }
+    }
+    normalized
+}
 
+// writes to a raw file descriptor without taking ownership of it
+struct FdWriter(RawFd);
+
+impl Write for FdWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.0 == CLOSED_FD {
+            return Err(Errno::EBADF.into());
+        }
+        let fd = unsafe { BorrowedFd::borrow_raw(self.0) };
+        Ok(write(fd, buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
-}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum JobState {
+    Running,
+    // by the signal that stopped it, a job's status is 128 plus its number
+    Stopped(Signal),
+    Done(i32),
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobState::Running => write!(f, "Running"),
+            JobState::Stopped(_) => write!(f, "Stopped"),
+            JobState::Done(0) => write!(f, "Done"),
+            JobState::Done(code) => write!(f, "Exit {}", code),
+        }
+    }
+}
+
+struct Job {
+    id: usize,
+    // the process group, named after the first process
+    pid: Pid,
+    // every process of a pipeline and its status once it terminated
+    processes: Vec<(Pid, Option<i32>)>,
+    command: String,
+    state: JobState,
+    // disown -h, the job isn't sent SIGHUP when the shell is hung up
+    nohup: bool,
+}
+
+impl Job {
+    // record what waitpid reported for one of the processes, the job is done with the
+    // status of the last one when all of them are
+    fn update(&mut self, pid: Pid, result: nix::Result<WaitStatus>) {
+        let code = match result {
+            Ok(WaitStatus::Stopped(_, sig)) => return self.state = JobState::Stopped(sig),
+            Ok(WaitStatus::Continued(_)) => return self.state = JobState::Running,
+            Ok(status) => match status_code(status) {
+                Some(code) => code,
+                None => return,
+            },
+            Err(Errno::EINTR) => return,
+            // the child is already gone, nothing left to report
+            Err(_) => 127,
+        };
+        for (process, status) in self.processes.iter_mut() {
+            if *process == pid {
+                *status = Some(code);
+            }
+        }
+        if let [.., (_, Some(last))] = self.processes[..]
+            && self.processes.iter().all(|(_, status)| status.is_some())
+        {
+            self.state = JobState::Done(last);
+        }
+    }
+
+    fn running(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.processes
+            .iter()
+            .filter(|(_, status)| status.is_none())
+            .map(|(pid, _)| *pid)
+    }
+}
+
+// exit code as reported by $?, signals are reported as 128 + signal number
+fn status_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, signal, _) | WaitStatus::Stopped(_, signal) => {
+            Some(128 + signal as i32)
+        }
+        _ => None,
+    }
+}
+
+// shell pattern matching with *, ? and [...] as used by [[ == ]]
+fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_chars(&pattern, &text)
+}
+
+fn match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len()).any(|skip| match_chars(&pattern[1..], &text[skip..])),
+        Some('?') => !text.is_empty() && match_chars(&pattern[1..], &text[1..]),
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && match_chars(&pattern[2..], &text[1..])
+        }
+        Some(&p) => {
+            let Some(&c) = text.first() else {
+                return false;
+            };
+            // an unterminated bracket expression matches a literal [
+            match match_bracket(pattern, c).filter(|_| p == '[') {
+                Some((matched, length)) => matched && match_chars(&pattern[length..], &text[1..]),
+                None => p == c && match_chars(&pattern[1..], &text[1..]),
+            }
+        }
+    }
+}
+
+// whether c matches the bracket expression at the start of the pattern and its length
+fn match_bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let start = i;
+    let mut matched = false;
+    while let Some(&p) = pattern.get(i) {
+        // a ] right after the opening bracket is a literal
+        if p == ']' && i > start {
+            return Some((matched != negated, i + 1));
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                matched |= p <= c && c <= end;
+                i += 3;
+            }
+            _ => {
+                matched |= p == c;
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+// whether the pattern has an unescaped *, ? or [ and needs matching against file names
+fn has_glob_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn unescape_pattern(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+// brace expansion, a{b,c}d becomes abd acd and {1..3} or {a..c} a sequence, quoted braces
+// and commas and the braces of ${name} are left alone
+fn expand_braces(word: &Word) -> Vec<Word> {
+    // every character with the index of the part it's from, so quoting can be restored
+    let chars = word
+        .parts
+        .iter()
+        .enumerate()
+        .flat_map(|(index, (text, _))| text.chars().map(move |c| (c, index)))
+        .collect();
+    let mut expanded = Vec::new();
+    expand_brace_chars(chars, word, &mut expanded);
+    expanded
+        .into_iter()
+        .map(|chars| {
+            let mut parts: Vec<(String, Quoting)> = Vec::new();
+            let mut last = None;
+            for (c, index) in chars {
+                if last != Some(index) {
+                    parts.push((String::new(), word.parts[index].1.clone()));
+                    last = Some(index);
+                }
+                parts.last_mut().unwrap().0.push(c);
+            }
+            Word { parts }
+        })
+        .collect()
+}
+
+fn expand_brace_chars(
+    chars: Vec<(char, usize)>,
+    word: &Word,
+    expanded: &mut Vec<Vec<(char, usize)>>,
+) {
+    let unquoted = |i: usize| word.parts[chars[i].1].1 == Quoting::Unquoted;
+    let is = |i: usize, wanted: char| chars[i].0 == wanted && unquoted(i);
+    for open in 0..chars.len() {
+        if !is(open, '{') || (open > 0 && is(open - 1, '$')) {
+            continue;
+        }
+        let mut depth = 0;
+        let mut bounds = vec![open];
+        let mut close = None;
+        for i in open + 1..chars.len() {
+            if is(i, '{') {
+                depth += 1;
+            } else if is(i, '}') && depth > 0 {
+                depth -= 1;
+            } else if is(i, '}') {
+                close = Some(i);
+                break;
+            } else if is(i, ',') && depth == 0 {
+                bounds.push(i);
+            }
+        }
+        let Some(close) = close else {
+            continue;
+        };
+        bounds.push(close);
+        let alternatives: Vec<Vec<(char, usize)>> = if bounds.len() > 2 {
+            bounds
+                .windows(2)
+                .map(|bounds| chars[bounds[0] + 1..bounds[1]].to_vec())
+                .collect()
+        } else {
+            let body: String = chars[open + 1..close].iter().map(|(c, _)| c).collect();
+            let sequence = match (open + 1..close).all(unquoted) {
+                true => brace_sequence(&body),
+                false => None,
+            };
+            // without a comma or a sequence the braces are just characters
+            let Some(sequence) = sequence else {
+                continue;
+            };
+            let index = chars[open].1;
+            sequence
+                .into_iter()
+                .map(|item| item.chars().map(|c| (c, index)).collect())
+                .collect()
+        };
+        for alternative in alternatives {
+            let mut next = chars[..open].to_vec();
+            next.extend(alternative);
+            next.extend_from_slice(&chars[close + 1..]);
+            expand_brace_chars(next, word, expanded);
+        }
+        return;
+    }
+    // like bash a word that expanded to nothing is dropped
+    if !chars.is_empty() {
+        expanded.push(chars);
+    }
+}
+
+// the items of a start..end or start..end..step sequence of integers or letters
+fn brace_sequence(body: &str) -> Option<Vec<String>> {
+    let (start, end, step) = match body.split("..").collect::<Vec<_>>().as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.parse::<i64>().ok()?.abs().max(1)),
+        _ => return None,
+    };
+    let steps = |start: i64, end: i64| {
+        let step = if start <= end { step } else { -step };
+        std::iter::successors(Some(start), move |n| Some(n + step))
+            .take_while(move |n| if step > 0 { *n <= end } else { *n >= end })
+    };
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // a leading zero pads every number to the same width
+        let padded = |bound: &str| {
+            let digits = bound.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = match padded(start) || padded(end) {
+            true => start.len().max(end.len()),
+            false => 0,
+        };
+        return Some(
+            steps(first, last)
+                .map(|n| format!("{:0width$}", n))
+                .collect(),
+        );
+    }
+    match (start.as_bytes(), end.as_bytes()) {
+        ([first], [last]) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => Some(
+            steps(*first as i64, *last as i64)
+                .map(|c| (c as u8 as char).to_string())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+// text added to a pattern, quoted characters only ever match themselves
+fn push_pattern(pattern: &mut String, text: &str, quoted: bool) {
+    for c in text.chars() {
+        let literal = match quoted {
+            false => c == '\\',
+            true => matches!(c, '*' | '?' | '[' | ']' | '\\'),
+        };
+        if literal {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+}
+
+// pathname expansion one path component at a time, the matches are sorted by byte value
+// so the order doesn't depend on the order readdir returns entries in
+fn glob(pattern: &str, globstar: bool) -> Vec<String> {
+    let mut paths = vec![if pattern.starts_with('/') {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+    let components: Vec<&str> = pattern
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect();
+    for (i, component) in components.iter().enumerate() {
+        let mut next = Vec::new();
+        // with globstar ** matches the directory itself and all below it, as the last
+        // component the files in them too
+        if globstar && *component == "**" {
+            let last = i == components.len() - 1;
+            for path in paths {
+                if !last {
+                    next.push(path.clone());
+                }
+                descendants(&path, last, &mut next);
+            }
+            paths = next;
+            continue;
+        }
+        for path in paths {
+            if !has_glob_chars(component) {
+                next.push(format!("{}{}/", path, unescape_pattern(component)));
+                continue;
+            }
+            let dir = if path.is_empty() { "." } else { path.as_str() };
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                // hidden files only match a pattern that starts with a literal dot
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if pattern_matches(component, &name) {
+                    next.push(format!("{}{}/", path, name));
+                }
+            }
+        }
+        paths = next;
+    }
+
+    let mut matches: Vec<String> = paths
+        .into_iter()
+        .map(|path| {
+            let trimmed = path.trim_end_matches('/');
+            if pattern.ends_with('/') || trimmed.is_empty() {
+                path
+            } else {
+                trimmed.to_string()
+            }
+        })
+        .filter(|path| std::fs::symlink_metadata(path).is_ok())
+        .collect();
+    matches.sort();
+    // **/** reaches the same paths more than once
+    matches.dedup();
+    matches
+}
+
+// the directories below path at any depth, with files every entry below it. hidden entries
+// are skipped like by * and symlinks aren't followed so a link to a parent can't loop
+fn descendants(path: &str, files: bool, found: &mut Vec<String>) {
+    let dir = if path.is_empty() { "." } else { path };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+        if is_dir || files {
+            found.push(format!("{}{}/", path, name));
+        }
+        if is_dir {
+            descendants(&format!("{}{}/", path, name), files, found);
+        }
+    }
+}
+
+// recursive descent evaluation of the expression inside [[ ]]
+// the words of [[ ]] are patterns with their quoted characters escaped, operators are only
+// the words that were written out unquoted
+struct Conditional<'a> {
+    shell: &'a Shell,
+    args: &'a [String],
+    position: usize,
+}
+
+impl<'a> Conditional<'a> {
+    fn evaluate(shell: &'a Shell, args: &'a [String]) -> Result<bool, String> {
+        let mut conditional = Self {
+            shell,
+            args,
+            position: 0,
+        };
+        let result = conditional.or()?;
+        match conditional.peek() {
+            Some(token) => Err(format!("syntax error near `{}'", token)),
+            None => Ok(result),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.args.get(self.position).map(|arg| arg.as_str())
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let arg = self.peek();
+        self.position += 1;
+        arg
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek() == Some("||") {
+            self.next();
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek() == Some("&&") {
+            self.next();
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek() == Some("!") {
+            self.next();
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        let first = self
+            .next()
+            .ok_or_else(|| "unexpected end of expression".to_string())?;
+
+        if first == "(" {
+            let result = self.or()?;
+            return match self.next() {
+                Some(")") => Ok(result),
+                _ => Err("expected `)'".to_string()),
+            };
+        }
+
+        let operand_follows =
+            matches!(self.peek(), Some(arg) if arg != "&&" && arg != "||" && arg != ")");
+        if is_unary_test(first) && operand_follows {
+            let operand = self.next().unwrap();
+            return self.unary(first, &unescape_pattern(operand));
+        }
+
+        match self.peek() {
+            Some(operator) if is_binary_test(operator) => {
+                self.next();
+                let right = self
+                    .next()
+                    .ok_or_else(|| format!("argument expected after `{}'", operator))?;
+                binary_test(&unescape_pattern(first), operator, right)
+            }
+            _ => Ok(!first.is_empty()),
+        }
+    }
+
+    fn unary(&self, operator: &str, operand: &str) -> Result<bool, String> {
+        let path = Path::new(operand);
+        Ok(match operator {
+            "-v" => self.shell.get_var(operand).is_some(),
+            "-z" => operand.is_empty(),
+            "-n" => !operand.is_empty(),
+            "-e" => path.exists(),
+            "-f" => path.is_file(),
+            "-d" => path.is_dir(),
+            "-L" | "-h" => path.is_symlink(),
+            "-s" => path.metadata().is_ok_and(|metadata| metadata.len() > 0),
+            "-r" => access(path, AccessFlags::R_OK).is_ok(),
+            "-w" => access(path, AccessFlags::W_OK).is_ok(),
+            "-x" => access(path, AccessFlags::X_OK).is_ok(),
+            _ => unreachable!(),
+        })
+    }
+}
+
+fn is_conditional_operator(word: &str) -> bool {
+    matches!(word, "&&" | "||" | "!" | "(" | ")") || is_unary_test(word) || is_binary_test(word)
+}
+
+fn is_unary_test(operator: &str) -> bool {
+    matches!(
+        operator,
+        "-v" | "-z" | "-n" | "-e" | "-f" | "-d" | "-L" | "-h" | "-s" | "-r" | "-w" | "-x"
+    )
+}
+
+fn is_binary_test(operator: &str) -> bool {
+    matches!(
+        operator,
+        "==" | "=" | "!=" | "=~" | "<" | ">" | "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge"
+    )
+}
+
+// the right side is a pattern, matched as one by == and != and unescaped for the others
+fn binary_test(left: &str, operator: &str, pattern: &str) -> Result<bool, String> {
+    let right = &unescape_pattern(pattern);
+    let integer = |value: &str| {
+        value
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| format!("{}: integer expression expected", value))
+    };
+    Ok(match operator {
+        "==" | "=" => pattern_matches(pattern, left),
+        "!=" => !pattern_matches(pattern, left),
+        "=~" => Regex::new(right)
+            .map_err(|_| format!("{}: invalid regular expression", right))?
+            .is_match(left),
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" => integer(left)? == integer(right)?,
+        "-ne" => integer(left)? != integer(right)?,
+        "-lt" => integer(left)? < integer(right)?,
+        "-le" => integer(left)? <= integer(right)?,
+        "-gt" => integer(left)? > integer(right)?,
+        "-ge" => integer(left)? >= integer(right)?,
+        _ => unreachable!(),
+    })
+}
+
+// backslash escapes as understood by echo -e and printf %b, \c stops all further output
+fn echo_escapes(text: &str) -> (String, bool) {
+    let mut output = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('c') => return (output, true),
+            Some('0') => output.push(char::from(read_digits(&mut chars, 8, 3) as u8)),
+            Some('x') if chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) => {
+                output.push(char::from(read_digits(&mut chars, 16, 2) as u8))
+            }
+            Some(escape) => match simple_escape(escape) {
+                Some(c) => output.push(c),
+                None => {
+                    output.push('\\');
+                    output.push(escape);
+                }
+            },
+            None => output.push('\\'),
+        }
+    }
+    (output, false)
+}
+
+// backslash escapes in a printf format string, \NNN is octal without the leading 0
+fn format_escapes(text: &str) -> String {
+    let mut output = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.peek().copied() {
+            Some('0'..='7') => output.push(char::from(read_digits(&mut chars, 8, 3) as u8)),
+            Some('x') => {
+                chars.next();
+                if chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                    output.push(char::from(read_digits(&mut chars, 16, 2) as u8));
+                } else {
+                    output.push_str("\\x");
+                }
+            }
+            Some(escape) => {
+                chars.next();
+                match simple_escape(escape) {
+                    Some(c) => output.push(c),
+                    None => {
+                        output.push('\\');
+                        output.push(escape);
+                    }
+                }
+            }
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+fn simple_escape(escape: char) -> Option<char> {
+    Some(match escape {
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        'a' => '\x07',
+        'b' => '\x08',
+        'e' | 'E' => '\x1b',
+        'f' => '\x0c',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'v' => '\x0b',
+        _ => return None,
+    })
+}
+
+fn read_digits(chars: &mut std::iter::Peekable<std::str::Chars>, radix: u32, max: usize) -> u32 {
+    let mut value = 0;
+    for _ in 0..max {
+        match chars.peek().and_then(|c| c.to_digit(radix)) {
+            Some(digit) => {
+                value = value * radix + digit;
+                chars.next();
+            }
+            None => break,
+        }
+    }
+    value
+}
+
+#[derive(Default)]
+struct FormatSpec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec {
+    // pad a converted value to the field width, zero padding goes between sign and digits
+    fn pad(&self, sign: &str, body: &str, zero_padding: bool) -> String {
+        let length = sign.chars().count() + body.chars().count();
+        let fill = self.width.saturating_sub(length);
+        if self.left {
+            format!("{}{}{}", sign, body, " ".repeat(fill))
+        } else if zero_padding && self.zero {
+            format!("{}{}{}", sign, "0".repeat(fill), body)
+        } else {
+            format!("{}{}{}", " ".repeat(fill), sign, body)
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+}
+
+// output and error messages of printf, the format is reused until all arguments are consumed
+struct Printf<'a> {
+    args: &'a [String],
+    position: usize,
+    output: String,
+    errors: Vec<String>,
+}
+
+impl<'a> Printf<'a> {
+    fn format(format: &str, args: &'a [String]) -> Self {
+        let mut printf = Self {
+            args,
+            position: 0,
+            output: String::new(),
+            errors: Vec::new(),
+        };
+        let format = format_escapes(format);
+        loop {
+            let start = printf.position;
+            if printf.format_once(&format) {
+                break;
+            }
+            if printf.position == start || printf.position >= args.len() {
+                break;
+            }
+        }
+        printf
+    }
+
+    fn next_arg(&mut self) -> Option<&'a str> {
+        let arg = self.args.get(self.position).map(|arg| arg.as_str());
+        self.position += 1;
+        arg
+    }
+
+    fn next_integer(&mut self) -> i64 {
+        let arg = self.next_arg().unwrap_or("");
+        match parse_integer(arg) {
+            Some(value) => value,
+            None => {
+                self.errors.push(format!("{}: invalid number", arg));
+                0
+            }
+        }
+    }
+
+    fn next_float(&mut self) -> f64 {
+        let arg = self.next_arg().unwrap_or("");
+        if let Some(value) = parse_integer(arg) {
+            return value as f64;
+        }
+        match arg.trim().parse() {
+            Ok(value) => value,
+            Err(_) => {
+                self.errors.push(format!("{}: invalid number", arg));
+                0.0
+            }
+        }
+    }
+
+    // one pass over the format, returns true if \c in a %b argument stopped the output
+    fn format_once(&mut self, format: &str) -> bool {
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                self.output.push(c);
+                continue;
+            }
+            let mut spec = FormatSpec::default();
+            while let Some(&flag) = chars.peek() {
+                match flag {
+                    '-' => spec.left = true,
+                    '+' => spec.plus = true,
+                    ' ' => spec.space = true,
+                    '#' => spec.alternate = true,
+                    '0' => spec.zero = true,
+                    _ => break,
+                }
+                chars.next();
+            }
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                let width = self.next_integer();
+                spec.left |= width < 0;
+                spec.width = width.unsigned_abs() as usize;
+            } else {
+                spec.width = read_digits(&mut chars, 10, usize::MAX) as usize;
+            }
+            if chars.peek() == Some(&'.') {
+                chars.next();
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    spec.precision = usize::try_from(self.next_integer()).ok();
+                } else {
+                    spec.precision = Some(read_digits(&mut chars, 10, usize::MAX) as usize);
+                }
+            }
+
+            let Some(conversion) = chars.next() else {
+                self.output.push('%');
+                break;
+            };
+            match conversion {
+                '%' => self.output.push('%'),
+                's' => {
+                    let arg = self.next_arg().unwrap_or("");
+                    self.push_string(&spec, arg);
+                }
+                // quoted like ${name@Q} so the shell reads it back as the same word
+                'q' => {
+                    let arg = single_quote(self.next_arg().unwrap_or(""));
+                    self.push_string(&spec, &arg);
+                }
+                'b' => {
+                    let (arg, stop) = echo_escapes(self.next_arg().unwrap_or(""));
+                    self.push_string(&spec, &arg);
+                    if stop {
+                        return true;
+                    }
+                }
+                'c' => {
+                    let arg = self.next_arg().unwrap_or("");
+                    let first: String = arg.chars().take(1).collect();
+                    self.output.push_str(&spec.pad("", &first, false));
+                }
+                'd' | 'i' => {
+                    let value = self.next_integer();
+                    let mut digits = value.unsigned_abs().to_string();
+                    if let Some(precision) = spec.precision {
+                        digits = format!("{:0>1$}", digits, precision);
+                    }
+                    let sign = spec.sign(value < 0);
+                    let padded = spec.pad(sign, &digits, spec.precision.is_none());
+                    self.output.push_str(&padded);
+                }
+                'u' | 'o' | 'x' | 'X' => {
+                    let value = self.next_integer() as u64;
+                    let mut digits = match conversion {
+                        'o' => format!("{:o}", value),
+                        'x' => format!("{:x}", value),
+                        'X' => format!("{:X}", value),
+                        _ => value.to_string(),
+                    };
+                    if let Some(precision) = spec.precision {
+                        digits = format!("{:0>1$}", digits, precision);
+                    }
+                    let prefix = match conversion {
+                        'o' if spec.alternate && !digits.starts_with('0') => "0",
+                        'x' if spec.alternate && value != 0 => "0x",
+                        'X' if spec.alternate && value != 0 => "0X",
+                        _ => "",
+                    };
+                    let padded = spec.pad(prefix, &digits, spec.precision.is_none());
+                    self.output.push_str(&padded);
+                }
+                'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                    let value = self.next_float();
+                    let body = format_float(value.abs(), conversion, &spec);
+                    let sign = spec.sign(value.is_sign_negative() && value != 0.0);
+                    let padded = spec.pad(sign, &body, value.is_finite());
+                    self.output.push_str(&padded);
+                }
+                _ => {
+                    self.errors
+                        .push(format!("%{}: invalid format character", conversion));
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn push_string(&mut self, spec: &FormatSpec, arg: &str) {
+        let text: String = match spec.precision {
+            Some(precision) => arg.chars().take(precision).collect(),
+            None => arg.to_string(),
+        };
+        self.output.push_str(&spec.pad("", &text, false));
+    }
+}
+
+// printf numeric arguments: decimal, 0x hex, 0 octal or 'c for a character code
+fn parse_integer(arg: &str) -> Option<i64> {
+    if let Some(quoted) = arg.strip_prefix(['\'', '"']) {
+        return Some(quoted.chars().next().map_or(0, |c| c as i64));
+    }
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Some(0);
+    }
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, arg.strip_prefix('+').unwrap_or(arg)),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn format_float(value: f64, conversion: char, spec: &FormatSpec) -> String {
+    if !value.is_finite() {
+        let text = if value.is_nan() { "nan" } else { "inf" };
+        return if conversion.is_ascii_uppercase() {
+            text.to_uppercase()
+        } else {
+            text.to_string()
+        };
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let text = match conversion.to_ascii_lowercase() {
+        'f' => format!("{:.*}", precision, value),
+        'e' => format_exponent(value, precision),
+        _ => {
+            // %g picks the shorter of %e and %f and drops trailing zeros
+            let precision = precision.max(1);
+            let exponent = if value == 0.0 {
+                0
+            } else {
+                value.log10().floor() as i32
+            };
+            let text = if exponent < -4 || exponent >= precision as i32 {
+                format_exponent(value, precision - 1)
+            } else {
+                format!(
+                    "{:.*}",
+                    (precision as i32 - 1 - exponent).max(0) as usize,
+                    value
+                )
+            };
+            if spec.alternate {
+                text
+            } else {
+                strip_fraction_zeros(&text)
+            }
+        }
+    };
+    if conversion.is_ascii_uppercase() {
+        text.to_uppercase()
+    } else {
+        text
+    }
+}
+
+// C style exponent notation like 3.140000e+00
+fn format_exponent(value: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = text.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+}
+
+fn strip_fraction_zeros(text: &str) -> String {
+    let (number, exponent) = match text.find('e') {
+        Some(index) => text.split_at(index),
+        None => (text, ""),
+    };
+    let number = if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    };
+    format!("{}{}", number, exponent)
+}
+
+// a here-doc body in a temporary file that is already unlinked, ready to be read from the start
+fn here_document(body: &str) -> std::io::Result<OwnedFd> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "trash-heredoc-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    file.write_all(body.as_bytes())?;
+    file.seek(std::io::SeekFrom::Start(0))?;
+    Ok(file.into())
+}
+
+// the plain description of an io error, without the "(os error N)" suffix
+fn io_error_message(error: &std::io::Error) -> String {
+    match error.raw_os_error() {
+        Some(code) => Errno::from_raw(code).desc().to_string(),
+        None => error.to_string(),
+    }
+}
+
+#[derive(Clone)]
+struct Variable {
+    value: Value,
+    exported: bool,
+    readonly: bool,
+    // declare -i, assigned values are evaluated as arithmetic expressions
+    integer: bool,
+}
+
+// a string, after declare -A a map from keys to strings, and after declare -a or name[n]=
+// a possibly sparse array
+#[derive(Clone)]
+enum Value {
+    Scalar(String),
+    Associative(HashMap<String, String>),
+    Indexed(BTreeMap<usize, String>),
+}
+
+impl Value {
+    // a string is like an array with only the element 0, which is also what $name is
+    fn get(&self, key: &str) -> Option<&str> {
+        match self {
+            Value::Scalar(value) => (key == "0").then_some(value.as_str()),
+            Value::Associative(map) => map.get(key).map(String::as_str),
+            Value::Indexed(elements) => key
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| elements.get(&index))
+                .map(String::as_str),
+        }
+    }
+
+    // sorted, though only the order of keys and values matching is promised, the indices of
+    // an indexed array are in numeric order
+    fn keys(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(_) => vec!["0".to_string()],
+            Value::Associative(map) => {
+                let mut keys: Vec<String> = map.keys().cloned().collect();
+                keys.sort();
+                keys
+            }
+            Value::Indexed(elements) => elements.keys().map(usize::to_string).collect(),
+        }
+    }
+
+    // the value as written after name= by declare, e.g. "text" or ([key]="text" )
+    fn declaration(&self) -> String {
+        match self {
+            Value::Scalar(value) => double_quote(value),
+            Value::Associative(_) | Value::Indexed(_) => {
+                let elements: String = self
+                    .keys()
+                    .into_iter()
+                    .filter_map(|key| Some(format!("[{}]={} ", key, double_quote(self.get(&key)?))))
+                    .collect();
+                format!("({})", elements)
+            }
+        }
+    }
+}
+
+// user and sys seconds used so far by the shell and its waited-for children together
+fn cpu_times() -> (f64, f64) {
+    let seconds =
+        |time: nix::sys::time::TimeVal| time.tv_sec() as f64 + time.tv_usec() as f64 / 1e6;
+    let mut times = (0.0, 0.0);
+    for who in [UsageWho::RUSAGE_SELF, UsageWho::RUSAGE_CHILDREN] {
+        if let Ok(usage) = getrusage(who) {
+            times.0 += seconds(usage.user_time());
+            times.1 += seconds(usage.system_time());
+        }
+    }
+    times
+}
+
+// ${name:offset:length} of a value for the text after the first colon, counted in
+// characters, a negative offset counts from the end and a negative length leaves that many
+// characters off the end, None unless offset and length are numbers
+fn substring(value: &str, spec: &str) -> Option<String> {
+    let (offset, length) = match spec.split_once(':') {
+        Some((offset, length)) => (offset, Some(length.trim().parse::<i64>().ok()?)),
+        None => (spec, None),
+    };
+    let offset: i64 = offset.trim().parse().ok()?;
+    let count = value.chars().count() as i64;
+    let start = if offset < 0 { count + offset } else { offset };
+    let end = match length {
+        Some(length) if length < 0 => count + length,
+        Some(length) => start.saturating_add(length),
+        None => count,
+    };
+    if start < 0 || start > count || end < start {
+        return Some(String::new());
+    }
+    let chars = value.chars().skip(start as usize);
+    Some(chars.take((end.min(count) - start) as usize).collect())
+}
+
+// a number, variable name or operator of an arithmetic expression
+enum Term {
+    Number(i64),
+    Name(String),
+    Operator(char),
+}
+
+fn arithmetic_terms(expression: &str) -> Result<Vec<Term>, &'static str> {
+    let mut terms = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => {}
+            '+' | '-' | '*' | '/' | '%' | '(' | ')' => terms.push(Term::Operator(c)),
+            _ if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek()
+                    && (next.is_ascii_alphanumeric() || next == '_')
+                {
+                    word.push(next);
+                    chars.next();
+                }
+                terms.push(match c.is_ascii_digit() {
+                    true => Term::Number(word.parse().map_err(|_| "value too great for base")?),
+                    false => Term::Name(word),
+                });
+            }
+            _ => return Err("syntax error: invalid arithmetic operator"),
+        }
+    }
+    Ok(terms)
+}
+
+// text in double quotes with the characters that are special inside them escaped
+fn double_quote(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+        .replace('`', "\\`");
+    format!("\"{}\"", escaped)
+}
+
+// text in single quotes so it can be read back as input, a quote inside becomes '\''
+fn single_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// where command -p looks for programs, whatever $PATH is set to
+const DEFAULT_PATH: &str = "/usr/bin:/bin";
+
+// the first executable file called name in the directories of path
+fn search_path(name: &str, path: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+    path.split(':')
+        .map(|dir| Path::new(if dir.is_empty() { "." } else { dir }).join(name))
+        .find(|path| path.is_file() && access(path, AccessFlags::X_OK).is_ok())
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// split a line read by `read` on IFS, the last name takes the rest of the line
+// escaped characters never separate fields
+fn split_read_fields(line: &[(char, bool)], ifs: &str, count: usize) -> Vec<String> {
+    let is_separator = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c);
+    let mut fields = Vec::new();
+    let mut rest = line;
+    for index in 0..count {
+        let start = rest
+            .iter()
+            .position(|c| !is_separator(c))
+            .unwrap_or(rest.len());
+        rest = &rest[start..];
+        let end = if index + 1 == count {
+            rest.iter()
+                .rposition(|c| !is_separator(c))
+                .map_or(0, |end| end + 1)
+        } else {
+            rest.iter().position(is_separator).unwrap_or(rest.len())
+        };
+        fields.push(rest[..end].iter().map(|&(c, _)| c).collect());
+        rest = &rest[end..];
+    }
+    fields
+}
+
+// the settings turned on with set -o name and off with set +o name
+struct Options {
+    // key bindings of the line editor, at most one of them is on
+    emacs: bool,
+    vi: bool,
+    // bash extensions like brace expansion and [[ ]] are turned off
+    posix: bool,
+    // how long every pipeline took is printed to stderr
+    timing: bool,
+    // a command repeating the one before isn't added to the history again
+    histignoredups: bool,
+    // job control: every job gets a process group of its own and fg and bg work
+    monitor: bool,
+    // aliases are replaced in commands, by default only in an interactive shell
+    expand_aliases: bool,
+    // set -n, commands are parsed but not run, which an interactive shell ignores
+    noexec: bool,
+    // ** as a whole path component matches any number of directories
+    globstar: bool,
+    // echo interprets backslash escapes without -e, like System V echo
+    xpg_echo: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            emacs: true,
+            vi: false,
+            posix: false,
+            timing: false,
+            histignoredups: false,
+            monitor: false,
+            expand_aliases: false,
+            noexec: false,
+            globstar: false,
+            xpg_echo: false,
+        }
+    }
+}
+
+impl Options {
+    // every option by name, in the order set -o lists them
+    fn by_name(&mut self) -> [(&'static str, &mut bool); 10] {
+        [
+            ("emacs", &mut self.emacs),
+            ("expand_aliases", &mut self.expand_aliases),
+            ("globstar", &mut self.globstar),
+            ("histignoredups", &mut self.histignoredups),
+            ("monitor", &mut self.monitor),
+            ("noexec", &mut self.noexec),
+            ("posix", &mut self.posix),
+            ("timing", &mut self.timing),
+            ("vi", &mut self.vi),
+            ("xpg_echo", &mut self.xpg_echo),
+        ]
+    }
+
+    fn set(&mut self, name: &str, on: bool) -> Result<(), String> {
+        let Some((_, option)) = self
+            .by_name()
+            .into_iter()
+            .find(|(option, _)| *option == name)
+        else {
+            return Err(format!("{}: invalid option name", name));
+        };
+        *option = on;
+        // turning on one editing mode turns off the other
+        match name {
+            "emacs" if on => self.vi = false,
+            "vi" if on => self.emacs = false,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// a function call in progress: the function and where it was called from
+struct Frame {
+    function: String,
+    line: usize,
+    file: String,
+}
+
+struct Shell {
+    parser: Parser,
+    shell_pid: Pid,
+    interactive: bool,
+    // started with -l or a name starting with -, runs ~/.trash_logout when it exits
+    login: bool,
+    last_status: i32,
+    last_bg_pid: Option<Pid>,
+    jobs: Vec<Job>,
+    // processes of <(...) and >(...) still to be reaped, they aren't jobs
+    substitutions: Vec<Pid>,
+    variables: HashMap<String, Variable>,
+    // $0 and the positional parameters $1, $2, ...
+    name: String,
+    positional: Vec<String>,
+    functions: HashMap<String, Rc<Node>>,
+    // one frame per running function, holding what its locals shadow
+    locals: Vec<HashMap<String, Option<Variable>>>,
+    // set by return, unwinds until the function or sourced script ends
+    returning: bool,
+    sourcing: usize,
+    // the file read by source, None for the script or input of the shell itself
+    source_file: Option<String>,
+    // $LINENO, the line of the command being run counted in the script or sourced file, in a
+    // function body the line it was defined on
+    lineno: usize,
+    calls: Vec<Frame>,
+    // trap actions by EXIT or signal name, an empty action ignores the signal
+    traps: HashMap<String, String>,
+    // the terminal's process group before the shell took it over
+    original_pgrp: Option<Pid>,
+    // the home directory from the password database, for when $HOME isn't set
+    passwd_home: OnceCell<Option<String>>,
+    options: Options,
+    fds: FdTable,
+    // descriptors above 2 opened for good by exec without a command
+    exec_fds: HashSet<RawFd>,
+    // builtins turned off with enable -n, their names are looked up in $PATH instead
+    disabled_builtins: HashSet<String>,
+    // commands read interactively, oldest first
+    history: Vec<String>,
+    // where commands were found in $PATH and how often that was used, cleared when it changes
+    hashed: HashMap<String, (PathBuf, usize)>,
+    aliases: HashMap<String, String>,
+    stdin_handle: std::io::Stdin,
+    stdout_handle: std::io::Stdout,
+}
+
+impl Shell {
+    fn new(interactive: bool) -> nix::Result<Self> {
+        let shell_pid = getpid();
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+        // job control needs a terminal to hand between process groups
+        let monitor = interactive && isatty(&stdin).unwrap_or(false);
+        let original_pgrp = if monitor {
+            tcgetpgrp(&stdin).ok()
+        } else {
+            None
+        };
+
+        if monitor {
+            // ignore signals
+            unsafe {
+                // required when shell process is not foreground and uses tcsetpgrp
+                signal(Signal::SIGTTOU, SigHandler::SigIgn)?;
+                // required for ignoring ctrl-z
+                signal(Signal::SIGTSTP, SigHandler::SigIgn)?;
+            }
+            setpgid(shell_pid, shell_pid)?;
+            tcsetpgrp(&stdin, shell_pid)?;
+        }
+
+        // the environment we were started with is exported to every child
+        let variables = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .map(|(name, value)| {
+                let variable = Variable {
+                    value: Value::Scalar(value),
+                    exported: true,
+                    readonly: false,
+                    integer: false,
+                };
+                (name, variable)
+            })
+            .collect();
+
+        Ok(Self {
+            parser: Parser::new(),
+            shell_pid,
+            interactive,
+            login: false,
+            last_status: 0,
+            last_bg_pid: None,
+            jobs: Vec::new(),
+            substitutions: Vec::new(),
+            variables,
+            name: "trash".to_string(),
+            positional: Vec::new(),
+            functions: HashMap::new(),
+            locals: Vec::new(),
+            returning: false,
+            sourcing: 0,
+            source_file: None,
+            lineno: 0,
+            calls: Vec::new(),
+            traps: HashMap::new(),
+            original_pgrp,
+            passwd_home: OnceCell::new(),
+            options: Options {
+                monitor,
+                expand_aliases: interactive,
+                ..Options::default()
+            },
+            exec_fds: HashSet::new(),
+            disabled_builtins: HashSet::new(),
+            history: Vec::new(),
+            hashed: HashMap::new(),
+            aliases: HashMap::new(),
+            fds: FdTable::default(),
+            stdin_handle: stdin,
+            stdout_handle: stdout,
+        })
+    }
+
+    fn run(&mut self) -> nix::Result<()> {
+        install_sigchld_handler()?;
+        install_sigwinch_handler()?;
+        self.install_signal_handlers()?;
+        loop {
+            self.run_pending_traps();
+            if CHILD_STATUS_CHANGED.swap(false, Ordering::Relaxed) {
+                self.notify_jobs();
+            }
+            let prompt = self.prompt();
+            self.lineno += 1;
+            let Some(mut input) = self.read_input(&prompt) else {
+                if self.interactive {
+                    println!("\nexit");
+                }
+                self.shutdown(self.last_status);
+            };
+
+            // keep reading with the PS2 prompt until the command is complete
+            let first_line = self.lineno;
+            let parsed = loop {
+                let parsed = self.parse_input(&input, first_line);
+                if !matches!(parsed, Err(ParseError::Incomplete)) {
+                    break parsed;
+                }
+                let prompt = self.get_var("PS2").unwrap_or("> ").to_string();
+                match self.read_input(&prompt) {
+                    Some(line) => input.push_str(&line),
+                    None => break parsed,
+                }
+            };
+            if self.interactive {
+                self.add_history(&input);
+            }
+            self.run_parsed(parsed)?;
+            self.lineno = first_line + input.lines().count().saturating_sub(1);
+        }
+    }
+
+    // HISTCONTROL holds ignorespace, ignoredups or ignoreboth separated by colons
+    fn add_history(&mut self, input: &str) {
+        let line = input.trim_end_matches('\n');
+        let control = self.get_var("HISTCONTROL").unwrap_or("");
+        let ignoring = |mode: &str| {
+            control
+                .split(':')
+                .any(|value| value == mode || value == "ignoreboth")
+        };
+        if line.trim().is_empty()
+            || ignoring("ignorespace") && line.starts_with(' ')
+            || (self.options.histignoredups || ignoring("ignoredups"))
+                && self.history.last().is_some_and(|last| last == line)
+        {
+            return;
+        }
+        self.history.push(line.to_string());
+    }
+
+    // PS1 with its backslash escapes replaced
+    fn prompt(&self) -> String {
+        let ps1 = self.get_var("PS1").unwrap_or("\n$ ");
+        let mut prompt = String::new();
+        let mut chars = ps1.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                prompt.push(c);
+                continue;
+            }
+            match chars.next() {
+                // running and stopped jobs alike
+                Some('j') => {
+                    let active = self
+                        .jobs
+                        .iter()
+                        .filter(|job| !matches!(job.state, JobState::Done(_)))
+                        .count();
+                    prompt.push_str(&active.to_string());
+                }
+                Some('n') => prompt.push('\n'),
+                Some('\\') | None => prompt.push('\\'),
+                Some(other) => {
+                    prompt.push('\\');
+                    prompt.push(other);
+                }
+            }
+        }
+        prompt
+    }
+
+    // show the prompt and read a line, edited in place when stdin is a terminal
+    // prompts only go out when a user is there to see them, commands piped in are read
+    // silently
+    fn read_input(&mut self, prompt: &str) -> Option<String> {
+        if !self.interactive {
+            return self.read_line();
+        }
+        print!("{}", prompt);
+        self.stdout_handle.flush().unwrap();
+        if let Some(mode) = TerminalMode::editing(self.stdin_handle.as_raw_fd()) {
+            let line = self.edit_line(prompt);
+            drop(mode);
+            return line;
+        }
+        self.read_line()
+    }
+
+    fn edit_line(&mut self, prompt: &str) -> Option<String> {
+        let fd = self.stdin_handle.as_raw_fd();
+        let mut editor = LineEditor::new(prompt);
+        editor.columns = terminal_columns(fd);
+        loop {
+            let byte = match editor.unread.take() {
+                Some(byte) => byte,
+                None => match self.read_byte(fd, None) {
+                    Ok(Some(byte)) => byte,
+                    _ => return None,
+                },
+            };
+            match byte {
+                b'\r' | b'\n' => {
+                    // below the last row of a line wrapping onto several
+                    editor.cursor = editor.line.len();
+                    print!("{}", editor.redraw());
+                    println!();
+                    editor.line.push('\n');
+                    return Some(editor.line);
+                }
+                // ctrl-d ends the input on an empty line and deletes otherwise
+                0x04 if editor.line.is_empty() => return None,
+                0x04 => editor.delete(),
+                // ctrl-c drops the line
+                0x03 => {
+                    println!("^C");
+                    editor = LineEditor {
+                        columns: editor.columns,
+                        ..LineEditor::new(prompt)
+                    };
+                }
+                // a lone escape switches vi to command mode
+                0x1b => {
+                    if !self.edit_escape(fd, &mut editor) && self.options.vi && !editor.vi_command {
+                        editor.vi_command = true;
+                        editor.left();
+                    }
+                }
+                _ if editor.vi_command => editor.vi_key(byte as char),
+                0x7f | 0x08 => editor.backspace(),
+                b'\t' => {
+                    let listing = self.complete(&mut editor);
+                    if !listing.is_empty() {
+                        println!();
+                        println!("{}", listing.join("  "));
+                        editor.cursor_row = 0;
+                    }
+                }
+                // ctrl-u and ctrl-w work in both modes, like in a terminal's own line editing
+                0x15 => editor.kill_to_start(),
+                0x17 => editor.kill_word(),
+                0x01 if self.options.emacs => editor.cursor = 0,
+                0x05 if self.options.emacs => editor.cursor = editor.line.len(),
+                0x0b if self.options.emacs => editor.kill_to_end(),
+                0x02 if self.options.emacs => editor.left(),
+                0x06 if self.options.emacs => editor.right(),
+                0x00..=0x1f => {}
+                _ => {
+                    // the rest of a multibyte character
+                    let length = match byte {
+                        0xc0..=0xdf => 2,
+                        0xe0..=0xef => 3,
+                        0xf0..=0xff => 4,
+                        _ => 1,
+                    };
+                    let mut bytes = vec![byte];
+                    while bytes.len() < length {
+                        match self.read_byte(fd, None) {
+                            Ok(Some(byte)) => bytes.push(byte),
+                            _ => return None,
+                        }
+                    }
+                    editor.insert(&String::from_utf8_lossy(&bytes));
+                }
+            }
+            if WINDOW_RESIZED.swap(false, Ordering::Relaxed) {
+                editor.columns = terminal_columns(fd);
+            }
+            print!("{}", editor.redraw());
+            self.stdout_handle.flush().unwrap();
+        }
+    }
+
+    // the keys that send an escape sequence, like the arrow keys, false for a lone escape
+    // which is told apart by nothing following right away
+    fn edit_escape(&mut self, fd: RawFd, editor: &mut LineEditor) -> bool {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        match self.read_byte(fd, Some(deadline)) {
+            Ok(Some(b'[' | b'O')) => {}
+            Ok(Some(byte)) => {
+                editor.unread = Some(byte);
+                return false;
+            }
+            _ => return false,
+        }
+        let mut parameters = String::new();
+        let last = loop {
+            match self.read_byte(fd, None) {
+                Ok(Some(byte @ 0x40..=0x7e)) => break byte,
+                Ok(Some(byte)) => parameters.push(byte as char),
+                _ => return true,
+            }
+        };
+        match (parameters.as_str(), last) {
+            ("", b'C') => editor.right(),
+            ("", b'D') => editor.left(),
+            ("", b'H') | ("1" | "7", b'~') => editor.cursor = 0,
+            ("", b'F') | ("4" | "8", b'~') => editor.cursor = editor.line.len(),
+            ("3", b'~') => editor.delete(),
+            _ => {}
+        }
+        editor.clamp();
+        true
+    }
+
+    // candidates for the word being completed as (text, is a directory)
+    fn completions(&self, words: &[String], word: &str) -> Vec<(String, bool)> {
+        if !words.is_empty() || word.contains('/') || word.starts_with('~') {
+            // these commands only take directories
+            let directories = matches!(
+                words.first().map(String::as_str),
+                Some("cd" | "pushd" | "rmdir")
+            );
+            return self.path_completions(word, directories);
+        }
+        let mut candidates = Vec::new();
+        let names = BuiltinCommand::NAMES
+            .into_iter()
+            .map(str::to_string)
+            .chain(self.functions.keys().cloned())
+            .filter(|name| name.starts_with(word));
+        candidates.extend(names.map(|name| (name, false)));
+        for dir in self.get_var("PATH").unwrap_or_default().split(':') {
+            let dir = Path::new(if dir.is_empty() { "." } else { dir });
+            let executables = directory_entries(dir, word)
+                .into_iter()
+                .filter(|(name, _)| {
+                    let path = dir.join(name);
+                    path.is_file() && access(&path, AccessFlags::X_OK).is_ok()
+                });
+            candidates.extend(executables);
+        }
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    // files and directories the word is the start of, only directories for cd and the like
+    fn path_completions(&self, word: &str, directories: bool) -> Vec<(String, bool)> {
+        // ~ and the start of a user name complete to the name, which stands for a directory
+        if let Some(prefix) = word.strip_prefix('~')
+            && !prefix.contains('/')
+        {
+            let users = user_names(prefix).into_iter();
+            return users.map(|name| (format!("~{}", name), true)).collect();
+        }
+        let mut candidates = Vec::new();
+        let (dir, prefix) = match word.rfind('/') {
+            Some(index) => word.split_at(index + 1),
+            None => ("", word),
+        };
+        // a leading ~ is kept in the completed text but looked up as the home directory
+        let search = match self.expand_tilde(dir) {
+            Some((home, rest)) => format!("{}{}", home, rest),
+            None => dir.to_string(),
+        };
+        let mut dirs = vec![PathBuf::from(if dir.is_empty() { "." } else { &search })];
+        // cd also finds relative directories in $CDPATH
+        if directories && !word.starts_with('/') && !word.starts_with('.') {
+            let cdpath = self.get_var("CDPATH").unwrap_or_default().split(':');
+            dirs.extend(
+                cdpath
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| Path::new(entry).join(dir)),
+            );
+        }
+        for search in dirs {
+            let entries = directory_entries(&search, prefix)
+                .into_iter()
+                .filter(|(_, is_dir)| *is_dir || !directories)
+                .map(|(name, is_dir)| (format!("{}{}", dir, name), is_dir));
+            candidates.extend(entries);
+        }
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    // complete the word before the cursor as far as the candidates agree, a unique match is
+    // finished with a space or the / of a directory, the choices are returned when ambiguous
+    fn complete(&self, editor: &mut LineEditor) -> Vec<String> {
+        let (start, word, words, redirect) = completion_context(&editor.line[..editor.cursor]);
+        // a redirection takes a file whatever the command is
+        let candidates = if redirect {
+            self.path_completions(&word, false)
+        } else {
+            self.completions(&words, &word)
+        };
+        let completed = match candidates.as_slice() {
+            [] => return Vec::new(),
+            [(text, true)] => format!("{}/", text),
+            [(text, false)] => format!("{} ", text),
+            [(first, _), rest @ ..] => {
+                let common = rest.iter().fold(first.as_str(), |common, (text, _)| {
+                    let length = common
+                        .char_indices()
+                        .zip(text.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(common.len().min(text.len()), |((index, _), _)| index);
+                    &common[..length]
+                });
+                if common.len() <= word.len() {
+                    let names = candidates.iter().map(|(text, is_dir)| {
+                        let name = text.rsplit('/').next().unwrap_or_default();
+                        if *is_dir {
+                            format!("{}/", name)
+                        } else {
+                            name.to_string()
+                        }
+                    });
+                    return names.collect();
+                }
+                common.to_string()
+            }
+        };
+        editor.line.replace_range(start..editor.cursor, "");
+        editor.cursor = start;
+        let escaped = escape_completion(completed.trim_end_matches(' '));
+        editor.insert(&escaped);
+        if completed.ends_with(' ') {
+            editor.insert(" ");
+        }
+        Vec::new()
+    }
+
+    // read a line from stdin without reading ahead, a signal interrupting the read runs
+    // its trap before reading continues
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            match read(&self.stdin_handle, &mut byte) {
+                Ok(0) if line.is_empty() => return None,
+                Ok(0) => break,
+                Ok(_) => {
+                    line.push(byte[0]);
+                    if byte[0] == b'\n' {
+                        break;
+                    }
+                }
+                Err(Errno::EINTR) => self.run_pending_traps(),
+                Err(_) => return None,
+            }
+        }
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    // the disposition a signal has while no trap is set for it
+    fn default_disposition(&self, sig: Signal) -> nix::Result<()> {
+        let handler = match sig {
+            // caught so the EXIT trap still runs when the shell is told to go away
+            Signal::SIGHUP => return catch_signal(sig),
+            Signal::SIGTERM if !self.interactive => return catch_signal(sig),
+            Signal::SIGTERM | Signal::SIGTSTP | Signal::SIGTTOU if self.interactive => {
+                SigHandler::SigIgn
+            }
+            _ => SigHandler::SigDfl,
+        };
+        unsafe { signal(sig, handler) }?;
+        Ok(())
+    }
+
+    fn install_signal_handlers(&self) -> nix::Result<()> {
+        self.default_disposition(Signal::SIGHUP)?;
+        self.default_disposition(Signal::SIGTERM)
+    }
+
+    fn set_trap(&mut self, name: &str, action: Option<String>) -> nix::Result<()> {
+        if name != "EXIT" {
+            let sig: Signal = format!("SIG{}", name).parse()?;
+            match action.as_deref() {
+                Some("") => unsafe { signal(sig, SigHandler::SigIgn) }.map(drop)?,
+                Some(_) => catch_signal(sig)?,
+                None => self.default_disposition(sig)?,
+            }
+        }
+        match action {
+            Some(action) => self.traps.insert(name.to_string(), action),
+            None => self.traps.remove(name),
+        };
+        Ok(())
+    }
+
+    // a trap runs between commands and leaves $? as it was
+    fn run_trap(&mut self, action: &str) {
+        let status = self.last_status;
+        let _ = self.run_str(action);
+        self.last_status = status;
+    }
+
+    fn run_pending_traps(&mut self) {
+        let pending = PENDING_SIGNALS.swap(0, Ordering::Relaxed);
+        for number in (1..64).filter(|number| pending & (1 << number) != 0) {
+            let Ok(signal) = Signal::try_from(number) else {
+                continue;
+            };
+            match self.traps.get(signal.as_str().trim_start_matches("SIG")) {
+                Some(action) => {
+                    let action = action.clone();
+                    self.run_trap(&action);
+                }
+                // only signals that end the shell are caught without a trap
+                None => self.terminate(signal),
+            }
+        }
+    }
+
+    // every way the shell ends goes through here: the EXIT trap runs once, then unless a
+    // signal ended it a login shell runs ~/.trash_logout, the terminal is handed back to the
+    // process group that had it and pending output is flushed
+    fn finish(&mut self, logout: bool) {
+        if let Some(action) = self.traps.remove("EXIT") {
+            self.run_trap(&action);
+        }
+        if logout
+            && std::mem::take(&mut self.login)
+            && let Some(home) = self.home_of("")
+            && let Ok(script) = std::fs::read_to_string(Path::new(&home).join(".trash_logout"))
+        {
+            let _ = self.source(".trash_logout", &script);
+        }
+        if self.options.monitor
+            && let Some(pgrp) = self.original_pgrp
+        {
+            let _ = tcsetpgrp(&self.stdin_handle, pgrp);
+        }
+        let _ = self.stdout_handle.flush();
+        let _ = std::io::stderr().flush();
+    }
+
+    // a login shell reads ~/.trash_profile, any other interactive one the rc file,
+    // ~/.trashrc unless another one is given
+    fn read_startup_files(&mut self, rcfile: Option<&str>) {
+        let path = match (self.login, rcfile) {
+            (true, _) => self
+                .home_of("")
+                .map(|home| Path::new(&home).join(".trash_profile")),
+            (false, _) if !self.interactive => None,
+            (false, Some(rcfile)) => Some(PathBuf::from(rcfile)),
+            (false, None) => self
+                .home_of("")
+                .map(|home| Path::new(&home).join(".trashrc")),
+        };
+        if let Some(path) = path
+            && let Ok(script) = std::fs::read_to_string(&path)
+        {
+            let _ = self.source(&path.to_string_lossy(), &script);
+        }
+    }
+
+    fn shutdown(&mut self, status: i32) -> ! {
+        // an interactive shell takes its jobs with it, a script leaves them running
+        if self.interactive {
+            self.hangup_jobs();
+        }
+        self.finish(true);
+        exit(status);
+    }
+
+    // die from the signal itself so the parent sees how the shell ended
+    fn terminate(&mut self, signal: Signal) -> ! {
+        if signal == Signal::SIGHUP {
+            self.hangup_jobs();
+        }
+        self.finish(false);
+        unsafe {
+            let _ = sigaction(
+                signal,
+                &SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty()),
+            );
+        }
+        let _ = raise(signal);
+        exit(128 + signal as i32);
+    }
+
+    // run a script in the current shell, where return ends it
+    fn source(&mut self, path: &str, script: &str) -> nix::Result<()> {
+        self.sourcing += 1;
+        let outer_file = self.source_file.replace(path.to_string());
+        let outer_line = self.lineno;
+        let result = self.run_script(script);
+        self.source_file = outer_file;
+        self.lineno = outer_line;
+        self.sourcing -= 1;
+        self.returning = false;
+        result
+    }
+
+    // state a forked subshell doesn't inherit
+    fn enter_subshell(&mut self) {
+        self.substitutions.clear();
+        self.interactive = false;
+        self.options.monitor = false;
+        self.login = false;
+        self.jobs.clear();
+        self.traps.retain(|_, action| action.is_empty());
+    }
+
+    // signals go back to their defaults for a new program, ignored ones stay ignored
+    fn reset_signals(&self) {
+        let signals = [
+            Signal::SIGHUP,
+            Signal::SIGINT,
+            Signal::SIGQUIT,
+            Signal::SIGTERM,
+            Signal::SIGPIPE,
+            Signal::SIGTSTP,
+            Signal::SIGTTOU,
+            Signal::SIGCHLD,
+        ];
+        let trapped = self
+            .traps
+            .keys()
+            .filter_map(|name| format!("SIG{}", name).parse::<Signal>().ok());
+        for sig in signals.into_iter().chain(trapped) {
+            let name = sig.as_str().trim_start_matches("SIG");
+            if self.traps.get(name).is_some_and(|action| action.is_empty()) {
+                continue;
+            }
+            unsafe {
+                let _ = signal(sig, SigHandler::SigDfl);
+            }
+        }
+    }
+
+    // commands are numbered from first_line on, for $LINENO
+    fn parse_input(&self, input: &str, first_line: usize) -> Result<Vec<AndOrList>, ParseError> {
+        let aliases = self.options.expand_aliases.then_some(&self.aliases);
+        self.parser
+            .parse(self.parser.tokenize(input), aliases, first_line)
+    }
+
+    fn run_str(&mut self, input: &str) -> nix::Result<()> {
+        let parsed = self.parse_input(input, self.lineno.max(1));
+        self.run_parsed(parsed)
+    }
+
+    fn run_parsed(&mut self, parsed: Result<Vec<AndOrList>, ParseError>) -> nix::Result<()> {
+        match parsed {
+            Ok(lists) => self.execute_lists(&lists)?,
+            Err(ParseError::Unexpected(token)) => {
+                eprintln!("trash: syntax error near unexpected token `{}'", token);
+                self.last_status = 2;
+            }
+            Err(ParseError::Incomplete) => {
+                eprintln!("trash: syntax error: unexpected end of file");
+                self.last_status = 2;
+            }
+        }
+
+        Ok(())
+    }
+
+    // run a script a complete command at a time, so a command spanning several lines (or
+    // followed by here-doc bodies) runs once its last line is read, blank and comment lines
+    // are skipped without touching $?
+    fn run_script(&mut self, script: &str) -> nix::Result<()> {
+        let mut ran_command = false;
+        let mut input = String::new();
+        let mut first_line = 0;
+        for (index, line) in script.lines().enumerate() {
+            let trimmed = line.trim_start();
+            if input.is_empty() && (trimmed.is_empty() || trimmed.starts_with('#')) {
+                continue;
+            }
+            if input.is_empty() {
+                first_line = index + 1;
+            }
+            input.push_str(line);
+            input.push('\n');
+            let parsed = self.parse_input(&input, first_line);
+            if let Err(ParseError::Incomplete) = parsed {
+                continue;
+            }
+            ran_command = true;
+            input.clear();
+            self.lineno = first_line;
+            self.run_parsed(parsed)?;
+            if self.returning {
+                break;
+            }
+        }
+        if !input.is_empty() && !self.returning {
+            ran_command = true;
+            self.run_parsed(Err(ParseError::Incomplete))?;
+        }
+        if !ran_command {
+            self.last_status = 0;
+        }
+        Ok(())
+    }
+
+    fn expand_variables(&self, text: &str) -> String {
+        let mut expanded = String::new();
+        let mut last = 0;
+        for parameter in self.parser.parameters(text) {
+            let start = parameter.caps.get(0).unwrap().start();
+            expanded.push_str(&text[last..start]);
+            expanded.push_str(&self.expand_match(&parameter));
+            last = start + parameter.text.len();
+        }
+        expanded.push_str(&text[last..]);
+        expanded
+    }
+
+    // what one parameter expansion expands to
+    fn expand_match(&self, parameter: &Parameter) -> String {
+        let caps = &parameter.caps;
+        if let Some(name) = caps.get(1) {
+            return self.parameter(name.as_str());
+        }
+        let name = &caps[3];
+        let indirect = caps.get(2).is_some();
+        let value = match (caps.get(4), caps.get(5).is_some()) {
+            (Some(subscript), false) => self.elements(name, subscript.as_str(), indirect),
+            (None, false) if indirect => self.indirect(&self.parameter(name)),
+            (None, false) => self.parameter(name),
+            (None, true) if indirect => {
+                let mut names: Vec<&str> = self
+                    .variables
+                    .keys()
+                    .map(String::as_str)
+                    .filter(|variable| variable.starts_with(name))
+                    .collect();
+                names.sort();
+                names.join(" ")
+            }
+            // like ${name*}, not an expansion at all
+            _ => return parameter.text.to_string(),
+        };
+        // :- right after the name is always a default, an offset starting with a minus
+        // has to be set apart from the colon, as in ${name: -3}
+        match caps.get(7).map(|operator| operator.as_str()) {
+            Some(":-") if value.is_empty() => return self.expand_variables(parameter.word),
+            Some(":-") => return value,
+            Some(_) => {
+                return match substring(&value, &self.expand_variables(parameter.word)) {
+                    Some(substring) => substring,
+                    None => parameter.text.to_string(),
+                };
+            }
+            None => {}
+        }
+        match caps.get(6).map(|operator| operator.as_str()) {
+            // an unset variable quotes to nothing, not to ''
+            Some("Q") if value.is_empty() && !self.variables.contains_key(name) => value,
+            Some("Q") => single_quote(&value),
+            Some("U") => value.to_uppercase(),
+            Some("L") => value.to_lowercase(),
+            Some("u") => {
+                let mut chars = value.chars();
+                chars.next().map_or(String::new(), |first| {
+                    first.to_uppercase().chain(chars).collect()
+                })
+            }
+            _ => value,
+        }
+    }
+
+    // the pieces text expands to, where an unquoted expansion is split later and "$@" is a
+    // field for every parameter
+    fn expand_segments(&self, text: &str, quoted: bool, pieces: &mut Vec<Piece>) {
+        let mut last = 0;
+        for parameter in self.parser.parameters(text) {
+            let start = parameter.caps.get(0).unwrap().start();
+            if start > last {
+                pieces.push(Piece::Text(text[last..start].to_string(), quoted));
+            }
+            last = start + parameter.text.len();
+            match self.fields(&parameter.caps) {
+                Some((fields, at)) if at || !quoted => {
+                    for (index, field) in fields.into_iter().enumerate() {
+                        if index > 0 {
+                            pieces.push(Piece::Break);
+                        }
+                        pieces.push(match quoted {
+                            true => Piece::Text(field, true),
+                            false => Piece::Split(field),
+                        });
+                    }
+                }
+                _ => {
+                    let value = self.expand_match(&parameter);
+                    pieces.push(match quoted {
+                        true => Piece::Text(value, true),
+                        false => Piece::Split(value),
+                    });
+                }
+            }
+        }
+        if last < text.len() {
+            pieces.push(Piece::Text(text[last..].to_string(), quoted));
+        }
+    }
+
+    // the values $@, $*, ${name[@]} and ${name[*]} stand for, and whether it's the @ form
+    fn fields(&self, caps: &regex::Captures) -> Option<(Vec<String>, bool)> {
+        if (2..=7).any(|group| group != 3 && group != 4 && caps.get(group).is_some()) {
+            return None;
+        }
+        let name = caps.get(1).or_else(|| caps.get(3))?.as_str();
+        match caps.get(4).map(|subscript| subscript.as_str()) {
+            None if matches!(name, "@" | "*") => Some((self.positional.clone(), name == "@")),
+            Some(subscript @ ("@" | "*")) => {
+                let values = self.variables.get(name).map_or(Vec::new(), |variable| {
+                    let value = &variable.value;
+                    let keys = value.keys();
+                    let values = keys.iter().filter_map(|key| value.get(key));
+                    values.map(str::to_string).collect()
+                });
+                Some((values, subscript == "@"))
+            }
+            _ => None,
+        }
+    }
+
+    // what "$*" puts between the parameters, the first character of $IFS
+    fn ifs_separator(&self) -> String {
+        match self.get_var("IFS") {
+            Some(ifs) => ifs.chars().take(1).collect(),
+            None => " ".to_string(),
+        }
+    }
+
+    // ${!name} expands the parameter or array element named by the value of name, only one
+    // level is followed so a variable naming itself can't loop
+    fn indirect(&self, target: &str) -> String {
+        if let Some((name, subscript)) = target
+            .strip_suffix(']')
+            .and_then(|target| target.split_once('['))
+            && is_valid_name(name)
+        {
+            return self.elements(name, subscript, false);
+        }
+        match is_valid_name(target)
+            || target.parse::<usize>().is_ok()
+            || matches!(target, "$" | "!" | "?" | "#" | "@" | "*")
+        {
+            true => self.parameter(target),
+            false => String::new(),
+        }
+    }
+
+    // ${name[key]}, with @ or * as the key all values, with ! in front the keys instead
+    fn elements(&self, name: &str, subscript: &str, keys: bool) -> String {
+        let Some(variable) = self.variables.get(name) else {
+            return String::new();
+        };
+        let value = &variable.value;
+        match subscript {
+            "@" | "*" if keys => value.keys().join(" "),
+            "@" | "*" => {
+                let values: Vec<&str> = value
+                    .keys()
+                    .into_iter()
+                    .filter_map(|key| value.get(&key))
+                    .collect();
+                match subscript {
+                    "*" => values.join(&self.ifs_separator()),
+                    _ => values.join(" "),
+                }
+            }
+            key => {
+                let element = value.get(&self.expand_variables(key)).unwrap_or_default();
+                match keys {
+                    true => self.indirect(element),
+                    false => element.to_string(),
+                }
+            }
+        }
+    }
+
+    // the value of a variable, positional or special parameter, empty if it's unset
+    fn parameter(&self, name: &str) -> String {
+        match name {
+            "$" => self.shell_pid.to_string(),
+            "!" => self
+                .last_bg_pid
+                .map(|pid| pid.to_string())
+                .unwrap_or_default(),
+            "?" => self.last_status.to_string(),
+            "#" => self.positional.len().to_string(),
+            "@" => self.positional.join(" "),
+            "*" => self.positional.join(&self.ifs_separator()),
+            "LINENO" => self.lineno.to_string(),
+            name => match name.parse::<usize>() {
+                Ok(0) => self.name.clone(),
+                Ok(index) => self.positional.get(index - 1).cloned().unwrap_or_default(),
+                Err(_) => self.get_var(name).unwrap_or_default().to_string(),
+            },
+        }
+    }
+
+    fn expand_part(&self, (text, quoting): &(String, Quoting)) -> String {
+        match quoting {
+            Quoting::SingleQuoted | Quoting::Expanded => text.clone(),
+            // only commands and redirections run them, anywhere else they stay as written
+            Quoting::ProcessIn => format!("<({})", text),
+            Quoting::ProcessOut => format!(">({})", text),
+            Quoting::Command | Quoting::QuotedCommand => format!("$({})", text),
+            _ => self.expand_variables(text),
+        }
+    }
+
+    // the home directory ~ (empty user) or ~user stands for
+    fn home_of(&self, user: &str) -> Option<String> {
+        if !user.is_empty() {
+            let user = User::from_name(user).ok().flatten()?;
+            return Some(user.dir.to_string_lossy().into_owned());
+        }
+        // $HOME is always used as it is now
+        if let Some(dir) = self.get_var("HOME") {
+            return Some(dir.to_string());
+        }
+        self.passwd_home()
+    }
+
+    // the user's home directory from the password database, only looked up once
+    fn passwd_home(&self) -> Option<String> {
+        self.passwd_home
+            .get_or_init(|| {
+                let user = User::from_uid(getuid()).ok().flatten()?;
+                Some(user.dir.to_string_lossy().into_owned())
+            })
+            .clone()
+    }
+
+    // $PWD as long as it is an absolute name of the current directory, which it stops being
+    // when a directory on the way is renamed, otherwise the physical path
+    fn current_dir(&self) -> nix::Result<PathBuf> {
+        let same =
+            |a: &std::fs::Metadata, b: &std::fs::Metadata| a.dev() == b.dev() && a.ino() == b.ino();
+        if let Some(pwd) = self.get_var("PWD")
+            && pwd.starts_with('/')
+            && let (Ok(named), Ok(current)) = (std::fs::metadata(pwd), std::fs::metadata("."))
+            && same(&named, &current)
+        {
+            return Ok(normalize_path(Path::new(pwd)));
+        }
+        getcwd()
+    }
+
+    // the home directory for a leading ~ or ~user up to the first slash, and what follows it
+    fn expand_tilde<'a>(&self, text: &'a str) -> Option<(String, &'a str)> {
+        let tilde = text.strip_prefix('~')?;
+        let (user, rest) = tilde.split_at(tilde.find('/').unwrap_or(tilde.len()));
+        Some((self.home_of(user)?, rest))
+    }
+
+    // the expanded parts of a word and whether each was quoted, a home directory from tilde
+    // expansion counts as quoted
+    fn expand_parts(&self, word: &Word) -> Vec<(String, bool)> {
+        let mut parts = Vec::new();
+        let mut rest = &word.parts[..];
+        // quoted characters before the first slash prevent tilde expansion
+        if let Some(((text, Quoting::Unquoted), after)) = word.parts.split_first()
+            && (after.is_empty() || text.contains('/'))
+            && let Some((home, path)) = self.expand_tilde(text)
+        {
+            parts.push((home, true));
+            parts.push((self.expand_variables(path), false));
+            rest = after;
+        }
+        parts.extend(
+            rest.iter()
+                .map(|part| (self.expand_part(part), part.1 != Quoting::Unquoted)),
+        );
+        parts
+    }
+
+    // like expand_parts, but in pieces that split_fields makes the fields of the word from
+    fn expand_pieces(&self, word: &Word) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        let mut rest = &word.parts[..];
+        if let Some(((text, Quoting::Unquoted), after)) = word.parts.split_first()
+            && (after.is_empty() || text.contains('/'))
+            && let Some((home, path)) = self.expand_tilde(text)
+        {
+            pieces.push(Piece::Text(home, true));
+            self.expand_segments(path, false, &mut pieces);
+            rest = after;
+        }
+        for part in rest {
+            match part.1 {
+                Quoting::Unquoted => self.expand_segments(&part.0, false, &mut pieces),
+                // "" is an empty field, "$@" without parameters isn't any
+                Quoting::DoubleQuoted if part.0.is_empty() => {
+                    pieces.push(Piece::Text(String::new(), true));
+                }
+                Quoting::DoubleQuoted => self.expand_segments(&part.0, true, &mut pieces),
+                Quoting::Expanded => pieces.push(Piece::Split(part.0.clone())),
+                _ => pieces.push(Piece::Text(self.expand_part(part), true)),
+            }
+        }
+        pieces
+    }
+
+    // the fields the pieces make, each as text and as a pattern with its quoted characters
+    // escaped. unquoted expansions are split at the characters of $IFS: runs of its
+    // whitespace separate fields and are dropped at either end, any other of its characters
+    // ends a field, an empty one if there is nothing before it
+    fn split_fields(&self, pieces: Vec<Piece>) -> Vec<(String, String)> {
+        let ifs = self.get_var("IFS").unwrap_or(" \t\n");
+        let mut fields = Vec::new();
+        let mut field: Option<(String, String)> = None;
+        // a field just ended at whitespace, which a following delimiter belongs to
+        let mut after_blank = false;
+        for piece in pieces {
+            match piece {
+                Piece::Text(text, quoted) => {
+                    let (field_text, pattern) = field.get_or_insert_default();
+                    push_pattern(pattern, &text, quoted);
+                    field_text.push_str(&text);
+                    after_blank = false;
+                }
+                Piece::Break => {
+                    fields.extend(field.take());
+                    after_blank = false;
+                }
+                Piece::Split(value) => {
+                    for c in value.chars() {
+                        if !ifs.contains(c) {
+                            let (field_text, pattern) = field.get_or_insert_default();
+                            push_pattern(pattern, c.encode_utf8(&mut [0; 4]), false);
+                            field_text.push(c);
+                            after_blank = false;
+                        } else if c.is_whitespace() {
+                            if let Some(ended) = field.take() {
+                                fields.push(ended);
+                                after_blank = true;
+                            }
+                        } else {
+                            match field.take() {
+                                Some(ended) => fields.push(ended),
+                                None if !after_blank => fields.push(Default::default()),
+                                None => {}
+                            }
+                            after_blank = false;
+                        }
+                    }
+                }
+            }
+        }
+        fields.extend(field);
+        fields
+    }
+
+    fn expand_word(&self, word: &Word) -> String {
+        self.expand_parts(word)
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect()
+    }
+
+    // NAME=value words, the value is expanded like any other word
+    fn expand_assignment(&self, word: &Word) -> Option<(String, String)> {
+        let (text, quoting) = word.parts.first()?;
+        if *quoting != Quoting::Unquoted {
+            return None;
+        }
+        let (name, value) = text.split_once('=')?;
+        // the key of an element is expanded, e.g. in map[$key]=value
+        let name = match name.strip_suffix(']').and_then(|name| name.split_once('[')) {
+            Some((name, key)) if is_valid_name(name) => {
+                format!("{}[{}]", name, self.expand_variables(key))
+            }
+            _ if is_valid_name(name) => name.to_string(),
+            _ => return None,
+        };
+        // the value is expanded like a word of its own, so it may start with ~ as well
+        let mut parts = vec![(value.to_string(), Quoting::Unquoted)];
+        parts.extend(word.parts[1..].iter().cloned());
+        Some((name, self.expand_word(&Word { parts })))
+    }
+
+    // the words a word brace expands to, each expanded like expand_pathnames does
+    fn expand_fields(&self, word: &Word) -> Vec<String> {
+        // without an unquoted { the word is kept whole, empty quoted parts included
+        let braced = word
+            .parts
+            .iter()
+            .any(|(text, quoting)| *quoting == Quoting::Unquoted && text.contains('{'));
+        if self.options.posix || !braced {
+            return self.expand_pathnames(word);
+        }
+        expand_braces(word)
+            .iter()
+            .flat_map(|word| self.expand_pathnames(word))
+            .collect()
+    }
+
+    // the fields of the expanded word, each replaced by the file names it matches if its
+    // unquoted parts form a pattern
+    fn expand_pathnames(&self, word: &Word) -> Vec<String> {
+        let fields = self.split_fields(self.expand_pieces(word));
+        fields
+            .into_iter()
+            .flat_map(|(text, pattern)| {
+                if has_glob_chars(&pattern) {
+                    let matches = glob(&pattern, self.options.globstar);
+                    if !matches.is_empty() {
+                        return matches;
+                    }
+                }
+                vec![text]
+            })
+            .collect()
+    }
+
+    // a word of [[ ]] expanded to a pattern, where one that only reads like an operator because
+    // it was quoted or expanded is escaped too
+    fn conditional_word(&self, word: &Word) -> String {
+        if let Some(operator) = word.literal().filter(|text| is_conditional_operator(text)) {
+            return operator.to_string();
+        }
+        let (_, pattern) = self.expand_pattern(word);
+        match is_conditional_operator(&pattern) {
+            true => format!("\\{}", pattern),
+            false => pattern,
+        }
+    }
+
+    // the expanded word, and the same as a pattern with its quoted characters escaped
+    fn expand_pattern(&self, word: &Word) -> (String, String) {
+        let mut text = String::new();
+        let mut pattern = String::new();
+        for (expanded, quoted) in self.expand_parts(word) {
+            push_pattern(&mut pattern, &expanded, quoted);
+            text.push_str(&expanded);
+        }
+        (text, pattern)
+    }
+
+    // split into leading assignments and the expanded arguments of the command
+    fn expand_command(&self, command: &SimpleCommand) -> (Vec<(String, String)>, Vec<String>) {
+        let mut assignments = Vec::new();
+        let mut words = command.words.iter();
+        let mut args = Vec::new();
+        for word in words.by_ref() {
+            match self.expand_assignment(word) {
+                Some(assignment) => assignments.push(assignment),
+                None => {
+                    args.extend(self.expand_fields(word));
+                    break;
+                }
+            }
+        }
+        // the operands of [[ ]] are patterns and strings, not file names
+        if args.first().is_some_and(|arg| arg == "[[") && !self.options.posix {
+            args.extend(words.map(|word| self.conditional_word(word)));
+        } else if args.first().is_some_and(|arg| {
+            matches!(
+                arg.as_str(),
+                "declare" | "typeset" | "local" | "export" | "readonly"
+            )
+        }) {
+            // assignments given to declaration builtins aren't split, as in export x=$y
+            for word in words {
+                match self.expand_assignment(word) {
+                    Some((name, value)) => args.push(format!("{}={}", name, value)),
+                    None => args.extend(self.expand_fields(word)),
+                }
+            }
+        } else {
+            args.extend(words.flat_map(|word| self.expand_fields(word)));
+        }
+        (assignments, args)
+    }
+
+    fn get_var(&self, name: &str) -> Option<&str> {
+        self.variables
+            .get(name)
+            .and_then(|variable| variable.value.get("0"))
+    }
+
+    // the value of an arithmetic expression of integers and variable names joined by + - * / %
+    // and parentheses, a name stands for its value evaluated in turn, 0 if unset or empty
+    fn arithmetic(&self, expression: &str) -> Result<i64, String> {
+        self.evaluate(expression, 0)
+            .map_err(|error| format!("{}: {}", expression.trim(), error))
+    }
+
+    fn evaluate(&self, expression: &str, depth: usize) -> Result<i64, &'static str> {
+        // a variable naming itself would never end
+        if depth > 64 {
+            return Err("expression recursion level exceeded");
+        }
+        let terms = arithmetic_terms(expression)?;
+        if terms.is_empty() {
+            return Ok(0);
+        }
+        let mut position = 0;
+        let value = self.sum(&terms, &mut position, depth)?;
+        match position == terms.len() {
+            true => Ok(value),
+            false => Err("syntax error in expression"),
+        }
+    }
+
+    fn sum(&self, terms: &[Term], position: &mut usize, depth: usize) -> Result<i64, &'static str> {
+        let mut value = self.product(terms, position, depth)?;
+        while let Some(Term::Operator(operator @ ('+' | '-'))) = terms.get(*position) {
+            *position += 1;
+            let operand = self.product(terms, position, depth)?;
+            value = match operator {
+                '+' => value.wrapping_add(operand),
+                _ => value.wrapping_sub(operand),
+            };
+        }
+        Ok(value)
+    }
+
+    fn product(
+        &self,
+        terms: &[Term],
+        position: &mut usize,
+        depth: usize,
+    ) -> Result<i64, &'static str> {
+        let mut value = self.operand(terms, position, depth)?;
+        while let Some(Term::Operator(operator @ ('*' | '/' | '%'))) = terms.get(*position) {
+            *position += 1;
+            let operand = self.operand(terms, position, depth)?;
+            value = match operator {
+                '*' => value.wrapping_mul(operand),
+                _ if operand == 0 => return Err("division by 0"),
+                '/' => value.wrapping_div(operand),
+                _ => value.wrapping_rem(operand),
+            };
+        }
+        Ok(value)
+    }
+
+    fn operand(
+        &self,
+        terms: &[Term],
+        position: &mut usize,
+        depth: usize,
+    ) -> Result<i64, &'static str> {
+        let term = terms
+            .get(*position)
+            .ok_or("syntax error: operand expected")?;
+        *position += 1;
+        match term {
+            Term::Number(number) => Ok(*number),
+            Term::Name(name) => self.evaluate(self.get_var(name).unwrap_or(""), depth + 1),
+            Term::Operator('+') => self.operand(terms, position, depth),
+            Term::Operator('-') => Ok(self.operand(terms, position, depth)?.wrapping_neg()),
+            Term::Operator('(') => {
+                let value = self.sum(terms, position, depth)?;
+                match terms.get(*position) {
+                    Some(Term::Operator(')')) => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err("missing `)'"),
+                }
+            }
+            Term::Operator(_) => Err("syntax error: operand expected"),
+        }
+    }
+
+    // every assignment goes through here so readonly variables can't be bypassed, a name
+    // like name[key] assigns an element of an associative array
+    fn set_var(&mut self, name: &str, value: String) -> Result<(), String> {
+        if name == "PATH" {
+            self.hashed.clear();
+        }
+        let (name, key, subscripted) =
+            match name.strip_suffix(']').and_then(|name| name.split_once('[')) {
+                Some((name, key)) => (name, key, true),
+                None => (name, "0", false),
+            };
+        let value = match self.variables.get(name) {
+            Some(variable) if variable.integer && !variable.readonly => {
+                self.arithmetic(&value)?.to_string()
+            }
+            _ => value,
+        };
+        match self.variables.get_mut(name) {
+            Some(variable) if variable.readonly => {
+                return Err(format!("{}: readonly variable", name));
+            }
+            Some(Variable {
+                value: Value::Associative(map),
+                ..
+            }) => {
+                map.insert(key.to_string(), value);
+            }
+            Some(Variable {
+                value: Value::Indexed(elements),
+                ..
+            }) => match key.parse::<usize>() {
+                Ok(index) => {
+                    elements.insert(index, value);
+                }
+                Err(_) => return Err(format!("{}[{}]: bad array subscript", name, key)),
+            },
+            // an element assigned to an unset name makes it an indexed array
+            None if subscripted && let Ok(index) = key.parse::<usize>() => {
+                let variable = Variable {
+                    value: Value::Indexed(BTreeMap::from([(index, value)])),
+                    exported: false,
+                    readonly: false,
+                    integer: false,
+                };
+                self.variables.insert(name.to_string(), variable);
+            }
+            _ if key != "0" => {
+                return Err(format!("{}: not an array", name));
+            }
+            Some(variable) => variable.value = Value::Scalar(value),
+            None => {
+                let variable = Variable {
+                    value: Value::Scalar(value),
+                    exported: false,
+                    readonly: false,
+                    integer: false,
+                };
+                self.variables.insert(name.to_string(), variable);
+            }
+        }
+        Ok(())
+    }
+
+    fn export_var(&mut self, name: &str) {
+        if let Some(variable) = self.variables.get_mut(name) {
+            variable.exported = true;
+        }
+    }
+
+    // NAME=value strings of all exported variables, passed to children on exec
+    fn environment(&self) -> Vec<CString> {
+        self.variables
+            .iter()
+            .filter(|(_, variable)| variable.exported)
+            .filter_map(|(name, variable)| match &variable.value {
+                Value::Scalar(value) => CString::new(format!("{}={}", name, value)).ok(),
+                // like in bash arrays can't be exported
+                Value::Associative(_) | Value::Indexed(_) => None,
+            })
+            .collect()
+    }
+
+    // resolve a command name to an executable through the shell's $PATH
+    fn find_executable(&self, name: &str) -> Option<PathBuf> {
+        if let Some((path, _)) = self.hashed.get(name) {
+            return Some(path.clone());
+        }
+        search_path(name, self.get_var("PATH")?)
+    }
+
+    // remember where a command was found, a remembered file that is gone is looked up again
+    fn hash_command(&mut self, name: &str) -> Option<&PathBuf> {
+        if name.contains('/') {
+            return None;
+        }
+        if self
+            .hashed
+            .get(name)
+            .is_some_and(|(path, _)| !path.is_file())
+        {
+            self.hashed.remove(name);
+        }
+        if !self.hashed.contains_key(name) {
+            let path = search_path(name, self.get_var("PATH")?)?;
+            self.hashed.insert(name.to_string(), (path, 0));
+        }
+        let (path, hits) = self.hashed.get_mut(name)?;
+        *hits += 1;
+        Some(path)
+    }
+
+    fn execute_lists(&mut self, lists: &[AndOrList]) -> nix::Result<()> {
+        for list in lists {
+            // set -n takes effect with the next command, an interactive shell ignores it
+            if self.returning || (self.options.noexec && !self.interactive) {
+                break;
+            }
+            self.execute_list(list)?;
+            self.run_pending_traps();
+        }
+        Ok(())
+    }
+
+    fn execute_list(&mut self, list: &AndOrList) -> nix::Result<()> {
+        if list.background {
+            self.spawn_background(list)
+        } else {
+            self.execute_and_or(list)
+        }
+    }
+
+    fn execute_and_or(&mut self, list: &AndOrList) -> nix::Result<()> {
+        self.execute_pipeline(&list.first)?;
+        for (operator, pipeline) in &list.rest {
+            let run = match operator {
+                Operator::And => self.last_status == 0,
+                _ => self.last_status != 0,
+            };
+            if run && !self.returning {
+                self.execute_pipeline(pipeline)?;
+            }
+        }
+        Ok(())
+    }
+
+    // a single command runs in the shell itself, longer pipelines fork every stage
+    fn execute_pipeline(&mut self, pipeline: &Pipeline) -> nix::Result<()> {
+        let started = (self.options.timing || pipeline.time.is_some()).then(Instant::now);
+        let cpu = pipeline.time.map(|_| cpu_times());
+        let statuses = if let [command] = pipeline.commands.as_slice() {
+            self.execute_node(command)?;
+            vec![self.last_status]
+        } else {
+            let statuses = self.spawn_pipeline(pipeline, false)?;
+            self.last_status = *statuses.last().unwrap();
+            statuses
+        };
+        if pipeline.negated {
+            self.last_status = (self.last_status == 0) as i32;
+        }
+        let statuses: Vec<String> = statuses.iter().map(|status| status.to_string()).collect();
+        self.variables.insert(
+            "PIPESTATUS".to_string(),
+            Variable {
+                value: Value::Scalar(statuses.join(" ")),
+                exported: false,
+                readonly: false,
+                integer: false,
+            },
+        );
+        if let Some(started) = started {
+            let seconds = started.elapsed().as_secs_f64();
+            if self.options.timing {
+                let _ = writeln!(self.stderr(), "{:.3}s\t{}", seconds, pipeline);
+            }
+            if let (Some(format), Some((user, sys))) = (pipeline.time, cpu) {
+                let (user_now, sys_now) = cpu_times();
+                let times = [
+                    ("real", seconds),
+                    ("user", user_now - user),
+                    ("sys", sys_now - sys),
+                ];
+                let report: String = match format {
+                    TimeFormat::Default => {
+                        let lines = times.map(|(name, seconds)| {
+                            let minutes = (seconds / 60.0).floor();
+                            format!("{}\t{}m{:.3}s\n", name, minutes, seconds - minutes * 60.0)
+                        });
+                        format!("\n{}", lines.concat())
+                    }
+                    TimeFormat::Posix => times
+                        .map(|(name, seconds)| format!("{} {:.2}\n", name, seconds))
+                        .concat(),
+                };
+                let _ = write!(self.stderr(), "{}", report);
+            }
+        }
+        Ok(())
+    }
+
+    // fork one process per stage with stdout of each connected to stdin of the next,
+    // then wait for all of them and return their statuses in order, or in the background
+    // leave them running as one job
+    fn spawn_pipeline(&mut self, pipeline: &Pipeline, background: bool) -> nix::Result<Vec<i32>> {
+        let description = pipeline.to_string();
+        let stages = pipeline.commands.len();
+        let mut pids: Vec<Pid> = Vec::new();
+        let mut input: Option<OwnedFd> = None;
+
+        for (index, command) in pipeline.commands.iter().enumerate() {
+            let (read_end, write_end) = if index + 1 < stages {
+                let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC)?;
+                (Some(read_end), Some(write_end))
+            } else {
+                (None, None)
+            };
+            let pgid = pids.first().copied().unwrap_or(Pid::from_raw(0));
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { child, .. }) => {
+                    if self.options.monitor {
+                        let _ = setpgid(child, pgid);
+                        if index == 0 && !background {
+                            let _ = tcsetpgrp(&self.stdin_handle, child);
+                        }
+                    }
+                    pids.push(child);
+                    input = read_end;
+                }
+                Ok(ForkResult::Child) => {
+                    if self.options.monitor {
+                        let _ = setpgid(Pid::from_raw(0), pgid);
+                    }
+                    self.enter_subshell();
+                    // redirections of an enclosing builtin apply before the pipe
+                    let mut result = self.fds.install();
+                    self.fds = FdTable::default();
+                    if let Some(input) = &input {
+                        result = result.and(duplicate_fd(input.as_raw_fd(), 0));
+                    }
+                    if let Some(output) = &write_end {
+                        result = result.and(duplicate_fd(output.as_raw_fd(), 1));
+                    }
+                    if let Err(e) = result {
+                        let _ = writeln!(FdWriter(2), "trash: {}", e.desc());
+                        unsafe { libc::_exit(1) };
+                    }
+                    drop((input, read_end, write_end));
+                    self.exec_node(command);
+                }
+                Err(error) => {
+                    // let the stages already running see the end of their input and finish
+                    drop((input, read_end, write_end));
+                    for pid in &pids {
+                        let _ = wait_child(*pid, None);
+                    }
+                    if self.options.monitor {
+                        let _ = tcsetpgrp(&self.stdin_handle, self.shell_pid);
+                    }
+                    self.fork_failed(error);
+                    return Ok(vec![self.last_status]);
+                }
+            }
+        }
+
+        if background {
+            let id = self.add_job(&pids, description);
+            let last = *pids.last().unwrap();
+            self.last_bg_pid = Some(last);
+            if self.interactive {
+                println!("[{}] {}", id, last);
+            }
+            return Ok(Vec::new());
+        }
+
+        let mut statuses = Vec::new();
+        // the signal that stopped the pipeline, and the codes of the processes that exited
+        let mut stopped = None;
+        let mut exited = Vec::new();
+        for pid in &pids {
+            let status = wait_child(*pid, Some(WaitPidFlag::WUNTRACED))?;
+            if let WaitStatus::Stopped(_, sig) = status {
+                stopped = Some(sig);
+                exited.push(None);
+            } else {
+                exited.push(status_code(status));
+            }
+            statuses.push(status_code(status).unwrap_or(0));
+        }
+        if self.options.monitor {
+            let _ = tcsetpgrp(&self.stdin_handle, self.shell_pid);
+        }
+        if let Some(sig) = stopped {
+            let id = self.add_job(&pids, description.clone());
+            let job = self.jobs.last_mut().unwrap();
+            for (process, code) in job.processes.iter_mut().zip(exited) {
+                process.1 = code;
+            }
+            job.state = JobState::Stopped(sig);
+            println!("\n[{}]  Stopped\t{}", id, description);
+        }
+        Ok(statuses)
+    }
+
+    fn open_redirects(&self, redirects: &[Redirect]) -> Result<Vec<Redirection>, String> {
+        let mut redirections = Vec::new();
+        for redirect in redirects {
+            let path = self.expand_word(&redirect.target);
+            if let Operator::DuplicateIn | Operator::DuplicateOut = redirect.operator {
+                if path == "-" {
+                    redirections.push(Redirection::Close(redirect.fd));
+                    continue;
+                }
+                if let Ok(from) = path.parse::<i32>() {
+                    // the source has to be open, as seen from the current builtin context
+                    let source = self.fds.get(from);
+                    let open = source != CLOSED_FD
+                        && fcntl(unsafe { BorrowedFd::borrow_raw(source) }, FcntlArg::F_GETFD)
+                            .is_ok();
+                    if !open {
+                        return Err(format!("{}: {}", from, Errno::EBADF.desc()));
+                    }
+                    redirections.push(Redirection::Duplicate {
+                        from,
+                        to: redirect.fd,
+                    });
+                    continue;
+                }
+                // >&file is another way to write &>file
+                if redirect.operator == Operator::DuplicateIn || redirect.fd != 1 {
+                    return Err(format!("{}: ambiguous redirect", path));
+                }
+            }
+            let flags = match redirect.operator {
+                Operator::RedirectIn => OFlag::O_RDONLY,
+                Operator::RedirectAppend | Operator::RedirectAppendAll => {
+                    OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND
+                }
+                _ => OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC,
+            };
+            let file = match redirect.operator {
+                // the target of a here-doc is its body
+                Operator::HereDoc | Operator::HereDocStrip => here_document(&path)
+                    .map_err(|e| format!("here-document: {}", io_error_message(&e)))?,
+                Operator::HereString => here_document(&format!("{}\n", path))
+                    .map_err(|e| format!("here-string: {}", io_error_message(&e)))?,
+                _ => open(
+                    path.as_str(),
+                    flags | OFlag::O_CLOEXEC,
+                    Mode::from_bits_truncate(0o644),
+                )
+                .map_err(|e| format!("{}: {}", path, e.desc()))?,
+            };
+            // keep it clear of low descriptors a later redirection like 3>&1 may overwrite
+            let file = match fcntl(&file, FcntlArg::F_DUPFD_CLOEXEC(10)) {
+                Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+                Err(_) => file,
+            };
+
+            match redirect.operator {
+                // one open file shared by stdout and stderr
+                Operator::RedirectAll | Operator::RedirectAppendAll | Operator::DuplicateOut => {
+                    redirections.push(Redirection::File(1, file));
+                    redirections.push(Redirection::Duplicate { from: 1, to: 2 });
+                }
+                _ => redirections.push(Redirection::File(redirect.fd, file)),
+            }
+        }
+        Ok(redirections)
+    }
+
+    fn execute_node(&mut self, node: &Node) -> nix::Result<()> {
+        match node {
+            Node::Simple(command) => self.execute_simple(command),
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    self.execute_lists(condition)?;
+                    if self.returning {
+                        return Ok(());
+                    }
+                    if self.last_status == 0 {
+                        return self.execute_lists(body);
+                    }
+                }
+                match otherwise {
+                    Some(body) => self.execute_lists(body),
+                    None => {
+                        self.last_status = 0;
+                        Ok(())
+                    }
+                }
+            }
+            Node::While {
+                condition,
+                body,
+                until,
+            } => {
+                // the status of the last body run, or 0 if the body never ran
+                let mut status = 0;
+                loop {
+                    self.execute_lists(condition)?;
+                    if self.returning {
+                        return Ok(());
+                    }
+                    if (self.last_status == 0) == *until {
+                        break;
+                    }
+                    self.execute_lists(body)?;
+                    status = self.last_status;
+                    if self.returning {
+                        return Ok(());
+                    }
+                }
+                self.last_status = status;
+                Ok(())
+            }
+            Node::Case { subject, clauses } => {
+                let mut pipes = Vec::new();
+                let subject = self.substitute_word(subject, &mut pipes);
+                let subject = self.expand_word(&subject);
+                self.last_status = 0;
+                // after ;& the next body runs without its patterns being tested
+                let mut falling_through = false;
+                for (patterns, body, terminator) in clauses {
+                    if !falling_through
+                        && !patterns.iter().any(|pattern| {
+                            pattern_matches(&self.expand_pattern(pattern).1, &subject)
+                        })
+                    {
+                        continue;
+                    }
+                    self.last_status = 0;
+                    self.execute_lists(body)?;
+                    if self.returning {
+                        return Ok(());
+                    }
+                    match terminator {
+                        Operator::CaseFallthrough => falling_through = true,
+                        Operator::CaseContinue => falling_through = false,
+                        _ => break,
+                    }
+                }
+                Ok(())
+            }
+            Node::Group(lists) => self.execute_lists(lists),
+            Node::Subshell(lists) => self.spawn_subshell(lists),
+            Node::Function(name, body) => {
+                self.functions.insert(name.clone(), body.clone());
+                self.last_status = 0;
+                Ok(())
+            }
+            Node::Redirected(node, redirects) => {
+                let mut pipes = Vec::new();
+                let substituted: Option<Vec<Redirect>> = redirects
+                    .iter()
+                    .any(|redirect| redirect.target.has_substitution())
+                    .then(|| self.substitute_redirects(redirects, &mut pipes));
+                let redirects = substituted.as_deref().unwrap_or(redirects);
+                let redirections = match self.open_redirects(redirects) {
+                    Ok(redirections) => redirections,
+                    Err(e) => {
+                        let _ = writeln!(self.stderr(), "trash: {}", e);
+                        self.last_status = 1;
+                        return Ok(());
+                    }
+                };
+                // everything inside sees the redirections like a builtin would
+                let fds = self.fds.layer(redirections);
+                let outer = std::mem::replace(&mut self.fds, fds);
+                let result = self.execute_node(node);
+                self.fds = outer;
+                result
+            }
+        }
+    }
+
+    // the command with its substitutions replaced by the output of commands and the paths of
+    // the pipes of processes, None if it has none
+    fn substitute_command(
+        &mut self,
+        command: &SimpleCommand,
+        pipes: &mut Vec<OwnedFd>,
+    ) -> Option<SimpleCommand> {
+        let targets = command.redirects.iter().map(|redirect| &redirect.target);
+        if !command
+            .words
+            .iter()
+            .chain(targets)
+            .any(Word::has_substitution)
+        {
+            return None;
+        }
+        let words = command
+            .words
+            .iter()
+            .map(|word| self.substitute_word(word, pipes))
+            .collect();
+        let redirects = self.substitute_redirects(&command.redirects, pipes);
+        Some(SimpleCommand {
+            words,
+            redirects,
+            line: command.line,
+        })
+    }
+
+    fn substitute_redirects(
+        &mut self,
+        redirects: &[Redirect],
+        pipes: &mut Vec<OwnedFd>,
+    ) -> Vec<Redirect> {
+        redirects
+            .iter()
+            .map(|redirect| Redirect {
+                fd: redirect.fd,
+                operator: redirect.operator.clone(),
+                target: self.substitute_word(&redirect.target, pipes),
+            })
+            .collect()
+    }
+
+    fn substitute_word(&mut self, word: &Word, pipes: &mut Vec<OwnedFd>) -> Word {
+        let parts = word
+            .parts
+            .iter()
+            .map(|(text, quoting)| match quoting {
+                Quoting::Command => (self.command_output(text), Quoting::Expanded),
+                Quoting::QuotedCommand => (self.command_output(text), Quoting::SingleQuoted),
+                Quoting::ProcessIn | Quoting::ProcessOut => {
+                    let path = match self.spawn_substitution(text, *quoting == Quoting::ProcessIn) {
+                        Some(pipe) => {
+                            let path = format!("/dev/fd/{}", pipe.as_raw_fd());
+                            pipes.push(pipe);
+                            path
+                        }
+                        None => String::new(),
+                    };
+                    (path, Quoting::SingleQuoted)
+                }
+                _ => (text.clone(), quoting.clone()),
+            })
+            .collect();
+        Word { parts }
+    }
+
+    // what the list writes to stdout without trailing newlines, run in a subshell which
+    // sees everything the shell has, functions and positional parameters included
+    fn command_output(&mut self, list: &str) -> String {
+        let (read_end, write_end) = match pipe2(OFlag::O_CLOEXEC) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "trash: {}", e.desc());
+                return String::new();
+            }
+        };
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child, .. }) => {
+                drop(write_end);
+                let mut output = Vec::new();
+                let _ = std::fs::File::from(read_end).read_to_end(&mut output);
+                if let Ok(status) = wait_child(child, None)
+                    && let Some(code) = status_code(status)
+                {
+                    self.last_status = code;
+                }
+                let output = String::from_utf8_lossy(&output);
+                output.trim_end_matches('\n').to_string()
+            }
+            Ok(ForkResult::Child) => {
+                self.enter_subshell();
+                let mut result = self.fds.install();
+                self.fds = FdTable::default();
+                result = result.and(duplicate_fd(write_end.as_raw_fd(), 1));
+                if let Err(e) = result {
+                    let _ = writeln!(FdWriter(2), "trash: {}", e.desc());
+                    unsafe { libc::_exit(1) };
+                }
+                drop((read_end, write_end));
+                let _ = self.run_str(list);
+                let _ = self.stdout_handle.flush();
+                unsafe { libc::_exit(self.last_status) };
+            }
+            Err(error) => {
+                self.fork_failed(error);
+                String::new()
+            }
+        }
+    }
+
+    // run the list in the background with its output, or input if it isn't reading from
+    // it, connected to a pipe and return the shell's end, left open across exec
+    fn spawn_substitution(&mut self, list: &str, reading: bool) -> Option<OwnedFd> {
+        let (read_end, write_end) = match pipe2(OFlag::O_CLOEXEC) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "trash: {}", e.desc());
+                return None;
+            }
+        };
+        let (ours, theirs) = match reading {
+            true => (read_end, write_end),
+            false => (write_end, read_end),
+        };
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child, .. }) => {
+                self.substitutions.push(child);
+                self.last_bg_pid = Some(child);
+                let _ = fcntl(&ours, FcntlArg::F_SETFD(FdFlag::empty()));
+                Some(ours)
+            }
+            Ok(ForkResult::Child) => {
+                self.enter_subshell();
+                let mut result = self.fds.install();
+                self.fds = FdTable::default();
+                let fd = if reading { 1 } else { 0 };
+                result = result.and(duplicate_fd(theirs.as_raw_fd(), fd));
+                if let Err(e) = result {
+                    let _ = writeln!(FdWriter(2), "trash: {}", e.desc());
+                    unsafe { libc::_exit(1) };
+                }
+                drop((ours, theirs));
+                let _ = self.run_str(list);
+                let _ = self.stdout_handle.flush();
+                unsafe { libc::_exit(self.last_status) };
+            }
+            Err(error) => {
+                self.fork_failed(error);
+                None
+            }
+        }
+    }
+
+    // reap the processes of finished process substitutions so they don't linger as zombies,
+    // except the one in $! which wait can still ask for
+    fn reap_substitutions(&mut self) {
+        let last = self.last_bg_pid;
+        self.substitutions.retain(|&pid| {
+            Some(pid) == last
+                || matches!(
+                    waitpid(pid, Some(WaitPidFlag::WNOHANG)),
+                    Ok(WaitStatus::StillAlive)
+                )
+        });
+    }
+
+    // run a pipeline stage in an already forked child
+    fn exec_node(&mut self, node: &Node) -> ! {
+        if let Node::Simple(command) = node {
+            self.exec_simple(command);
+        }
+        let _ = self.execute_node(node);
+        let _ = self.stdout_handle.flush();
+        unsafe { libc::_exit(self.last_status) };
+    }
+
+    fn execute_simple(&mut self, command: &SimpleCommand) -> nix::Result<()> {
+        self.reap_substitutions();
+        self.lineno = command.line;
+        let ran_commands = command.words.iter().any(|word| {
+            word.parts
+                .iter()
+                .any(|(_, q)| matches!(q, Quoting::Command | Quoting::QuotedCommand))
+        });
+        // the pipes of process substitutions stay open until the command is done
+        let mut pipes = Vec::new();
+        let substituted = self.substitute_command(command, &mut pipes);
+        let command = substituted.as_ref().unwrap_or(command);
+        let redirections = match self.open_redirects(&command.redirects) {
+            Ok(redirections) => redirections,
+            Err(e) => {
+                eprintln!("trash: {}", e);
+                self.last_status = 1;
+                return Ok(());
+            }
+        };
+
+        // without a command the assignments are made in the shell itself, in order
+        // values are expanded before $? is reset so `x=$?` sees the previous status
+        let assignments: Option<Vec<_>> = command
+            .words
+            .iter()
+            .map(|word| self.expand_assignment(word))
+            .collect();
+        if let Some(assignments) = assignments {
+            self.assign_all(assignments, ran_commands, redirections);
+            return Ok(());
+        }
+
+        // assignments in front of a command are only exported to that command, except for
+        // a special builtin which keeps them
+        let (assignments, args) = self.expand_command(command);
+        // a command word that expanded to nothing leaves only the assignments, like $(true)
+        if args.is_empty() {
+            self.assign_all(assignments, ran_commands, redirections);
+            return Ok(());
+        }
+        let resolved = self.resolve(args);
+        let special = matches!(&resolved, Command::Builtin(builtin) if builtin.is_special());
+        let mut saved: Vec<(String, Option<Variable>)> = Vec::new();
+        let mut result = Ok(());
+        for (name, value) in assignments {
+            let previous = self.variables.get(&name).cloned();
+            if let Err(e) = self.set_var(&name, value) {
+                eprintln!("trash: {}", e);
+                self.last_status = 1;
+                result = Err(());
+                break;
+            }
+            if !special {
+                self.export_var(&name);
+                saved.push((name, previous));
+            }
+        }
+
+        let result = match result {
+            Ok(()) => self.execute(resolved, redirections),
+            Err(()) => Ok(()),
+        };
+
+        for (name, previous) in saved.into_iter().rev() {
+            match previous {
+                Some(variable) => self.variables.insert(name, variable),
+                None => self.variables.remove(&name),
+            };
+        }
+        result
+    }
+
+    // assignments without a command are made in the shell itself, in order, with the
+    // redirections only in effect for their errors
+    fn assign_all(
+        &mut self,
+        assignments: Vec<(String, String)>,
+        ran_commands: bool,
+        redirections: Vec<Redirection>,
+    ) {
+        // after x=$(cmd) the status is that of cmd
+        if !ran_commands {
+            self.last_status = 0;
+        }
+        let fds = self.fds.layer(redirections);
+        let outer = std::mem::replace(&mut self.fds, fds);
+        for (name, value) in assignments {
+            if let Err(e) = self.set_var(&name, value) {
+                let _ = writeln!(self.stderr(), "trash: {}", e);
+                self.last_status = 1;
+                break;
+            }
+        }
+        self.fds = outer;
+    }
+
+    // run a command in an already forked child, external commands replace the process
+    fn exec_simple(&mut self, command: &SimpleCommand) -> ! {
+        let mut pipes = Vec::new();
+        let substituted = self.substitute_command(command, &mut pipes);
+        let command = substituted.as_ref().unwrap_or(command);
+        let redirections = match self.open_redirects(&command.redirects) {
+            Ok(redirections) => redirections,
+            Err(e) => {
+                eprintln!("trash: {}", e);
+                unsafe { libc::_exit(1) };
+            }
+        };
+        let (assignments, args) = self.expand_command(command);
+        for (name, value) in assignments {
+            if let Err(e) = self.set_var(&name, value) {
+                eprintln!("trash: {}", e);
+                unsafe { libc::_exit(1) };
+            }
+            self.export_var(&name);
+        }
+        // like in the shell itself only assignments or redirections succeed
+        self.last_status = 0;
+        if !args.is_empty() {
+            match self.resolve(args) {
+                Command::External(external) => self.exec_external(&external, &redirections),
+                command => {
+                    let _ = self.execute(command, redirections);
+                }
+            }
+        }
+        let _ = self.stdout_handle.flush();
+        unsafe { libc::_exit(self.last_status) };
+    }
+
+    // functions shadow builtins and external commands of the same name
+    fn resolve(&self, args: Vec<String>) -> Command {
+        match self.functions.get(&args[0]) {
+            Some(body) => Command::Function(body.clone(), args),
+            None => self.builtin_or_external(args),
+        }
+    }
+
+    // in posix mode [[ is looked up like any other command name, as are disabled builtins
+    fn builtin_or_external(&self, args: Vec<String>) -> Command {
+        if (self.options.posix && args[0] == "[[") || self.disabled_builtins.contains(&args[0]) {
+            return Command::External(ExternalCommand::new(args[0].clone(), args));
+        }
+        Command::from_args(args)
+    }
+
+    fn call_function(&mut self, body: &Node, args: Vec<String>) -> nix::Result<()> {
+        let mut args = args;
+        let function = args.remove(0);
+        let outer_positional = std::mem::replace(&mut self.positional, args);
+        self.locals.push(HashMap::new());
+        self.calls.push(Frame {
+            function,
+            line: self.lineno,
+            file: self
+                .source_file
+                .clone()
+                .unwrap_or_else(|| self.name.clone()),
+        });
+        let result = self.execute_node(body);
+        // back on the line of the call, where the body left it
+        self.lineno = self.calls.pop().unwrap().line;
+        self.returning = false;
+        for (name, previous) in self.locals.pop().unwrap() {
+            match previous {
+                Some(variable) => self.variables.insert(name, variable),
+                None => self.variables.remove(&name),
+            };
+        }
+        self.positional = outer_positional;
+        result
+    }
+
+    fn spawn_subshell(&mut self, lists: &[AndOrList]) -> nix::Result<()> {
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child, .. }) => {
+                let status = wait_child(child, None)?;
+                if let Some(code) = status_code(status) {
+                    self.last_status = code;
+                }
+                Ok(())
+            }
+            Ok(ForkResult::Child) => {
+                self.enter_subshell();
+                let _ = self.execute_lists(lists);
+                let _ = self.stdout_handle.flush();
+                unsafe { libc::_exit(self.last_status) };
+            }
+            Err(error) => {
+                self.fork_failed(error);
+                Ok(())
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command, redirections: Vec<Redirection>) -> nix::Result<()> {
+        match command {
+            Command::Function(body, args) => {
+                let fds = self.fds.layer(redirections);
+                let outer = std::mem::replace(&mut self.fds, fds);
+                let result = self.call_function(&body, args);
+                self.fds = outer;
+                result
+            }
+            Command::Builtin(builtin) => {
+                let fds = self.fds.layer(redirections);
+                let outer = std::mem::replace(&mut self.fds, fds);
+                self.last_status = self.handle_builtin(builtin).unwrap_or(1);
+                self.fds = outer;
+                Ok(())
+            }
+            Command::External(external) => {
+                self.hash_command(&external.cmd);
+                let Some(status) = self.spawn_foreground(&external, &redirections)? else {
+                    return Ok(());
+                };
+                if let WaitStatus::Stopped(child_pid, sig) = status {
+                    let id = self.add_job(&[child_pid], external.args.join(" "));
+                    self.jobs.last_mut().unwrap().state = JobState::Stopped(sig);
+                    println!("\n[{}]  Stopped\t{}", id, external.args.join(" "));
+                }
+                if let Some(code) = status_code(status) {
+                    self.last_status = code;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // returns None if no child could be started
+    fn spawn_foreground(
+        &mut self,
+        command: &ExternalCommand,
+        redirections: &[Redirection],
+    ) -> nix::Result<Option<WaitStatus>> {
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child, .. }) => {
+                if self.options.monitor {
+                    let _ = setpgid(child, child);
+                    let _ = tcsetpgrp(&self.stdin_handle, child);
+                }
+                let status = wait_child(child, Some(WaitPidFlag::WUNTRACED))?;
+                if self.options.monitor {
+                    let _ = tcsetpgrp(&self.stdin_handle, self.shell_pid);
+                }
+                Ok(Some(status))
+            }
+            Ok(ForkResult::Child) => self.exec_external(command, redirections),
+            Err(error) => {
+                self.fork_failed(error);
+                Ok(None)
+            }
+        }
+    }
+
+    fn spawn_background(&mut self, list: &AndOrList) -> nix::Result<()> {
+        // the stages of a lone pipeline are the job themselves, without a subshell around them
+        if list.rest.is_empty() && list.first.commands.len() > 1 {
+            self.spawn_pipeline(&list.first, true)?;
+            self.last_status = 0;
+            return Ok(());
+        }
+        let command = list.to_string();
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child, .. }) => {
+                if self.options.monitor {
+                    let _ = setpgid(child, child);
+                }
+                let id = self.add_job(&[child], command);
+                self.last_bg_pid = Some(child);
+                self.last_status = 0;
+                if self.interactive {
+                    println!("[{}] {}", id, child);
+                }
+                Ok(())
+            }
+            Ok(ForkResult::Child) => {
+                if self.options.monitor {
+                    let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+                }
+                // the background subshell has no job control of its own
+                self.enter_subshell();
+
+                if let ([command], []) = (list.first.commands.as_slice(), list.rest.as_slice()) {
+                    self.exec_node(command);
+                }
+                let _ = self.execute_and_or(list);
+                let _ = self.stdout_handle.flush();
+                unsafe { libc::_exit(self.last_status) };
+            }
+            Err(error) => {
+                self.fork_failed(error);
+                Ok(())
+            }
+        }
+    }
+
+    fn exec_external(&self, command: &ExternalCommand, redirections: &[Redirection]) -> ! {
+        self.reset_signals();
+        // a signal caught between the fork and the reset above is delivered again, now
+        // with its default action
+        let pending = PENDING_SIGNALS.swap(0, Ordering::Relaxed);
+        for number in (1..64).filter(|number| pending & (1 << number) != 0) {
+            if let Ok(sig) = Signal::try_from(number) {
+                let _ = raise(sig);
+            }
+        }
+        if self.options.monitor {
+            let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+        }
+        // redirections of an enclosing builtin like source apply first
+        if let Err(e) = self.fds.install() {
+            let _ = writeln!(FdWriter(2), "trash: {}", e.desc());
+            unsafe { libc::_exit(1) };
+        }
+        for redirection in redirections {
+            let result = match redirection {
+                Redirection::File(fd, file) => duplicate_fd(file.as_raw_fd(), *fd),
+                Redirection::Duplicate { from, to } => duplicate_fd(*from, *to),
+                Redirection::Close(fd) => {
+                    unsafe { libc::close(*fd) };
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                let _ = writeln!(FdWriter(2), "trash: {}", e.desc());
+                unsafe { libc::_exit(1) };
+            }
+        }
+        let Some(path) = self.find_executable(&command.cmd) else {
+            let _ = writeln!(FdWriter(2), "trash: {}: command not found", command.cmd);
+            unsafe { libc::_exit(127) };
+        };
+        let cpath = CString::new(path.clone().into_os_string().into_vec()).unwrap();
+        let mut error =
+            execve(&cpath, &command.args_as_cstring(), &self.environment()).unwrap_err();
+        // a remembered path that no longer exists is searched for in $PATH once more
+        if error == Errno::ENOENT
+            && self.hashed.contains_key(&command.cmd)
+            && let Some(found) = search_path(&command.cmd, self.get_var("PATH").unwrap_or(""))
+            && found != path
+        {
+            let found = CString::new(found.into_os_string().into_vec()).unwrap();
+            error = execve(&found, &command.args_as_cstring(), &self.environment()).unwrap_err();
+        }
+        let _ = writeln!(FdWriter(2), "trash: {}: {}", command.cmd, error.desc());
+        let status = if error == Errno::ENOENT { 127 } else { 126 };
+        unsafe { libc::_exit(status) };
+    }
+
+    // only the standard descriptors and the redirected ones are passed on to a program run by exec
+    fn close_stray_fds(&self) {
+        let Ok(entries) = std::fs::read_dir("/proc/self/fd") else {
+            return;
+        };
+        let fds: Vec<RawFd> = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        for fd in fds {
+            if fd > 2 && !self.fds.fds.contains_key(&fd) && !self.exec_fds.contains(&fd) {
+                let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                let _ = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+            }
+        }
+    }
+
+    // read one byte without reading ahead, ETIMEDOUT once the deadline passes
+    fn read_byte(&mut self, fd: RawFd, deadline: Option<Instant>) -> nix::Result<Option<u8>> {
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        loop {
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let timeout =
+                    PollTimeout::try_from(remaining.as_millis().min(i32::MAX as u128) as i32)
+                        .unwrap_or(PollTimeout::MAX);
+                let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+                match poll(&mut fds, timeout) {
+                    Ok(0) => return Err(Errno::ETIMEDOUT),
+                    Ok(_) => {}
+                    Err(Errno::EINTR) => {
+                        self.run_pending_traps();
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            let mut byte = [0];
+            match read(fd, &mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(Errno::EINTR) => self.run_pending_traps(),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn stdout(&self) -> FdWriter {
+        FdWriter(self.fds.get(1))
+    }
+
+    fn stderr(&self) -> FdWriter {
+        FdWriter(self.fds.get(2))
+    }
+
+    // e.g. when hitting the process limit, the command fails but the shell keeps going
+    fn fork_failed(&mut self, error: Errno) {
+        let _ = writeln!(self.stderr(), "trash: fork: {}", error.desc());
+        self.last_status = 1;
+    }
+
+    fn add_job(&mut self, pids: &[Pid], command: String) -> usize {
+        let id = self.jobs.last().map_or(1, |job| job.id + 1);
+        self.jobs.push(Job {
+            id,
+            pid: pids[0],
+            processes: pids.iter().map(|&pid| (pid, None)).collect(),
+            command,
+            state: JobState::Running,
+            nohup: false,
+        });
+        id
+    }
+
+    // poll all jobs without blocking and record state changes
+    fn reap_jobs(&mut self) {
+        self.reap_substitutions();
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+        for job in self.jobs.iter_mut() {
+            let pids: Vec<Pid> = job.running().collect();
+            for pid in pids {
+                job.update(pid, waitpid(pid, Some(flags)));
+            }
+        }
+    }
+
+    // report finished jobs before the prompt and drop them from the table
+    fn notify_jobs(&mut self) {
+        self.reap_jobs();
+        // a script isn't told, its finished jobs stay until wait or jobs collects them
+        if !self.interactive {
+            return;
+        }
+        for job in &self.jobs {
+            if let JobState::Done(_) = job.state {
+                println!("[{}]  {}\t{}", job.id, job.state, job.command);
+            }
+        }
+        self.jobs
+            .retain(|job| !matches!(job.state, JobState::Done(_)));
+    }
+
+    fn find_job(&self, spec: &str) -> Option<usize> {
+        match spec.strip_prefix('%') {
+            Some(id) => {
+                let id: usize = id.parse().ok()?;
+                self.jobs.iter().position(|job| job.id == id)
+            }
+            None => {
+                let pid = Pid::from_raw(spec.parse().ok()?);
+                self.jobs
+                    .iter()
+                    .position(|job| job.processes.iter().any(|(process, _)| *process == pid))
+            }
+        }
+    }
+
+    // a job's whole process group gets the signal when it has one of its own
+    fn signal_job(&self, index: usize, sig: Option<Signal>) -> nix::Result<()> {
+        let job = &self.jobs[index];
+        if self.options.monitor {
+            killpg(job.pid, sig)
+        } else {
+            job.running().try_for_each(|pid| kill(pid, sig))
+        }
+    }
+
+    // pass a hangup on to the jobs not marked with disown -h, stopped ones are continued so
+    // they see it
+    fn hangup_jobs(&mut self) {
+        self.reap_jobs();
+        for index in 0..self.jobs.len() {
+            let job = &self.jobs[index];
+            if job.nohup || matches!(job.state, JobState::Done(_)) {
+                continue;
+            }
+            let stopped = matches!(job.state, JobState::Stopped(_));
+            let _ = self.signal_job(index, Some(Signal::SIGHUP));
+            if stopped {
+                let _ = self.signal_job(index, Some(Signal::SIGCONT));
+            }
+        }
+    }
+
+    // the job fg and bg act on, by default the most recent one
+    fn job_control_target(&self, args: &[String]) -> Option<usize> {
+        let index = match args.get(1) {
+            Some(spec) => self.find_job(spec),
+            None => self.jobs.len().checked_sub(1),
+        };
+        if index.is_none() {
+            let spec = args.get(1).map_or("current", String::as_str);
+            let _ = writeln!(self.stderr(), "trash: {}: {}: no such job", args[0], spec);
+        }
+        index
+    }
+
+    // block until the job stops or terminates and return its status
+    // a stopped job ends the wait unless force is given, as by wait -f, then only its
+    // termination does
+    fn wait_for_job(&mut self, index: usize, force: bool) -> nix::Result<i32> {
+        let job = &mut self.jobs[index];
+        loop {
+            match job.state {
+                JobState::Done(code) => return Ok(code),
+                JobState::Stopped(sig) if !force => return Ok(128 + sig as i32),
+                JobState::Stopped(_) | JobState::Running => {}
+            }
+            let Some(pid) = job.running().next() else {
+                return Ok(0);
+            };
+            job.update(pid, waitpid(pid, Some(WaitPidFlag::WUNTRACED)));
+        }
+    }
+
+    // the declare command that recreates a variable, e.g. declare -rx name="value"
+    fn declaration(&self, name: &str) -> String {
+        let variable = &self.variables[name];
+        let mut flags = String::new();
+        match variable.value {
+            Value::Associative(_) => flags.push('A'),
+            Value::Indexed(_) => flags.push('a'),
+            Value::Scalar(_) => {}
+        }
+        if variable.integer {
+            flags.push('i');
+        }
+        if variable.readonly {
+            flags.push('r');
+        }
+        if variable.exported {
+            flags.push('x');
+        }
+        if flags.is_empty() {
+            flags.push('-');
+        }
+        format!(
+            "declare -{} {}={}",
+            flags,
+            name,
+            variable.value.declaration()
+        )
+    }
+
+    // remember what a local variable shadows, only the first time in this call
+    fn make_local(&mut self, name: &str) {
+        let frame = self.locals.last_mut().unwrap();
+        if !frame.contains_key(name) {
+            frame.insert(name.to_string(), self.variables.remove(name));
+        } else {
+            self.variables.remove(name);
+        }
+    }
+
+    // exchanges the variable with the one saved when the function at frame made it local
+    fn swap_global(&mut self, frame: usize, name: &str) {
+        let saved = self.locals[frame].get_mut(name).unwrap();
+        let current = self.variables.remove(name);
+        if let Some(variable) = std::mem::replace(saved, current) {
+            self.variables.insert(name.to_string(), variable);
+        }
+    }
+
+    // export and readonly: assign NAME=value operands and set the attribute, or list
+    // all variables having it
+    fn mark_variables(
+        &mut self,
+        args: &[String],
+        attribute: fn(&mut Variable) -> &mut bool,
+    ) -> i32 {
+        let operands: Vec<&String> = args[1..].iter().filter(|arg| *arg != "-p").collect();
+
+        if operands.is_empty() {
+            let mut names: Vec<String> = self
+                .variables
+                .iter_mut()
+                .filter_map(|(name, variable)| (*attribute(variable)).then(|| name.clone()))
+                .collect();
+            names.sort();
+            for name in names {
+                let _ = writeln!(self.stdout(), "{}", self.declaration(&name));
+            }
+            return 0;
+        }
+
+        let mut status = 0;
+        for operand in operands {
+            let (name, value) = match operand.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (operand.as_str(), None),
+            };
+            if !is_valid_name(name) {
+                let _ = writeln!(
+                    self.stderr(),
+                    "trash: {}: `{}': not a valid identifier",
+                    args[0],
+                    operand
+                );
+                status = 1;
+                continue;
+            }
+            let value = value
+                .map(|value| value.to_string())
+                .or_else(|| (!self.variables.contains_key(name)).then(String::new));
+            if let Some(value) = value
+                && let Err(e) = self.set_var(name, value)
+            {
+                let _ = writeln!(self.stderr(), "trash: {}", e);
+                status = 1;
+                continue;
+            }
+            if let Some(variable) = self.variables.get_mut(name) {
+                *attribute(variable) = true;
+            }
+        }
+        status
+    }
+
+    fn handle_builtin(&mut self, builtin: BuiltinCommand) -> nix::Result<i32> {
+        match builtin {
+            BuiltinCommand::Exit(args) => {
+                if args[0] == "logout" && !self.login {
+                    let _ = writeln!(self.stderr(), "trash: logout: not login shell: use `exit'");
+                    return Ok(1);
+                }
+                if self.interactive {
+                    let _ = writeln!(self.stderr(), "{}", args[0]);
+                }
+                let status = match args.get(1) {
+                    Some(arg) => match arg.parse::<i64>() {
+                        Ok(status) => (status & 0xff) as i32,
+                        Err(_) => {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: exit: {}: numeric argument required",
+                                arg
+                            );
+                            2
+                        }
+                    },
+                    None => self.last_status,
+                };
+                self.shutdown(status);
+            }
+            BuiltinCommand::Cd(args) => {
+                // -L keeps the symlinks followed in $PWD and is the default, -P resolves them
+                let mut physical = false;
+                let mut operands = &args[1..];
+                while let Some(option @ ("-L" | "-P")) = operands.first().map(String::as_str) {
+                    physical = option == "-P";
+                    operands = &operands[1..];
+                }
+                let target = match operands {
+                    // an empty $HOME counts as unset
+                    [] => match self
+                        .get_var("HOME")
+                        .filter(|home| !home.is_empty())
+                        .map(str::to_string)
+                        .or_else(|| self.passwd_home())
+                    {
+                        Some(home) => PathBuf::from(home),
+                        None => {
+                            let _ = writeln!(self.stderr(), "trash: cd: HOME is not set");
+                            return Ok(1);
+                        }
+                    },
+                    [dir] if dir == "-" => match self.get_var("OLDPWD") {
+                        Some(oldpwd) => {
+                            let _ = writeln!(self.stdout(), "{}", oldpwd);
+                            PathBuf::from(oldpwd)
+                        }
+                        None => {
+                            let _ = writeln!(self.stderr(), "trash: cd: OLDPWD is not set");
+                            return Ok(1);
+                        }
+                    },
+                    [dir] => {
+                        // a relative directory is looked for in $CDPATH first, and printed
+                        // when found there
+                        let found = match self.get_var("CDPATH") {
+                            Some(cdpath) if !dir.starts_with('/') && !dir.starts_with('.') => {
+                                cdpath
+                                    .split(':')
+                                    .filter(|entry| !entry.is_empty())
+                                    .map(|entry| Path::new(entry).join(dir))
+                                    .find(|path| path.is_dir())
+                            }
+                            _ => None,
+                        };
+                        match found {
+                            Some(path) => {
+                                let _ = writeln!(self.stdout(), "{}", path.display());
+                                path
+                            }
+                            None => PathBuf::from(dir),
+                        }
+                    }
+                    _ => {
+                        let _ = writeln!(self.stderr(), "trash: cd: too many arguments");
+                        return Ok(1);
+                    }
+                };
+
+                let pwd = self.current_dir()?;
+                // the logical directory is the path as given on top of $PWD with . and ..
+                // removed, so cd .. goes back out of a symlink
+                let logical = normalize_path(&pwd.join(&target));
+
+                if let Err(e) = chdir(&logical) {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: cd: {}: {}",
+                        target.display(),
+                        e.desc()
+                    );
+                    return Ok(1);
+                } else {
+                    // update PWD and OLDPWD
+                    let new_pwd = match physical {
+                        true => getcwd()?,
+                        false => logical,
+                    };
+                    for (name, dir) in [("OLDPWD", pwd), ("PWD", new_pwd)] {
+                        match self.set_var(name, dir.to_string_lossy().into_owned()) {
+                            Ok(()) => self.export_var(name),
+                            Err(e) => {
+                                let _ = writeln!(self.stderr(), "trash: cd: {}", e);
+                            }
+                        }
+                    }
+                }
+            }
+            BuiltinCommand::Wait(args) => {
+                let mut status = 0;
+                let force = args.get(1).is_some_and(|arg| arg == "-f");
+                let specs = &args[1 + force as usize..];
+                if specs.is_empty() {
+                    for index in 0..self.jobs.len() {
+                        self.wait_for_job(index, force)?;
+                    }
+                    for pid in std::mem::take(&mut self.substitutions) {
+                        wait_child(pid, None)?;
+                    }
+                } else {
+                    for spec in specs {
+                        let substitution = spec.parse().ok().and_then(|pid| {
+                            self.substitutions.iter().position(|p| p.as_raw() == pid)
+                        });
+                        match self.find_job(spec) {
+                            Some(index) => status = self.wait_for_job(index, force)?,
+                            // $! after a process substitution is its process
+                            None if let Some(index) = substitution => {
+                                let pid = self.substitutions.remove(index);
+                                status = status_code(wait_child(pid, None)?).unwrap_or(0);
+                            }
+                            None => {
+                                let _ =
+                                    writeln!(self.stderr(), "trash: wait: {}: no such job", spec);
+                                status = 127;
+                            }
+                        }
+                    }
+                }
+                self.jobs
+                    .retain(|job| !matches!(job.state, JobState::Done(_)));
+                return Ok(status);
+            }
+            // with job control off there are no process groups to move between
+            BuiltinCommand::Fg(args) | BuiltinCommand::Bg(args) if !self.options.monitor => {
+                let _ = writeln!(
+                    self.stderr(),
+                    "trash: {}: no job control in this shell",
+                    args[0]
+                );
+                return Ok(1);
+            }
+            BuiltinCommand::Fg(args) => {
+                let Some(index) = self.job_control_target(&args) else {
+                    return Ok(1);
+                };
+                let job = &mut self.jobs[index];
+                let _ = writeln!(FdWriter(self.fds.get(1)), "{}", job.command);
+                let _ = tcsetpgrp(&self.stdin_handle, job.pid);
+                if matches!(job.state, JobState::Stopped(_)) {
+                    let _ = killpg(job.pid, Signal::SIGCONT);
+                    job.state = JobState::Running;
+                }
+                let status = self.wait_for_job(index, false);
+                let _ = tcsetpgrp(&self.stdin_handle, self.shell_pid);
+                let job = &self.jobs[index];
+                match job.state {
+                    JobState::Stopped(_) => {
+                        println!("\n[{}]  Stopped\t{}", job.id, job.command)
+                    }
+                    _ => {
+                        self.jobs.remove(index);
+                    }
+                }
+                return status;
+            }
+            BuiltinCommand::Bg(args) => {
+                let Some(index) = self.job_control_target(&args) else {
+                    return Ok(1);
+                };
+                let job = &mut self.jobs[index];
+                if matches!(job.state, JobState::Stopped(_)) {
+                    let _ = killpg(job.pid, Signal::SIGCONT);
+                    job.state = JobState::Running;
+                }
+                let _ = writeln!(FdWriter(self.fds.get(1)), "[{}] {} &", job.id, job.command);
+            }
+            BuiltinCommand::Kill(args) => {
+                let mut operands = &args[1..];
+                // the signal as -NAME, -NUMBER, -s NAME or -n NUMBER, 0 only checks the process
+                let spec = match operands {
+                    [flag] if flag == "-l" => {
+                        let names: Vec<&str> = Signal::iterator()
+                            .map(|sig| sig.as_str().trim_start_matches("SIG"))
+                            .collect();
+                        let _ = writeln!(self.stdout(), "{}", names.join(" "));
+                        return Ok(0);
+                    }
+                    [flag, spec, rest @ ..] if flag == "-s" || flag == "-n" => {
+                        operands = rest;
+                        Some(spec.as_str())
+                    }
+                    [flag, rest @ ..] if flag.len() > 1 && flag.starts_with('-') => {
+                        operands = rest;
+                        Some(&flag[1..])
+                    }
+                    _ => None,
+                };
+                let sig = match spec {
+                    None => Some(Signal::SIGTERM),
+                    Some("0") => None,
+                    Some(spec) => match parse_signal(spec) {
+                        Some(sig) => Some(sig),
+                        None => {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: kill: {}: invalid signal specification",
+                                spec
+                            );
+                            return Ok(1);
+                        }
+                    },
+                };
+                if operands.is_empty() {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ..."
+                    );
+                    return Ok(2);
+                }
+                let mut status = 0;
+                for operand in operands {
+                    // a job's whole process group gets the signal when it has one of its own
+                    let result = if operand.starts_with('%') {
+                        match self.find_job(operand) {
+                            Some(index) => self.signal_job(index, sig),
+                            None => {
+                                let _ = writeln!(
+                                    self.stderr(),
+                                    "trash: kill: {}: no such job",
+                                    operand
+                                );
+                                status = 1;
+                                continue;
+                            }
+                        }
+                    } else if let Ok(pid) = operand.parse::<i32>() {
+                        kill(Pid::from_raw(pid), sig)
+                    } else {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: kill: {}: arguments must be process or job IDs",
+                            operand
+                        );
+                        status = 1;
+                        continue;
+                    };
+                    if let Err(error) = result {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: kill: ({}) - {}",
+                            operand,
+                            error.desc()
+                        );
+                        status = 1;
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Conditional(args) => {
+                let expression = match args.as_slice() {
+                    [_, expression @ .., last] if last == "]]" => expression,
+                    _ => {
+                        let _ = writeln!(self.stderr(), "trash: [[: missing `]]'");
+                        return Ok(2);
+                    }
+                };
+                return match Conditional::evaluate(self, expression) {
+                    Ok(true) => Ok(0),
+                    Ok(false) => Ok(1),
+                    Err(e) => {
+                        let _ = writeln!(self.stderr(), "trash: [[: {}", e);
+                        Ok(2)
+                    }
+                };
+            }
+            BuiltinCommand::Export(args) => {
+                return Ok(self.mark_variables(&args, |variable| &mut variable.exported));
+            }
+            BuiltinCommand::Readonly(args) => {
+                return Ok(self.mark_variables(&args, |variable| &mut variable.readonly));
+            }
+            BuiltinCommand::Echo(args) => {
+                let mut newline = true;
+                let mut escapes = self.options.xpg_echo;
+                let mut words = &args[1..];
+                // options are only recognized as long as every letter is a valid one
+                while let Some(option) = words.first().and_then(|word| word.strip_prefix('-')) {
+                    if option.is_empty() || !option.chars().all(|c| "neE".contains(c)) {
+                        break;
+                    }
+                    for c in option.chars() {
+                        match c {
+                            'n' => newline = false,
+                            'e' => escapes = true,
+                            _ => escapes = false,
+                        }
+                    }
+                    words = &words[1..];
+                }
+
+                let mut output = words.join(" ");
+                if escapes {
+                    let (escaped, stop) = echo_escapes(&output);
+                    output = escaped;
+                    newline &= !stop;
+                }
+                if newline {
+                    output.push('\n');
+                }
+                if let Err(e) = self.stdout().write_all(output.as_bytes()) {
+                    let _ = writeln!(self.stderr(), "trash: echo: write error: {}", e);
+                    return Ok(1);
+                }
+            }
+            BuiltinCommand::Printf(args) => {
+                // with -v name the output is assigned to the variable instead
+                let (variable, operands) = match &args[1..] {
+                    [flag, name, operands @ ..] if flag == "-v" => (Some(name), operands),
+                    operands => (None, operands),
+                };
+                let Some(format) = operands.first() else {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: printf: usage: printf [-v var] format [arguments]"
+                    );
+                    return Ok(2);
+                };
+                if let Some(name) = variable {
+                    let base = name.split_once('[').map_or(name.as_str(), |(base, _)| base);
+                    if !is_valid_name(base) {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: printf: `{}': not a valid identifier",
+                            name
+                        );
+                        return Ok(2);
+                    }
+                }
+                let printf = Printf::format(format, &operands[1..]);
+                for error in &printf.errors {
+                    let _ = writeln!(self.stderr(), "trash: printf: {}", error);
+                }
+                if let Some(name) = variable {
+                    if let Err(e) = self.set_var(name, printf.output) {
+                        let _ = writeln!(self.stderr(), "trash: {}", e);
+                        return Ok(1);
+                    }
+                } else if let Err(e) = self.stdout().write_all(printf.output.as_bytes()) {
+                    let _ = writeln!(self.stderr(), "trash: printf: write error: {}", e);
+                    return Ok(1);
+                }
+                return Ok(if printf.errors.is_empty() { 0 } else { 1 });
+            }
+            BuiltinCommand::Command(mut args) => {
+                let default_path = args.get(1).is_some_and(|arg| arg == "-p");
+                let skip = if default_path { 2 } else { 1 };
+                if args.len() <= skip {
+                    return Ok(0);
+                }
+                // functions are skipped, with -p programs are looked up in the default path
+                let command = match self.builtin_or_external(args.split_off(skip)) {
+                    Command::External(external) if default_path => {
+                        let Some(path) = search_path(&external.cmd, DEFAULT_PATH) else {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: {}: command not found",
+                                external.cmd
+                            );
+                            return Ok(127);
+                        };
+                        let path = path.to_string_lossy().into_owned();
+                        Command::External(ExternalCommand::new(path, external.args))
+                    }
+                    command => command,
+                };
+                self.execute(command, Vec::new())?;
+                return Ok(self.last_status);
+            }
+            BuiltinCommand::Enable(args) => {
+                let disable = args.get(1).is_some_and(|arg| arg == "-n");
+                let names = &args[if disable { 2 } else { 1 }..];
+                if names.is_empty() {
+                    let flag = if disable { " -n" } else { "" };
+                    for name in BuiltinCommand::NAMES {
+                        if self.disabled_builtins.contains(name) == disable {
+                            let _ = writeln!(self.stdout(), "enable{} {}", flag, name);
+                        }
+                    }
+                    return Ok(0);
+                }
+                let mut status = 0;
+                for name in names {
+                    if !BuiltinCommand::NAMES.contains(&name.as_str()) {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: enable: {}: not a shell builtin",
+                            name
+                        );
+                        status = 1;
+                    } else if !disable {
+                        self.disabled_builtins.remove(name);
+                    } else if name == "exit" || name == "enable" {
+                        // without them there'd be no way to leave or to undo this
+                        let _ =
+                            writeln!(self.stderr(), "trash: enable: {}: cannot be disabled", name);
+                        status = 1;
+                    } else {
+                        self.disabled_builtins.insert(name.clone());
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Builtin(mut args) => {
+                if args.len() < 2 {
+                    return Ok(0);
+                }
+                // run the builtin itself, skipping any other lookup for the name
+                return match BuiltinCommand::from_args(args.split_off(1)) {
+                    Ok(builtin) => self.handle_builtin(builtin),
+                    Err(args) => {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: builtin: {}: not a shell builtin",
+                            args[0]
+                        );
+                        Ok(1)
+                    }
+                };
+            }
+            BuiltinCommand::Set(args) => match args.get(1).map(String::as_str) {
+                None => {
+                    let mut names: Vec<&String> = self.variables.keys().collect();
+                    names.sort();
+                    for name in names {
+                        let value = match &self.variables[name].value {
+                            Value::Scalar(value) => value.clone(),
+                            array => array.declaration(),
+                        };
+                        let _ = writeln!(self.stdout(), "{}={}", name, value);
+                    }
+                }
+                Some(_) => {
+                    let mut operands = &args[1..];
+                    let mut replace = false;
+                    while let Some(option) = operands.first() {
+                        if option == "--" {
+                            operands = &operands[1..];
+                            replace = true;
+                            break;
+                        }
+                        // -n is the short name of -o noexec
+                        if option == "-n" || option == "+n" {
+                            self.options.noexec = option == "-n";
+                            operands = &operands[1..];
+                            continue;
+                        }
+                        if option != "-o" && option != "+o" {
+                            if option.starts_with(['-', '+']) {
+                                let _ = writeln!(
+                                    self.stderr(),
+                                    "trash: set: {}: invalid option",
+                                    option
+                                );
+                                return Ok(2);
+                            }
+                            break;
+                        }
+                        // without a name every option is listed, by +o as the set commands
+                        // that restore them
+                        let Some(name) = operands.get(1) else {
+                            let states: Vec<(&str, bool)> = self
+                                .options
+                                .by_name()
+                                .into_iter()
+                                .map(|(name, on)| (name, *on))
+                                .collect();
+                            let reusable = option == "+o";
+                            for (name, on) in states {
+                                let _ = match reusable {
+                                    true => writeln!(
+                                        self.stdout(),
+                                        "set {}o {}",
+                                        if on { '-' } else { '+' },
+                                        name
+                                    ),
+                                    false => writeln!(
+                                        self.stdout(),
+                                        "{:<15}\t{}",
+                                        name,
+                                        if on { "on" } else { "off" }
+                                    ),
+                                };
+                            }
+                            return Ok(0);
+                        };
+                        if let Err(error) = self.options.set(name, option == "-o") {
+                            let _ = writeln!(self.stderr(), "trash: set: {}", error);
+                            return Ok(2);
+                        }
+                        operands = &operands[2..];
+                    }
+                    // plain operands, or any after --, become the positional parameters
+                    if replace || !operands.is_empty() {
+                        self.positional = operands.to_vec();
+                    }
+                }
+            },
+            BuiltinCommand::Shift(args) => {
+                let count = match args.get(1) {
+                    Some(arg) => match arg.parse::<usize>() {
+                        Ok(count) => count,
+                        Err(_) => {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: shift: {}: numeric argument required",
+                                arg
+                            );
+                            return Ok(1);
+                        }
+                    },
+                    None => 1,
+                };
+                // shifting past the end leaves the parameters alone
+                if count > self.positional.len() {
+                    return Ok(1);
+                }
+                self.positional.drain(..count);
+            }
+            BuiltinCommand::Local(args) => {
+                if self.locals.is_empty() {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: local: can only be used in a function"
+                    );
+                    return Ok(1);
+                }
+                // without arguments the locals of the innermost call are listed like by set
+                if args.len() == 1 {
+                    let mut names: Vec<&String> = self.locals.last().unwrap().keys().collect();
+                    names.sort();
+                    for name in names {
+                        let value = match self.variables.get(name).map(|variable| &variable.value) {
+                            Some(Value::Scalar(value)) => value.clone(),
+                            Some(array) => array.declaration(),
+                            None => continue,
+                        };
+                        let _ = writeln!(self.stdout(), "{}={}", name, value);
+                    }
+                    return Ok(0);
+                }
+                let mut status = 0;
+                for arg in &args[1..] {
+                    let (name, value) = match arg.split_once('=') {
+                        Some((name, value)) => (name, Some(value.to_string())),
+                        None => (arg.as_str(), None),
+                    };
+                    if !is_valid_name(name) {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: local: `{}': not a valid identifier",
+                            arg
+                        );
+                        status = 1;
+                        continue;
+                    }
+                    if self
+                        .variables
+                        .get(name)
+                        .is_some_and(|variable| variable.readonly)
+                    {
+                        let _ = writeln!(self.stderr(), "trash: {}: readonly variable", name);
+                        status = 1;
+                        continue;
+                    }
+                    self.make_local(name);
+                    if let Some(value) = value {
+                        let _ = self.set_var(name, value);
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Declare(args) => {
+                let mut associative = false;
+                let mut indexed = false;
+                let mut global = false;
+                let mut export = false;
+                let mut readonly = false;
+                let mut integer = false;
+                let mut print = false;
+                let mut operands = &args[1..];
+                while let Some(flags) = operands
+                    .first()
+                    .and_then(|arg| arg.strip_prefix('-'))
+                    .filter(|flags| !flags.is_empty())
+                {
+                    operands = &operands[1..];
+                    if flags == "-" {
+                        break;
+                    }
+                    for flag in flags.chars() {
+                        match flag {
+                            'A' => associative = true,
+                            'a' => indexed = true,
+                            'g' => global = true,
+                            'i' => integer = true,
+                            'p' => print = true,
+                            'x' => export = true,
+                            'r' => readonly = true,
+                            _ => {
+                                let _ = writeln!(
+                                    self.stderr(),
+                                    "trash: declare: -{}: invalid option",
+                                    flag
+                                );
+                                return Ok(2);
+                            }
+                        }
+                    }
+                }
+                if operands.is_empty() {
+                    let mut names: Vec<&String> = self.variables.keys().collect();
+                    names.sort();
+                    for name in names {
+                        let _ = writeln!(self.stdout(), "{}", self.declaration(name));
+                    }
+                    return Ok(0);
+                }
+                let mut status = 0;
+                if print {
+                    for name in operands {
+                        if self.variables.contains_key(name) {
+                            let _ = writeln!(self.stdout(), "{}", self.declaration(name));
+                        } else {
+                            let _ = writeln!(self.stderr(), "trash: declare: {}: not found", name);
+                            status = 1;
+                        }
+                    }
+                    return Ok(status);
+                }
+                for operand in operands {
+                    let (name, value) = match operand.split_once('=') {
+                        Some((name, value)) => (name, Some(value.to_string())),
+                        None => (operand.as_str(), None),
+                    };
+                    if !is_valid_name(name) {
+                        let _ = writeln!(
+                            self.stderr(),
+                            "trash: declare: `{}': not a valid identifier",
+                            operand
+                        );
+                        status = 1;
+                        continue;
+                    }
+                    // like local, declare in a function makes the variable local to it, unless
+                    // -g is given. when a function has made the name local the global is
+                    // swapped into the table meanwhile
+                    let in_function = !self.locals.is_empty() && !global;
+                    let shadowed = match global {
+                        true => self
+                            .locals
+                            .iter()
+                            .position(|frame| frame.contains_key(name)),
+                        false => None,
+                    };
+                    if let Some(frame) = shadowed {
+                        self.swap_global(frame, name);
+                    }
+                    let failed = 'declare: {
+                        if (in_function || associative || indexed || value.is_some())
+                            && self
+                                .variables
+                                .get(name)
+                                .is_some_and(|variable| variable.readonly)
+                        {
+                            let _ = writeln!(self.stderr(), "trash: {}: readonly variable", name);
+                            break 'declare true;
+                        }
+                        if in_function {
+                            self.make_local(name);
+                        }
+                        // a string becomes the element 0 of the new array, an array keeps its kind
+                        if associative || indexed {
+                            let array = match self
+                                .variables
+                                .get(name)
+                                .map(|variable| &variable.value)
+                            {
+                                Some(Value::Scalar(value)) if associative => {
+                                    Value::Associative(HashMap::from([("0".into(), value.clone())]))
+                                }
+                                Some(Value::Scalar(value)) => {
+                                    Value::Indexed(BTreeMap::from([(0, value.clone())]))
+                                }
+                                Some(array) => array.clone(),
+                                None if associative => Value::Associative(HashMap::new()),
+                                None => Value::Indexed(BTreeMap::new()),
+                            };
+                            let variable =
+                                self.variables.entry(name.to_string()).or_insert(Variable {
+                                    value: Value::Scalar(String::new()),
+                                    exported: false,
+                                    readonly: false,
+                                    integer: false,
+                                });
+                            variable.value = array;
+                        }
+                        // the attribute applies to the value given with it
+                        let value = value
+                            .or_else(|| (!self.variables.contains_key(name)).then(String::new));
+                        if integer {
+                            let variable =
+                                self.variables.entry(name.to_string()).or_insert(Variable {
+                                    value: Value::Scalar(String::new()),
+                                    exported: false,
+                                    readonly: false,
+                                    integer: false,
+                                });
+                            variable.integer = true;
+                        }
+                        if let Some(value) = value
+                            && let Err(e) = self.set_var(name, value)
+                        {
+                            let _ = writeln!(self.stderr(), "trash: {}", e);
+                            break 'declare true;
+                        }
+                        let variable = self.variables.get_mut(name).unwrap();
+                        variable.exported |= export;
+                        variable.readonly |= readonly;
+                        variable.integer |= integer;
+                        false
+                    };
+                    if let Some(frame) = shadowed {
+                        self.swap_global(frame, name);
+                    }
+                    if failed {
+                        status = 1;
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Read(args) => {
+                let mut raw = false;
+                let mut timeout = None;
+                // -n stops at a newline or after the count, -N only after the count
+                let mut limit = None;
+                let mut delimited = true;
+                let mut names = &args[1..];
+                while let Some(option) = names.first().and_then(|arg| arg.strip_prefix('-')) {
+                    if option.is_empty() || option == "-" {
+                        names = &names[usize::from(option == "-")..];
+                        break;
+                    }
+                    names = &names[1..];
+                    for (index, flag) in option.char_indices() {
+                        if flag == 'r' {
+                            raw = true;
+                            continue;
+                        }
+                        if !matches!(flag, 't' | 'n' | 'N') {
+                            let _ =
+                                writeln!(self.stderr(), "trash: read: -{}: invalid option", flag);
+                            return Ok(2);
+                        }
+                        // the value is the rest of this word or the next one
+                        let value = match &option[index + 1..] {
+                            "" => {
+                                let Some((value, rest)) = names.split_first() else {
+                                    let _ = writeln!(
+                                        self.stderr(),
+                                        "trash: read: -{}: option requires an argument",
+                                        flag
+                                    );
+                                    return Ok(2);
+                                };
+                                names = rest;
+                                value.as_str()
+                            }
+                            value => value,
+                        };
+                        if flag == 't' {
+                            match value
+                                .parse::<f64>()
+                                .ok()
+                                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                            {
+                                Some(duration) => timeout = Some(duration),
+                                None => {
+                                    let _ = writeln!(
+                                        self.stderr(),
+                                        "trash: read: {}: invalid timeout specification",
+                                        value
+                                    );
+                                    return Ok(1);
+                                }
+                            }
+                        } else {
+                            match value.parse::<usize>() {
+                                Ok(count) => limit = Some(count),
+                                Err(_) => {
+                                    let _ = writeln!(
+                                        self.stderr(),
+                                        "trash: read: {}: invalid number",
+                                        value
+                                    );
+                                    return Ok(1);
+                                }
+                            }
+                            delimited = flag == 'n';
+                        }
+                        break;
+                    }
+                }
+                let default_name = ["REPLY".to_string()];
+                let names = if names.is_empty() {
+                    &default_name[..]
+                } else {
+                    names
+                };
+                if let Some(name) = names.iter().find(|name| !is_valid_name(name)) {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: read: `{}': not a valid identifier",
+                        name
+                    );
+                    return Ok(1);
+                }
+
+                let fd = self.fds.get(0);
+                if fd == CLOSED_FD {
+                    let _ = writeln!(self.stderr(), "trash: read: {}", Errno::EBADF.desc());
+                    return Ok(1);
+                }
+                // -t 0 only reports whether input is waiting
+                if timeout == Some(Duration::ZERO) {
+                    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+                    let ready = poll(&mut fds, PollTimeout::ZERO).unwrap_or(0) > 0;
+                    return Ok(if ready { 0 } else { 1 });
+                }
+                let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                // a count shouldn't wait for the terminal to hand over a whole line
+                let _mode = limit.and_then(|_| TerminalMode::noncanonical(fd));
+                let mut bytes = Vec::new();
+                let mut escaped = Vec::new();
+                let mut status = 0;
+                let mut escaping = false;
+                // characters read so far and continuation bytes still to come for the last one
+                let mut count = 0;
+                let mut continuation = 0u8;
+                while limit.is_none_or(|limit| count < limit) {
+                    let byte = match self.read_byte(fd, deadline) {
+                        Ok(Some(byte)) => byte,
+                        Ok(None) => {
+                            status = 1;
+                            break;
+                        }
+                        // variables stay as they were when no full line arrived in time
+                        Err(Errno::ETIMEDOUT) => return Ok(128 + Signal::SIGALRM as i32),
+                        Err(error) => {
+                            let _ = writeln!(self.stderr(), "trash: read: {}", error.desc());
+                            return Ok(1);
+                        }
+                    };
+                    let was_escaped = escaping;
+                    if escaping {
+                        escaping = false;
+                        // a backslash before the newline continues the line
+                        if byte == b'\n' {
+                            continue;
+                        }
+                    } else {
+                        match byte {
+                            b'\n' if delimited => break,
+                            b'\\' if !raw => {
+                                escaping = true;
+                                continue;
+                            }
+                            _ => {}
+                        }
+                    }
+                    bytes.push(byte);
+                    escaped.push(was_escaped);
+                    continuation = match byte {
+                        0x80..=0xbf => continuation.saturating_sub(1),
+                        0xc0..=0xdf => 1,
+                        0xe0..=0xef => 2,
+                        0xf0..=0xff => 3,
+                        _ => 0,
+                    };
+                    if continuation == 0 {
+                        count += 1;
+                    }
+                }
+                // keep track of which characters were escaped through the utf-8 decoding
+                let mut line = Vec::new();
+                let mut start = 0;
+                for chunk in bytes.utf8_chunks() {
+                    for c in chunk.valid().chars() {
+                        line.push((c, escaped[start]));
+                        start += c.len_utf8();
+                    }
+                    if !chunk.invalid().is_empty() {
+                        line.push((char::REPLACEMENT_CHARACTER, escaped[start]));
+                        start += chunk.invalid().len();
+                    }
+                }
+                let ifs = self.get_var("IFS").unwrap_or(" \t\n").to_string();
+                let fields = split_read_fields(&line, &ifs, names.len());
+                for (name, value) in names.iter().zip(fields) {
+                    if let Err(error) = self.set_var(name, value) {
+                        let _ = writeln!(self.stderr(), "trash: {}", error);
+                        return Ok(1);
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Return(args) => {
+                if self.locals.is_empty() && self.sourcing == 0 {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: return: can only `return' from a function or sourced script"
+                    );
+                    return Ok(2);
+                }
+                // without an argument the status of the last command is kept
+                let status = match args.get(1) {
+                    Some(arg) => match arg.parse::<i64>() {
+                        Ok(status) => (status & 0xff) as i32,
+                        Err(_) => {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: return: {}: numeric argument required",
+                                arg
+                            );
+                            2
+                        }
+                    },
+                    None => self.last_status,
+                };
+                self.returning = true;
+                return Ok(status);
+            }
+            BuiltinCommand::Hash(args) => {
+                let mut operands = args[1..].iter();
+                match operands.next().map(String::as_str) {
+                    None if self.hashed.is_empty() => {
+                        let _ = writeln!(self.stderr(), "trash: hash: hash table empty");
+                    }
+                    None => {
+                        let mut hashed: Vec<_> = self.hashed.iter().collect();
+                        hashed.sort();
+                        let _ = writeln!(self.stdout(), "hits\tcommand");
+                        for (_, (path, hits)) in hashed {
+                            let _ = writeln!(self.stdout(), "{:4}\t{}", hits, path.display());
+                        }
+                    }
+                    // as commands that remember the same paths again
+                    Some("-l") => {
+                        let mut hashed: Vec<_> = self.hashed.iter().collect();
+                        hashed.sort();
+                        for (name, (path, _)) in hashed {
+                            let _ = writeln!(
+                                self.stdout(),
+                                "builtin hash -p {} {}",
+                                path.display(),
+                                name
+                            );
+                        }
+                    }
+                    Some("-r") => self.hashed.clear(),
+                    Some("-p") => {
+                        let (Some(path), Some(name)) = (operands.next(), operands.next()) else {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: hash: -p: usage: hash -p path name"
+                            );
+                            return Ok(2);
+                        };
+                        self.hashed.insert(name.clone(), (PathBuf::from(path), 0));
+                    }
+                    Some("-d") => {
+                        let mut status = 0;
+                        for name in operands {
+                            if self.hashed.remove(name).is_none() {
+                                let _ = writeln!(self.stderr(), "trash: hash: {}: not found", name);
+                                status = 1;
+                            }
+                        }
+                        return Ok(status);
+                    }
+                    Some(_) => {
+                        let mut status = 0;
+                        for name in &args[1..] {
+                            if self.hash_command(name).is_none() && !name.contains('/') {
+                                let _ = writeln!(self.stderr(), "trash: hash: {}: not found", name);
+                                status = 1;
+                            }
+                        }
+                        return Ok(status);
+                    }
+                }
+            }
+            BuiltinCommand::Alias(args) => {
+                let mut operands = &args[1..];
+                if operands.first().is_some_and(|arg| arg == "-p") {
+                    operands = &operands[1..];
+                }
+                if operands.is_empty() {
+                    let mut aliases: Vec<_> = self.aliases.iter().collect();
+                    aliases.sort();
+                    for (name, text) in aliases {
+                        let _ = writeln!(self.stdout(), "alias {}={}", name, single_quote(text));
+                    }
+                    return Ok(0);
+                }
+                let mut status = 0;
+                for operand in operands {
+                    match operand.split_once('=') {
+                        Some((name, _))
+                            if name.is_empty()
+                                || name.contains(['/', '$', '`', '\'', '"', '\\', ' ']) =>
+                        {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: alias: `{}': invalid alias name",
+                                name
+                            );
+                            status = 1;
+                        }
+                        Some((name, text)) => {
+                            self.aliases.insert(name.to_string(), text.to_string());
+                        }
+                        None => match self.aliases.get(operand) {
+                            Some(text) => {
+                                let text = single_quote(text);
+                                let _ = writeln!(self.stdout(), "alias {}={}", operand, text);
+                            }
+                            None => {
+                                let _ =
+                                    writeln!(self.stderr(), "trash: alias: {}: not found", operand);
+                                status = 1;
+                            }
+                        },
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Unalias(args) => {
+                if args.get(1).is_some_and(|arg| arg == "-a") {
+                    self.aliases.clear();
+                    return Ok(0);
+                }
+                let mut status = 0;
+                for name in &args[1..] {
+                    if self.aliases.remove(name).is_none() {
+                        let _ = writeln!(self.stderr(), "trash: unalias: {}: not found", name);
+                        status = 1;
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Disown(args) => {
+                let mut nohup = false;
+                let mut all = false;
+                let mut running = false;
+                let mut operands = &args[1..];
+                while let Some(flags) = operands
+                    .first()
+                    .and_then(|arg| arg.strip_prefix('-'))
+                    .filter(|flags| !flags.is_empty())
+                {
+                    operands = &operands[1..];
+                    if flags == "-" {
+                        break;
+                    }
+                    for flag in flags.chars() {
+                        match flag {
+                            'h' => nohup = true,
+                            'a' => all = true,
+                            'r' => running = true,
+                            _ => {
+                                let _ = writeln!(
+                                    self.stderr(),
+                                    "trash: disown: -{}: invalid option",
+                                    flag
+                                );
+                                return Ok(2);
+                            }
+                        }
+                    }
+                }
+                self.reap_jobs();
+                let mut status = 0;
+                let mut indices = Vec::new();
+                if all || running {
+                    indices.extend(
+                        (0..self.jobs.len()).filter(|&index| {
+                            !running || self.jobs[index].state == JobState::Running
+                        }),
+                    );
+                } else if operands.is_empty() {
+                    match self.jobs.len().checked_sub(1) {
+                        Some(index) => indices.push(index),
+                        None => {
+                            let _ = writeln!(self.stderr(), "trash: disown: current: no such job");
+                            return Ok(1);
+                        }
+                    }
+                }
+                for spec in operands {
+                    match self.find_job(spec) {
+                        Some(index) => indices.push(index),
+                        None => {
+                            let _ = writeln!(self.stderr(), "trash: disown: {}: no such job", spec);
+                            status = 1;
+                        }
+                    }
+                }
+                // -h keeps the job in the table, otherwise the shell forgets about it
+                if nohup {
+                    for index in indices {
+                        self.jobs[index].nohup = true;
+                    }
+                } else {
+                    let mut index = 0;
+                    self.jobs.retain(|_| {
+                        index += 1;
+                        !indices.contains(&(index - 1))
+                    });
+                }
+                return Ok(status);
+            }
+            // where the current function was called from, with a number the frame that many
+            // calls further out along with the function that made the call
+            BuiltinCommand::Caller(args) => {
+                let depth = match args.get(1).map(|arg| arg.parse::<usize>()) {
+                    Some(Ok(depth)) => Some(depth),
+                    Some(Err(_)) => {
+                        let _ =
+                            writeln!(self.stderr(), "trash: caller: {}: invalid number", args[1]);
+                        return Ok(2);
+                    }
+                    None => None,
+                };
+                let Some(index) = self.calls.len().checked_sub(1 + depth.unwrap_or(0)) else {
+                    return Ok(1);
+                };
+                let frame = &self.calls[index];
+                let _ = match depth {
+                    Some(_) => {
+                        let function = match index.checked_sub(1) {
+                            Some(outer) => self.calls[outer].function.as_str(),
+                            None => "main",
+                        };
+                        writeln!(self.stdout(), "{} {} {}", frame.line, function, frame.file)
+                    }
+                    None => writeln!(self.stdout(), "{} {}", frame.line, frame.file),
+                };
+            }
+            // the options of set -o under the names bash gives them in shopt
+            BuiltinCommand::Shopt(args) => {
+                let on = match args.get(1).map(String::as_str) {
+                    Some("-s") => true,
+                    Some("-u") => false,
+                    // names alone print their state, succeeding only if all of them are on
+                    Some(name) if !name.starts_with('-') => {
+                        let mut status = 0;
+                        for name in &args[1..] {
+                            let state = self
+                                .options
+                                .by_name()
+                                .into_iter()
+                                .find(|(option, _)| option == name)
+                                .map(|(_, on)| *on);
+                            let _ = match state {
+                                Some(on) => writeln!(
+                                    self.stdout(),
+                                    "{:<15}\t{}",
+                                    name,
+                                    if on { "on" } else { "off" }
+                                ),
+                                None => writeln!(
+                                    self.stderr(),
+                                    "trash: shopt: {}: invalid option name",
+                                    name
+                                ),
+                            };
+                            if state != Some(true) {
+                                status = 1;
+                            }
+                        }
+                        return Ok(status);
+                    }
+                    _ => {
+                        let _ =
+                            writeln!(self.stderr(), "trash: shopt: usage: shopt [-s|-u] name ...");
+                        return Ok(2);
+                    }
+                };
+                let mut status = 0;
+                for name in &args[2..] {
+                    if let Err(error) = self.options.set(name, on) {
+                        let _ = writeln!(self.stderr(), "trash: shopt: {}", error);
+                        status = 1;
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Colon => {}
+            BuiltinCommand::History => {
+                for (number, line) in self.history.iter().enumerate() {
+                    let _ = writeln!(self.stdout(), "{:5}  {}", number + 1, line);
+                }
+            }
+            BuiltinCommand::Help(args) => {
+                // with topics only the matching reserved words and operators are listed
+                let topics = &args[1..];
+                let selected = |name: &str| topics.is_empty() || topics.iter().any(|t| t == name);
+                let words: Vec<(&str, &str)> = RESERVED_WORDS
+                    .into_iter()
+                    .filter(|(name, _)| selected(name))
+                    .collect();
+                let operators: Vec<(&str, &str)> = Operator::ALL
+                    .iter()
+                    .map(|operator| (operator.as_str(), operator.description()))
+                    .filter(|(name, _)| selected(name))
+                    .collect();
+                if words.is_empty() && operators.is_empty() {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: help: no help topics match `{}'",
+                        topics.join(" ")
+                    );
+                    return Ok(1);
+                }
+                for (title, entries) in [("reserved words", words), ("operators", operators)] {
+                    if entries.is_empty() {
+                        continue;
+                    }
+                    let _ = writeln!(self.stdout(), "{}:", title);
+                    for (name, description) in entries {
+                        let _ = writeln!(self.stdout(), "  {:<10}{}", name, description);
+                    }
+                }
+            }
+            BuiltinCommand::Exec(args) => {
+                let _ = self.stdout_handle.flush();
+                // without a command the redirections stay in place for the rest of the shell
+                if args.len() == 1 {
+                    if let Err(e) = self.fds.install() {
+                        let _ = writeln!(self.stderr(), "trash: exec: {}", e.desc());
+                        return Ok(1);
+                    }
+                    for (&fd, &raw) in self.fds.fds.iter().filter(|(fd, _)| **fd > 2) {
+                        if raw == CLOSED_FD {
+                            self.exec_fds.remove(&fd);
+                        } else {
+                            self.exec_fds.insert(fd);
+                        }
+                    }
+                    return Ok(0);
+                }
+                let command = ExternalCommand::new(args[1].clone(), args[1..].to_vec());
+                if self.find_executable(&command.cmd).is_none() {
+                    let _ = writeln!(self.stderr(), "trash: exec: {}: not found", command.cmd);
+                    if !self.interactive {
+                        self.shutdown(127);
+                    }
+                    return Ok(127);
+                }
+                self.close_stray_fds();
+                self.exec_external(&command, &[]);
+            }
+            BuiltinCommand::Trap(args) => {
+                // listed as trap commands that set them again, with -p only the given ones
+                if args.len() == 1 || args[1] == "-p" {
+                    let mut names = Vec::new();
+                    let mut status = 0;
+                    for spec in args.iter().skip(2) {
+                        match trap_name(spec) {
+                            Some(name) => names.push(name),
+                            None => {
+                                let _ = writeln!(
+                                    self.stderr(),
+                                    "trash: trap: {}: invalid signal specification",
+                                    spec
+                                );
+                                status = 1;
+                            }
+                        }
+                    }
+                    let mut traps: Vec<_> = self
+                        .traps
+                        .iter()
+                        .filter(|(name, _)| args.len() <= 2 || names.contains(name))
+                        .collect();
+                    traps.sort();
+                    let listing: String = traps
+                        .into_iter()
+                        .map(|(name, action)| {
+                            format!("trap -- {} {}\n", single_quote(action), name)
+                        })
+                        .collect();
+                    let _ = write!(self.stdout(), "{}", listing);
+                    return Ok(status);
+                }
+                // a lone signal or - as the action resets to the default disposition
+                let args = match args[1].as_str() {
+                    "--" => &args[1..],
+                    _ => &args[..],
+                };
+                let (action, specs) = match args.get(1).map(String::as_str) {
+                    None => return Ok(0),
+                    Some("-") => (None, &args[2..]),
+                    _ if args.len() == 2 => (None, &args[1..]),
+                    Some(action) => (Some(action.to_string()), &args[2..]),
+                };
+                let mut status = 0;
+                for spec in specs {
+                    match trap_name(spec) {
+                        Some(name) => self.set_trap(&name, action.clone())?,
+                        None => {
+                            let _ = writeln!(
+                                self.stderr(),
+                                "trash: trap: {}: invalid signal specification",
+                                spec
+                            );
+                            status = 1;
+                        }
+                    }
+                }
+                return Ok(status);
+            }
+            BuiltinCommand::Source(args) => {
+                let Some(path) = args.get(1) else {
+                    let _ = writeln!(
+                        self.stderr(),
+                        "trash: {}: filename argument required",
+                        args[0]
+                    );
+                    return Ok(2);
+                };
+                return match std::fs::read_to_string(path) {
+                    Ok(script) => {
+                        self.source(path, &script)?;
+                        Ok(self.last_status)
+                    }
+                    Err(e) => {
+                        let _ =
+                            writeln!(self.stderr(), "trash: {}: {}", path, io_error_message(&e));
+                        Ok(1)
+                    }
+                };
+            }
+            BuiltinCommand::Jobs => {
+                self.reap_jobs();
+                for job in &self.jobs {
+                    let _ = writeln!(
+                        self.stdout(),
+                        "[{}]  {}\t{}",
+                        job.id,
+                        job.state,
+                        job.command
+                    );
+                }
+                self.jobs
+                    .retain(|job| !matches!(job.state, JobState::Done(_)));
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+struct ExternalCommand {
+    // TODO: look into OsString for POSIX compatibility
+    cmd: String,
+    args: Vec<String>,
+}
+
+impl ExternalCommand {
+    fn new(cmd: String, args: Vec<String>) -> Self {
+        Self { cmd, args }
+    }
+
+    fn args_as_cstring(&self) -> Vec<CString> {
+        self.args
+            .iter()
+            .map(|arg| CString::new(arg.as_str()).unwrap())
+            .collect()
+    }
+}
+
+// how the shell was started, from its arguments and TRASH_QUIET
+struct Invocation {
+    login: bool,
+    quiet: bool,
+    version: bool,
+    command: Option<String>,
+    norc: bool,
+    noexec: bool,
+    // -s reads commands from stdin, the arguments are all positional parameters
+    stdin: bool,
+    rcfile: Option<String>,
+    // the script or command name followed by the positional parameters
+    operands: Vec<String>,
+}
+
+impl Invocation {
+    fn parse(args: impl IntoIterator<Item = String>, quiet: bool) -> Result<Self, String> {
+        let mut args = args.into_iter().peekable();
+        // like login(1) starting the shell as -trash, or asked for explicitly
+        let login = args.next().is_some_and(|name| name.starts_with('-'));
+        let mut invocation = Self {
+            login,
+            quiet,
+            version: false,
+            command: None,
+            norc: false,
+            noexec: false,
+            stdin: false,
+            rcfile: None,
+            operands: Vec::new(),
+        };
+        // options come before the script or command name, anything after belongs to it
+        while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg != "-") {
+            match arg.as_str() {
+                "--" => break,
+                "--version" => {
+                    invocation.version = true;
+                    break;
+                }
+                "-l" | "--login" => invocation.login = true,
+                "-q" => invocation.quiet = true,
+                "-n" => invocation.noexec = true,
+                "-s" => invocation.stdin = true,
+                "--norc" => invocation.norc = true,
+                "--rcfile" => match args.next() {
+                    Some(path) => invocation.rcfile = Some(path),
+                    None => return Err("--rcfile: option requires an argument".to_string()),
+                },
+                "-c" => match args.next() {
+                    Some(input) => invocation.command = Some(input),
+                    None => return Err("-c: option requires an argument".to_string()),
+                },
+                _ => return Err(format!("{}: invalid option", arg)),
+            }
+        }
+        invocation.operands = args.collect();
+        Ok(invocation)
+    }
+
+    // the version line an interactive shell greets with, unless -q or TRASH_QUIET
+    fn banner(&self, interactive: bool) -> Option<String> {
+        (interactive && !self.quiet).then(version)
+    }
+
+    // reads the startup files unless --norc, -n only takes effect after them so they still run
+    fn start(&self, shell: &mut Shell, rcfile: Option<&str>) {
+        if !self.norc {
+            shell.read_startup_files(rcfile);
+        }
+        shell.options.noexec = self.noexec;
+    }
+}
+
+fn version() -> String {
+    format!("trash {}", env!("CARGO_PKG_VERSION"))
+}
+
+fn main() {
+    let quiet = env::var_os("TRASH_QUIET").is_some_and(|value| !value.is_empty());
+    let invocation = match Invocation::parse(env::args(), quiet) {
+        Ok(invocation) => invocation,
+        Err(e) => {
+            eprintln!("trash: {}", e);
+            exit(2);
+        }
+    };
+    if invocation.version {
+        println!("{}", version());
+        exit(0);
+    }
+    let mut args = invocation.operands.iter().cloned();
+    if let Some(input) = &invocation.command {
+        let mut shell = Shell::new(false).expect("Failed to spawn shell");
+        shell.login = invocation.login;
+        if let Some(name) = args.next() {
+            shell.name = name;
+        }
+        shell.positional = args.collect();
+        shell
+            .install_signal_handlers()
+            .expect("Failed to install signal handlers");
+        invocation.start(&mut shell, None);
+        // a line at a time like a script, so an alias is there for the lines after it
+        shell.run_script(input).expect("Failed to run command");
+        shell.shutdown(shell.last_status);
+    }
+    if !invocation.stdin
+        && let Some(path) = args.next()
+    {
+        let mut shell = Shell::new(false).expect("Failed to spawn shell");
+        shell.login = invocation.login;
+        shell.positional = args.collect();
+        let script = match std::fs::read_to_string(&path) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("trash: {}: {}", path, io_error_message(&e));
+                exit(127);
+            }
+        };
+        shell.name = path;
+        shell
+            .install_signal_handlers()
+            .expect("Failed to install signal handlers");
+        invocation.start(&mut shell, None);
+        shell.run_script(&script).expect("Failed to run script");
+        shell.shutdown(shell.last_status);
+    }
+
+    let interactive = isatty(std::io::stdin()).unwrap_or(false);
+    let mut shell = Shell::new(interactive).expect("Failed to spawn shell");
+    shell.login = invocation.login;
+    shell.positional = args.collect();
+    if let Some(banner) = invocation.banner(interactive) {
+        println!("{}", banner);
+    }
+    invocation.start(&mut shell, invocation.rcfile.as_deref());
+    shell.run().expect("Failed to run shell");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // counts allocations per thread so tests running in parallel don't skew each other
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(|count| count.get());
+        let result = f();
+        (result, ALLOCATIONS.with(|count| count.get()) - before)
+    }
+
+    #[test]
+    fn test_simple_words() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo hello world");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hello".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("world".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_single_quotes() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo 'hello world'");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hello world".into(), Quoting::SingleQuoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_double_quotes() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo \"hello world\"");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hello world".into(), Quoting::DoubleQuoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_and_double_quotes_with_space() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo 'hello' \"world\"");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hello".into(), Quoting::SingleQuoted),
+                Token::Whitespace,
+                Token::Word("world".into(), Quoting::DoubleQuoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_and_double_quotes_without_space() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo 'hello'\"world\"");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hello".into(), Quoting::SingleQuoted),
+                Token::Word("world".into(), Quoting::DoubleQuoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_and_double_inside_eachother() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo 'he\"llo' \"w'orld\"");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("he\"llo".into(), Quoting::SingleQuoted),
+                Token::Whitespace,
+                Token::Word("w'orld".into(), Quoting::DoubleQuoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_semicolon() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo hi;");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hi".into(), Quoting::Unquoted),
+                Token::Operator(Operator::Semicolon),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_and() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("hello && world");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("hello".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::And),
+                Token::Whitespace,
+                Token::Word("world".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_andpercent() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("ls &");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("ls".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::Andpercent),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_pipe() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo 'hi' | cat");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hi".into(), Quoting::SingleQuoted),
+                Token::Whitespace,
+                Token::Operator(Operator::Pipe),
+                Token::Whitespace,
+                Token::Word("cat".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_or() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("no || yes");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("no".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::Or),
+                Token::Whitespace,
+                Token::Word("yes".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiple_whitespace() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo  hi");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("hi".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaping_double_quotes() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo \\\"hi\\\"");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("\"hi\"".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaping_backslash() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo \\\\");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("\\".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaping_newline() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo \"\\n\"");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("\n".into(), Quoting::DoubleQuoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_bg_pid_survives_wait() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("true &").unwrap();
+        let pid = shell.last_bg_pid.expect("background job should set $!");
+        assert_eq!(shell.expand_variables("$!"), pid.to_string());
+
+        shell.run_str("wait").unwrap();
+        assert!(shell.jobs.is_empty());
+        assert_eq!(shell.expand_variables("$!"), pid.to_string());
+    }
+
+    #[test]
+    fn test_wait_for_last_bg_pid() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("false & wait $!").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert!(shell.last_bg_pid.is_some());
+    }
+
+    #[test]
+    fn test_background_jobs_end_to_end() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("sleep 0 & wait").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert!(shell.jobs.is_empty());
+
+        // the first job runs until the test closes the write end of its input
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        shell
+            .run_str(&format!("cat <&{} > /dev/null &", reader.as_raw_fd()))
+            .unwrap();
+        shell.run_str("exit 3 &").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.jobs.len(), 2);
+        shell.run_str("wait %2").unwrap();
+        assert_eq!(shell.last_status, 3);
+        let jobs = run_output(&mut shell, "jobs");
+        assert!(jobs.starts_with("[1]  Running\tcat"), "{}", jobs);
+        assert_eq!(jobs.lines().count(), 1);
+
+        drop(writer);
+        shell.run_str("wait").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert!(shell.jobs.is_empty());
+        assert_eq!(run_output(&mut shell, "jobs"), "");
+        shell.run_str("wait %1 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 127);
+    }
+
+    #[test]
+    fn test_fg_bg_without_job_control() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("sleep 0 &").unwrap();
+        for command in ["fg", "bg %1", "fg %9"] {
+            let path = temp_path("no-job-control");
+            shell
+                .run_str(&format!("{} 2> {}", command, path.display()))
+                .unwrap();
+            assert_eq!(shell.last_status, 1);
+            let error = std::fs::read_to_string(&path).unwrap();
+            assert!(
+                error.ends_with(": no job control in this shell\n"),
+                "{}",
+                error
+            );
+            std::fs::remove_file(&path).unwrap();
+        }
+        shell.run_str("wait").unwrap();
+    }
+
+    #[test]
+    fn test_background_pipeline_is_one_job() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(&mut shell, "sleep 100 | cat & jobs"),
+            "[1]  Running\tsleep 100 | cat\n"
+        );
+        let pids: Vec<Pid> = shell.jobs[0]
+            .processes
+            .iter()
+            .map(|(pid, _)| *pid)
+            .collect();
+        assert_eq!(pids.len(), 2);
+        assert_eq!(shell.last_bg_pid, Some(pids[1]));
+        shell.run_str("kill %1; wait %1").unwrap();
+        assert_eq!(shell.last_status, 143);
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_monitor_option() {
+        let mut shell = Shell::new(false).unwrap();
+        assert!(!shell.options.monitor);
+        shell.run_str("sleep 1 &").unwrap();
+        let pid = shell.last_bg_pid.unwrap();
+        assert_eq!(nix::unistd::getpgid(Some(pid)), nix::unistd::getpgid(None));
+        shell.run_str("kill $!; wait").unwrap();
+        shell.run_str("set -o monitor").unwrap();
+        assert!(shell.options.monitor);
+        shell.run_str("set +o monitor; fg").unwrap();
+        assert!(!shell.options.monitor);
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_wait_reports_signal_of_killed_job() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("sleep 100 & kill %1; wait %1").unwrap();
+        assert_eq!(shell.last_status, 143);
+        shell
+            .run_str("sleep 100 & kill -s KILL $!; wait $!")
+            .unwrap();
+        assert_eq!(shell.last_status, 137);
+        assert!(shell.jobs.is_empty());
+        shell.run_str("kill %1 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(
+            run_output(&mut shell, "{ kill 2>&1; }"),
+            "trash: kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ...\n"
+        );
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_wait_f_waits_through_stops() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("sleep 0.3 & pid=$!; kill -s STOP $pid; wait %1")
+            .unwrap();
+        // 128 plus the signal that stopped it, SIGSTOP here
+        assert_eq!(shell.last_status, 128 + Signal::SIGSTOP as i32);
+        shell
+            .run_str("(sleep 0.2; kill -s CONT $pid) & wait -f %1")
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("wait").unwrap();
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_invocation_version_and_quiet() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let parse = |list: &[&str], quiet| Invocation::parse(args(list), quiet).ok().unwrap();
+        assert!(parse(&["trash", "--version"], false).version);
+        assert_eq!(version(), format!("trash {}", env!("CARGO_PKG_VERSION")));
+        // the banner is only for interactive shells, and -q or TRASH_QUIET turn it off
+        assert_eq!(parse(&["trash"], false).banner(true), Some(version()));
+        assert_eq!(parse(&["trash"], false).banner(false), None);
+        assert_eq!(parse(&["trash", "-q"], false).banner(true), None);
+        assert_eq!(parse(&["trash"], true).banner(true), None);
+        let invocation = parse(&["-trash", "-n", "-c", "echo", "name", "arg"], false);
+        assert!(invocation.login && invocation.noexec);
+        assert_eq!(invocation.command.as_deref(), Some("echo"));
+        assert_eq!(invocation.operands, ["name", "arg"]);
+        assert_eq!(
+            Invocation::parse(args(&["trash", "-x"]), false).err(),
+            Some("-x: invalid option".to_string())
+        );
+    }
+
+    #[test]
+    fn test_wait_after_job_finished() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("false &").unwrap();
+        // the job ends before the next line is read, like in `printf 'false &\n...' | trash`
+        while !matches!(shell.jobs[0].state, JobState::Done(_)) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            shell.notify_jobs();
+        }
+        shell.notify_jobs();
+        shell.run_str("wait $!").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn test_wait_unknown_job() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(&mut shell, "{ wait %3 2>&1; }"),
+            "trash: wait: %3: no such job\n"
+        );
+        assert_eq!(shell.last_status, 127);
+    }
+
+    // running, not a zombie nobody has reaped yet
+    fn process_alive(pid: i32) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+            let state = stat.rsplit_once(") ").map(|(_, rest)| rest.chars().next());
+            !matches!(state, Some(Some('Z' | 'X')))
+        })
+    }
+
+    #[test]
+    fn test_disown_h_job_survives_hangup() {
+        let path = temp_path("disown-pids");
+        let status = run_forked(|shell| {
+            let script = format!(
+                "sleep 5 & echo $! > {0}; sleep 5 & echo $! >> {0}; disown -h %1",
+                path.display()
+            );
+            shell.run_str(&script).unwrap();
+            assert_eq!(shell.jobs.len(), 2);
+            shell.terminate(Signal::SIGHUP);
+        });
+        assert!(matches!(status, WaitStatus::Signaled(_, Signal::SIGHUP, _)));
+        let pids: Vec<i32> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while process_alive(pids[1]) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(process_alive(pids[0]));
+        assert!(!process_alive(pids[1]));
+        kill(Pid::from_raw(pids[0]), Signal::SIGKILL).unwrap();
+        // plain disown forgets the job
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("sleep 5 & disown; disown %1 2> /dev/null")
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert!(shell.jobs.is_empty());
+        kill(shell.last_bg_pid.unwrap(), Signal::SIGKILL).unwrap();
+        waitpid(shell.last_bg_pid.unwrap(), None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interactive_exit_hangs_up_jobs() {
+        let path = temp_path("exit-hangup-pids");
+        for interactive in [false, true] {
+            let status = run_forked(|shell| {
+                shell.interactive = interactive;
+                let script = format!("sleep 5 & echo $! > {}; exit 2> /dev/null", path.display());
+                shell.run_str(&script).unwrap();
+            });
+            assert!(matches!(status, WaitStatus::Exited(_, 0)));
+            let pid: i32 = std::fs::read_to_string(&path)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while interactive && process_alive(pid) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(process_alive(pid), !interactive);
+            let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("trash-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_redirect_all_operators() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("ls &> out &>> log &");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("ls".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::RedirectAll),
+                Token::Whitespace,
+                Token::Word("out".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::RedirectAppendAll),
+                Token::Whitespace,
+                Token::Word("log".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::Andpercent),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiple_redirections() {
+        let input = temp_path("multiple-redirections-in");
+        let output = temp_path("multiple-redirections-out");
+        let error = temp_path("multiple-redirections-err");
+        let command = format!(
+            "sh -c 'cat; echo err >&2' < {} > {} 2> {}",
+            input.display(),
+            output.display(),
+            error.display()
+        );
+        let mut shell = Shell::new(false).unwrap();
+        let Ok(lists) = shell.parse_input(&command, 1) else {
+            panic!("expected the command to parse");
+        };
+        let Node::Simple(simple) = &lists[0].first.commands[0] else {
+            panic!("expected a simple command");
+        };
+        let redirects: Vec<String> = simple.redirects.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            redirects,
+            vec![
+                format!("<{}", input.display()),
+                format!(">{}", output.display()),
+                format!("2>{}", error.display()),
+            ]
+        );
+        std::fs::write(&input, "in\n").unwrap();
+        shell.run_str(&command).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "in\n");
+        assert_eq!(std::fs::read_to_string(&error).unwrap(), "err\n");
+        for path in [input, output, error] {
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_redirect_all_truncates() {
+        let path = temp_path("redirect-all");
+        let mut shell = Shell::new(false).unwrap();
+        std::fs::write(&path, "old content\n").unwrap();
+        shell
+            .run_str(&format!(
+                "sh -c 'echo out; echo err >&2' &> {}",
+                path.display()
+            ))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "out\nerr\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redirect_append_all() {
+        let path = temp_path("redirect-append-all");
+        let mut shell = Shell::new(false).unwrap();
+        std::fs::write(&path, "first\n").unwrap();
+        shell
+            .run_str(&format!(
+                "sh -c 'echo out; echo err >&2' &>> {}",
+                path.display()
+            ))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nout\nerr\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redirect_all_builtin() {
+        let path = temp_path("redirect-all-builtin");
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!("wait %4 &> {}", path.display()))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "trash: wait: %4: no such job\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_variable_set() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("FOO=bar; [[ -v FOO ]]").unwrap();
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_conditional_variable_set_but_empty() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("FOO=; [[ -v FOO ]]").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("[[ -n $FOO ]]").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_conditional_variable_unset() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("[[ -v TRASH_TEST_UNSET ]]").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("[[ ! -v TRASH_TEST_UNSET ]] && [[ -v PATH ]]").unwrap();
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_conditional_operators() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("[[ abc == a* && ( 2 -lt 10 || -z x ) ]]").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("[[ abc != a[bc]c ]]").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("[[ 1 -eq one ]]").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_conditional_quoting() {
+        let mut shell = Shell::new(false).unwrap();
+        // a quoted pattern matches literally, also where it comes from a variable
+        shell.run_str("p='a*'").unwrap();
+        for (test, status) in [
+            ("[[ abc == \"a*\" ]]", 1),
+            ("[[ 'a*' == \"a*\" ]]", 0),
+            ("[[ abc == a'*' ]]", 1),
+            ("[[ abc == $p ]]", 0),
+            ("[[ abc == \"$p\" ]]", 1),
+            ("[[ abc != \"$p\" ]]", 0),
+        ] {
+            shell.run_str(test).unwrap();
+            assert_eq!(shell.last_status, status, "{}", test);
+        }
+        // only operators written out unquoted are operators
+        shell.run_str("op='&&'").unwrap();
+        for test in [
+            "[[ \"(\" == \"(\" ]]",
+            "[[ $op ]]",
+            "[[ $op == '&&' ]]",
+            "[[ \"!\" ]]",
+            "[[ ! -z x && ( 1 -lt 2 ) ]]",
+        ] {
+            shell.run_str(test).unwrap();
+            assert_eq!(shell.last_status, 0, "{}", test);
+        }
+    }
+
+    #[test]
+    fn test_assignment_prefix_is_temporary() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("FOO=bar sh -c 'test \"$FOO\" = bar'").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("FOO"), None);
+    }
+
+    #[test]
+    fn test_assignment_prefix_stays_for_special_builtins() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("FOO=bar :").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("FOO"), Some("bar"));
+        assert!(!shell.variables["FOO"].exported);
+        // cd is a regular builtin
+        shell.run_str("DIR=/ cd . && BAR=baz set --").unwrap();
+        assert_eq!(shell.get_var("DIR"), None);
+        assert_eq!(shell.get_var("BAR"), Some("baz"));
+    }
+
+    #[test]
+    fn test_readonly_assignment_fails() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("readonly FOO=one").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("FOO=two").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.get_var("FOO"), Some("one"));
+        assert!(shell.set_var("FOO", "three".into()).is_err());
+    }
+
+    #[test]
+    fn test_readonly_prefix_assignment_skips_command() {
+        let path = temp_path("readonly-prefix");
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!("readonly FOO; FOO=x touch {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_export_to_child() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("FOO=bar; sh -c 'test -z \"$FOO\"'").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("export FOO; sh -c 'test \"$FOO\" = bar'").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("export 1FOO=bar").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_backslash_in_single_quotes() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo 'a\\cb'");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("a\\cb".into(), Quoting::SingleQuoted),
+            ]
+        );
+    }
+
+    fn run_output(shell: &mut Shell, input: &str) -> String {
+        let thread: String = format!("{:?}", std::thread::current().id())
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect();
+        let path = temp_path(&format!("output-{}", thread));
+        shell
+            .run_str(&format!("{} > {}", input, path.display()))
+            .unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_command_substitution_sees_the_shell() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("f() { inner=$(echo $1 \"$#\"); }; f one two")
+            .unwrap();
+        assert_eq!(shell.get_var("inner"), Some("one 2"));
+        shell
+            .run_str("g() { echo from g; }; v=value; out=\"$(g) $(echo $v)\"")
+            .unwrap();
+        assert_eq!(shell.get_var("out"), Some("from g value"));
+        shell.run_str("status=$(exit 3)").unwrap();
+        assert_eq!(shell.last_status, 3);
+    }
+
+    #[test]
+    fn test_command_word_substituted_to_nothing() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("x=1 $(true)").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("x"), Some("1"));
+        shell.run_str("$(exit 4)").unwrap();
+        assert_eq!(shell.last_status, 4);
+        // quoted it is still a command name, an empty one
+        shell.run_str("\"$(true)\" 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 127);
+        // the redirections are still made, and apply to errors of the assignments
+        let path = temp_path("substituted_to_nothing");
+        shell
+            .run_str(&format!("$(true) > {}", path.display()))
+            .unwrap();
+        assert!(path.exists());
+        let _ = std::fs::remove_file(&path);
+        shell.run_str("readonly x").unwrap();
+        assert_eq!(
+            run_output(&mut shell, "{ x=2 $(true) 2>&1; }"),
+            "trash: x: readonly variable\n"
+        );
+    }
+
+    #[test]
+    fn test_field_splitting() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("f() { echo $#; }; set -- 'a b' c").unwrap();
+        assert_eq!(run_output(&mut shell, "f \"$@\""), "2\n");
+        assert_eq!(run_output(&mut shell, "f \"$*\""), "1\n");
+        assert_eq!(run_output(&mut shell, "f $@"), "3\n");
+        assert_eq!(run_output(&mut shell, "f x\"$@\"y"), "2\n");
+        assert_eq!(run_output(&mut shell, "echo \"<$@>\""), "<a b c>\n");
+        assert_eq!(run_output(&mut shell, "f $(echo /tmp /etc)"), "2\n");
+        assert_eq!(run_output(&mut shell, "f \"$(echo a b)\""), "1\n");
+        shell.run_str("x='1 2  3'; a[0]='p q'; a[1]=r").unwrap();
+        assert_eq!(run_output(&mut shell, "f $x"), "3\n");
+        assert_eq!(run_output(&mut shell, "f \"$x\""), "1\n");
+        assert_eq!(run_output(&mut shell, "f \"${a[@]}\""), "2\n");
+        assert_eq!(run_output(&mut shell, "f \"${a[*]}\""), "1\n");
+        assert_eq!(run_output(&mut shell, "f ${a[@]}"), "3\n");
+        // an empty expansion is no field at all, unless quoted
+        assert_eq!(run_output(&mut shell, "f $unset"), "0\n");
+        assert_eq!(run_output(&mut shell, "f \"$unset\" ''"), "2\n");
+        shell.run_str("set --").unwrap();
+        assert_eq!(run_output(&mut shell, "f \"$@\""), "0\n");
+        // non-whitespace separators delimit empty fields too, "$*" joins with the first one
+        shell.run_str("set -- a b; IFS=:; x=a::b").unwrap();
+        assert_eq!(run_output(&mut shell, "f $x"), "3\n");
+        assert_eq!(run_output(&mut shell, "echo \"$*\""), "a:b\n");
+        shell.run_str("IFS=").unwrap();
+        assert_eq!(run_output(&mut shell, "echo \"$*\""), "ab\n");
+        // assignments aren't split
+        shell
+            .run_str("IFS=' '; y=$x; declare z=$(echo '1  2')")
+            .unwrap();
+        assert_eq!(shell.get_var("y"), Some("a::b"));
+        assert_eq!(shell.get_var("z"), Some("1  2"));
+    }
+
+    #[test]
+    fn test_process_substitutions_are_reaped() {
+        let mut shell = Shell::new(false).unwrap();
+        let mut pids = Vec::new();
+        for _ in 0..50 {
+            assert_eq!(run_output(&mut shell, "cat <(echo hi)"), "hi\n");
+            pids.push(shell.last_bg_pid.unwrap());
+            assert!(shell.substitutions.len() <= 2);
+        }
+        shell.run_str("cat <(exit 3); wait $!").unwrap();
+        assert_eq!(shell.last_status, 3);
+        shell.run_str("cat <(true); wait").unwrap();
+        assert!(shell.substitutions.is_empty());
+        // a zombie would still be listed, as state Z
+        for pid in pids {
+            if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                assert!(!stat.contains(") Z "), "{}", stat);
+            }
+        }
+    }
+
+    #[test]
+    fn test_echo_escape_stops_output() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "echo -e 'a\\cb'"), "a");
+        assert_eq!(run_output(&mut shell, "echo 'a\\cb'"), "a\\cb\n");
+        assert_eq!(run_output(&mut shell, "echo -n -e 'x\\ty'"), "x\ty");
+    }
+
+    #[test]
+    fn test_xpg_echo_interprets_escapes() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "echo 'a\\tb'"), "a\\tb\n");
+        shell.run_str("set -o xpg_echo").unwrap();
+        assert_eq!(run_output(&mut shell, "echo 'a\\tb'"), "a\tb\n");
+        assert_eq!(run_output(&mut shell, "echo -E 'a\\tb'"), "a\\tb\n");
+        shell.run_str("set +o xpg_echo").unwrap();
+        assert_eq!(run_output(&mut shell, "echo 'a\\tb'"), "a\\tb\n");
+    }
+
+    #[test]
+    fn test_printf_q_quotes_for_reuse() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("value=\"it's a *.rs; \\$HOME | x\"").unwrap();
+        let quoted = run_output(&mut shell, "printf '%q' \"$value\"");
+        assert_eq!(quoted, "'it'\\''s a *.rs; $HOME | x'");
+        shell
+            .run_str(&format!("copy={}; [[ $copy == \"$value\" ]]", quoted))
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(run_output(&mut shell, "printf '[%q]' ''"), "['']");
+    }
+
+    #[test]
+    fn test_printf_escape_stops_output() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "printf '%b-%s' 'x\\cy' z"), "x");
+        assert_eq!(run_output(&mut shell, "printf 'a\\cb'"), "a\\cb");
+    }
+
+    #[test]
+    fn test_printf_conversions() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(&mut shell, "printf '%s %s\\n' 1 2 3"),
+            "1 2\n3 \n"
+        );
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "printf '%5.2f|%-4d|%x|%c|%05d|%e' 3.14159 7 255 hello -42 1234.5"
+            ),
+            " 3.14|7   |ff|h|-0042|1.234500e+03"
+        );
+        shell.run_str("printf %d abc 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_printf_into_variable() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "printf -v out '%05d' 42"), "");
+        assert_eq!(shell.get_var("out"), Some("00042"));
+        // the format is reused until all arguments are consumed
+        shell.run_str("printf -v out '%s=%d;' a 1 b 2 c").unwrap();
+        assert_eq!(shell.get_var("out"), Some("a=1;b=2;c=0;"));
+        shell
+            .run_str("declare -A m; printf -v m[k] %x 255")
+            .unwrap();
+        assert_eq!(run_output(&mut shell, "echo ${m[k]}"), "ff\n");
+        assert_eq!(
+            run_output(&mut shell, "{ printf -v 1x %s a 2>&1; }"),
+            "trash: printf: `1x': not a valid identifier\n"
+        );
+        assert_eq!(shell.last_status, 2);
+        shell
+            .run_str("readonly out; printf -v out %s a 2> /dev/null")
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.get_var("out"), Some("a=1;b=2;c=0;"));
+    }
+
+    #[test]
+    fn test_builtin_runs_builtin() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "builtin echo -n hello"), "hello");
+        shell.run_str("builtin ls 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("builtin").unwrap();
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_inline_comment() {
+        let parser = Parser::new();
+        let tokens = parser.tokenize("echo a#b 'c'#d # comment; ls");
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("a#b".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Word("c".into(), Quoting::SingleQuoted),
+                Token::Word("#d".into(), Quoting::Unquoted),
+                Token::Whitespace,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_skips_comments_and_blank_lines() {
+        let path = temp_path("source-comments");
+        std::fs::write(
+            &path,
+            "# leading comment\nFOO=1\n\n   # indented comment\nfalse\n# after false\n\nBAR=$?\necho done > /dev/null # inline\n",
+        )
+        .unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!("source {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("FOO"), Some("1"));
+        assert_eq!(shell.get_var("BAR"), Some("1"));
+
+        std::fs::write(&path, "# only a comment\n\n").unwrap();
+        shell
+            .run_str(&format!("false; . {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_source_redirects_nested_commands() {
+        let script = temp_path("source-redirect-script");
+        std::fs::write(&script, "echo builtin\nsh -c 'echo external'\n").unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(&mut shell, &format!("source {}", script.display())),
+            "builtin\nexternal\n"
+        );
+        shell.run_str("source /nonexistent 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        std::fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn test_pipeline_connects_stages() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(&mut shell, "printf 'b\\na\\n' | sort | tr a-z A-Z"),
+            "A\nB\n"
+        );
+        assert_eq!(shell.get_var("PIPESTATUS"), Some("0 0 0"));
+    }
+
+    #[test]
+    fn test_pipestatus_middle_stage_fails() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("echo hi | sh -c 'cat; exit 3' | cat > /dev/null")
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("PIPESTATUS"), Some("0 3 0"));
+
+        shell
+            .run_str("echo hi | sh -c 'cat > /dev/null; exit 1' | true; STATUS=$PIPESTATUS")
+            .unwrap();
+        assert_eq!(shell.get_var("STATUS"), Some("0 1 0"));
+
+        shell.run_str("false").unwrap();
+        assert_eq!(shell.get_var("PIPESTATUS"), Some("1"));
+    }
+
+    #[test]
+    fn test_inline_if() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(&mut shell, "if true; then echo yes; fi"),
+            "yes\n"
+        );
+        assert_eq!(run_output(&mut shell, "if false; then echo yes; fi"), "");
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_output(&mut shell, "if false; then echo yes; else echo no; fi"),
+            "no\n"
+        );
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "if false; then echo 1; elif true; then echo 2; else echo 3; fi"
+            ),
+            "2\n"
+        );
+        shell.run_str("if true; then false; fi").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_inline_while() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "while false; do echo x; done"), "");
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "X=; while [[ $X != aaa ]]; do X=a$X; echo $X; done"
+            ),
+            "a\naa\naaa\n"
+        );
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "X=; until [[ $X == aa ]]; do X=a$X; done; echo $X"
+            ),
+            "aa\n"
+        );
+    }
+
+    #[test]
+    fn test_reserved_word_syntax_errors() {
+        let mut shell = Shell::new(false).unwrap();
+        for input in [
+            "if true; fi",
+            "if true; then echo x",
+            "then echo x",
+            "while true; do; done",
+            "echo a; fi",
+        ] {
+            shell.run_str(input).unwrap();
+            assert_eq!(shell.last_status, 2, "{}", input);
+        }
+        // reserved words are plain arguments outside of command position
+        assert_eq!(run_output(&mut shell, "echo if then fi"), "if then fi\n");
+    }
+
+    #[test]
+    fn test_set_positional_parameters() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("set -- x y").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $#"), "2\n");
+        assert_eq!(run_output(&mut shell, "echo $2"), "y\n");
+        assert_eq!(run_output(&mut shell, "echo $1 $@"), "x x y\n");
+        // $10 is $1 followed by a literal 0
+        assert_eq!(run_output(&mut shell, "echo $10"), "x0\n");
+        shell.run_str("set --").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $#"), "0\n");
+        shell.run_str("set a b c").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $# $3"), "3 c\n");
+    }
+
+    #[test]
+    fn test_parameter_count_follows_shift_and_set() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "echo $#"), "0\n");
+        shell.run_str("set -- a b c d").unwrap();
+        shell.run_str("shift").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $# $1"), "3 b\n");
+        shell.run_str("shift 2").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $# $1"), "1 d\n");
+        shell.run_str("shift 2").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run_output(&mut shell, "echo $#"), "1\n");
+        shell.run_str("set -- x y z").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $#"), "3\n");
+        shell.run_str("shift 3").unwrap();
+        assert_eq!(run_output(&mut shell, "echo $#"), "0\n");
+    }
+
+    #[test]
+    fn test_duplicate_operators() {
+        let parser = Parser::new();
+        assert_eq!(
+            parser.tokenize("3>&1 <&4"),
+            vec![
+                Token::Word("3".into(), Quoting::Unquoted),
+                Token::Operator(Operator::DuplicateOut),
+                Token::Word("1".into(), Quoting::Unquoted),
+                Token::Whitespace,
+                Token::Operator(Operator::DuplicateIn),
+                Token::Word("4".into(), Quoting::Unquoted),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_and_restore_stdout() {
+        let inner = temp_path("restore-inner");
+        let outer = temp_path("restore-outer");
+        let mut shell = Shell::new(false).unwrap();
+        // fd 3 keeps the outer stdout while stdout itself goes to another file
+        shell
+            .run_str(&format!(
+                "if true; then if true; then echo saved >&3; sh -c 'echo external >&3'; echo redirected; fi 3>&1 > {}; echo restored; fi > {}",
+                inner.display(),
+                outer.display()
+            ))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&inner).unwrap(), "redirected\n");
+        assert_eq!(
+            std::fs::read_to_string(&outer).unwrap(),
+            "saved\nexternal\nrestored\n"
+        );
+        std::fs::remove_file(&inner).unwrap();
+        std::fs::remove_file(&outer).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_and_close_descriptors() {
+        let mut shell = Shell::new(false).unwrap();
+        let path = temp_path("duplicate-stderr");
+        shell
+            .run_str(&format!("sh -c 'echo err >&2' > {} 2>&1", path.display()))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "err\n");
+        std::fs::remove_file(&path).unwrap();
+        shell
+            .run_str("sh -c 'echo x >&3' 3>&1 3>&- 2> /dev/null")
+            .unwrap();
+        assert_ne!(shell.last_status, 0);
+        shell.run_str("echo x >&9 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_sigchld_flags_finished_job() {
+        install_sigchld_handler().unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("true &").unwrap();
+        // other tests reap their own children too, so only rely on the flag being raised
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !CHILD_STATUS_CHANGED.load(Ordering::Relaxed) {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let pid = shell.jobs[0].pid;
+        while !matches!(shell.jobs[0].state, JobState::Done(_)) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "{} never finished",
+                pid
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            shell.reap_jobs();
+        }
+        assert!(matches!(shell.jobs[0].state, JobState::Done(0)));
+    }
+
+    #[test]
+    fn test_read_from_here_string() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("LINE='1 2 3'; read x y <<< \"$LINE\"")
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("x"), Some("1"));
+        assert_eq!(shell.get_var("y"), Some("2 3"));
+        assert_eq!(run_output(&mut shell, "cat <<<$x"), "1\n");
+    }
+
+    #[test]
+    fn test_here_doc_feeds_pipeline() {
+        let path = temp_path("here-doc-pipe");
+        let mut shell = Shell::new(false).unwrap();
+        let script = format!(
+            "NAME=world\n\
+             cat <<EOF | tr a-z A-Z > {0}\n\
+             hello $NAME\n\
+             EOF\n\
+             cat <<'EOF' >> {0}; cat <<-END >> {0}\n\
+             hello $NAME\n\
+             EOF\n\
+             \tstripped\n\
+             \tEND\n",
+            path.display()
+        );
+        shell.run_script(&script).unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "HELLO WORLD\nhello $NAME\nstripped\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commands_spanning_lines() {
+        let mut shell = Shell::new(false).unwrap();
+        let script = "if false\n\
+                      then\n\
+                        A=then\n\
+                      else\n\
+                        A=else\n\
+                      fi\n\
+                      f() {\n\
+                        # comments and blank lines inside a command are fine\n\
+                      \n\
+                        B=$1\n\
+                      }\n\
+                      f one &&\n\
+                        C=two\n\
+                      D=three\\\n\
+                      four\n";
+        shell.run_script(script).unwrap();
+        assert_eq!(shell.get_var("A"), Some("else"));
+        assert_eq!(shell.get_var("B"), Some("one"));
+        assert_eq!(shell.get_var("C"), Some("two"));
+        assert_eq!(shell.get_var("D"), Some("threefour"));
+        // a command that never completes is a syntax error
+        shell.run_script("if true\nthen\n").unwrap();
+        assert_eq!(shell.last_status, 2);
+        shell.run_script("cat <<EOF\nno end\n").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_quotes_spanning_lines() {
+        let mut shell = Shell::new(false).unwrap();
+        // a backslash joins the lines inside double quotes, an open quote keeps the newline
+        shell
+            .run_script("A=\"one \\\ntwo\"\nB=\"one\ntwo\"\nC='one \\\ntwo'\n")
+            .unwrap();
+        assert_eq!(shell.get_var("A"), Some("one two"));
+        assert_eq!(shell.get_var("B"), Some("one\ntwo"));
+        assert_eq!(shell.get_var("C"), Some("one \\\ntwo"));
+        shell.run_script("echo \"never closed\n").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_associative_arrays() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("declare -A m; m[key]=val; k=other; m[$k]=\"two words\"")
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_output(&mut shell, "echo ${m[key]} ${m[$k]} [${m[none]}] [$m]"),
+            "val two words [] []\n"
+        );
+        // keys and values come out in the same order
+        assert_eq!(
+            run_output(&mut shell, "echo ${!m[@]} / ${m[*]}"),
+            "key other / val two words\n"
+        );
+        assert_eq!(
+            run_output(&mut shell, "declare | grep '^declare -A'"),
+            "declare -A m=([key]=\"val\" [other]=\"two words\" )\n"
+        );
+        // elements need an associative array
+        shell.run_str("s=text; s[1]=x 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell
+            .run_str("f() { declare -A local_map; local_map[a]=1; }; f")
+            .unwrap();
+        assert!(shell.get_var("local_map").is_none());
+    }
+
+    #[test]
+    fn test_indices_of_sparse_array() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("a[0]=first; a[5]=\"sixth one\"").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_output(&mut shell, "echo ${!a[@]} / ${a[@]} / ${a[5]}"),
+            "0 5 / first sixth one / sixth one\n"
+        );
+        assert_eq!(
+            run_output(&mut shell, "declare -p a"),
+            "declare -a a=([0]=\"first\" [5]=\"sixth one\" )\n"
+        );
+        shell.run_str("a[x]=1 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_declare_global_in_function() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("f() { declare -g made=1; local shadow=inner; declare -g shadow=outer; }; f")
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("made"), Some("1"));
+        assert_eq!(shell.get_var("shadow"), Some("outer"));
+    }
+
+    #[test]
+    fn test_declare_prints_attributes() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("declare -rx CONSTANT=fixed; declare -i COUNT=' 7 '; TEXT=plain")
+            .unwrap();
+        assert_eq!(
+            run_output(&mut shell, "declare -p CONSTANT COUNT TEXT"),
+            "declare -rx CONSTANT=\"fixed\"\n\
+             declare -i COUNT=\"7\"\n\
+             declare -- TEXT=\"plain\"\n"
+        );
+        // values are arithmetic, a name stands for its value and an unset one for 0
+        shell.run_str("COUNT=many").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("COUNT"), Some("0"));
+        shell
+            .run_str("x=4; COUNT='x + 1'; COUNT='COUNT*(2-5)%4'")
+            .unwrap();
+        assert_eq!(shell.get_var("COUNT"), Some("-3"));
+        shell
+            .run_str("y=y; COUNT=2/0 2> /dev/null || COUNT=y 2> /dev/null")
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.get_var("COUNT"), Some("-3"));
+        assert_eq!(
+            run_output(&mut shell, "{ COUNT=2/0 2>&1; }"),
+            "trash: 2/0: division by 0\n"
+        );
+        shell.run_str("declare -p UNSET 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_case_terminators() {
+        let mut shell = Shell::new(false).unwrap();
+        let script = "f() {\n\
+                        R=\n\
+                        case $1 in\n\
+                          a | b) R=${R}ab ;;\n\
+                          (c*) R=${R}c ;&\n\
+                          d) R=${R}d ;;\n\
+                          x) R=${R}x ;;&\n\
+                          ?) R=${R}one ;;&\n\
+                          '*') R=${R}star ;;\n\
+                          *) R=${R}default\n\
+                        esac\n\
+                      }\n";
+        shell.run_script(script).unwrap();
+        for (arg, expected) in [
+            ("a", "ab"),
+            ("cat", "cd"),
+            ("d", "d"),
+            ("x", "xonedefault"),
+            ("'*'", "onestar"),
+            ("zz", "default"),
+        ] {
+            shell.run_str(&format!("f {}", arg)).unwrap();
+            assert_eq!(shell.get_var("R"), Some(expected), "{}", arg);
+        }
+        // no matching clause leaves a status of 0
+        shell.run_str("false; case a in b) false;; esac").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("echo a;; echo b").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_completion_context() {
+        assert_eq!(
+            completion_context("ec"),
+            (0, "ec".to_string(), vec![], false)
+        );
+        assert_eq!(
+            completion_context("cd my\\ d"),
+            (3, "my d".to_string(), vec!["cd".to_string()], false)
+        );
+        assert_eq!(
+            completion_context("ls -l | rmdir a "),
+            (
+                16,
+                String::new(),
+                vec!["rmdir".to_string(), "a".to_string()],
+                false
+            )
+        );
+        // redirection targets and descriptors aren't arguments of the command
+        assert_eq!(
+            completion_context("cd 2>err >>out d"),
+            (15, "d".to_string(), vec!["cd".to_string()], false)
+        );
+        assert_eq!(
+            completion_context("cd &> "),
+            (6, String::new(), vec!["cd".to_string()], true)
+        );
+        for name in BuiltinCommand::NAMES {
+            assert!(BuiltinCommand::from_args(vec![name.to_string()]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_cd_completion_offers_directories() {
+        let dir = temp_path("cd-completion");
+        std::fs::create_dir_all(dir.join("alpha")).unwrap();
+        std::fs::create_dir_all(dir.join("cdpath/also")).unwrap();
+        std::fs::write(dir.join("alfile"), "").unwrap();
+        let _ = std::os::unix::fs::symlink("alpha", dir.join("alink"));
+        let mut shell = Shell::new(false).unwrap();
+        let word = format!("{}/al", dir.display());
+        let names = |candidates: Vec<(String, bool)>| -> Vec<String> {
+            candidates.into_iter().map(|(text, _)| text).collect()
+        };
+        assert_eq!(
+            names(shell.completions(&["ls".to_string()], &word)),
+            ["alfile", "alink", "alpha"].map(|name| format!("{}/{}", dir.display(), name))
+        );
+        assert_eq!(
+            names(shell.completions(&["cd".to_string()], &word)),
+            ["alink", "alpha"].map(|name| format!("{}/{}", dir.display(), name))
+        );
+        shell
+            .set_var("CDPATH", format!("{}/cdpath", dir.display()))
+            .unwrap();
+        assert_eq!(
+            names(shell.completions(&["cd".to_string()], "als")),
+            ["also"]
+        );
+        // a unique directory is finished with a slash, a common prefix is filled in
+        let mut editor = LineEditor::new("$ ");
+        editor.insert("cd als");
+        assert!(shell.complete(&mut editor).is_empty());
+        assert_eq!(editor.line, "cd also/");
+        let mut editor = LineEditor::new("$ ");
+        editor.insert(&format!("cd {}/a", dir.display()));
+        assert!(shell.complete(&mut editor).is_empty());
+        assert_eq!(editor.line, format!("cd {}/al", dir.display()));
+        assert_eq!(shell.complete(&mut editor), ["alink/", "alpha/"]);
+        // after a redirection files are offered, even in place of the command
+        let mut editor = LineEditor::new("$ ");
+        editor.insert("echo hi > /tm");
+        assert!(shell.complete(&mut editor).is_empty());
+        assert_eq!(editor.line, "echo hi > /tmp/");
+        let mut editor = LineEditor::new("$ ");
+        editor.insert(&format!("cd <{}/alf", dir.display()));
+        assert!(shell.complete(&mut editor).is_empty());
+        assert_eq!(editor.line, format!("cd <{}/alfile ", dir.display()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tilde_completes_user_names() {
+        let shell = Shell::new(false).unwrap();
+        assert_eq!(
+            shell.completions(&["ls".to_string()], "~roo"),
+            [("~root".to_string(), true)]
+        );
+        assert!(shell.completions(&[], "~no-such-user-").is_empty());
+        let mut editor = LineEditor::new("$ ");
+        editor.insert("cd ~roo");
+        assert!(shell.complete(&mut editor).is_empty());
+        assert_eq!(editor.line, "cd ~root/");
+    }
+
+    #[test]
+    fn test_set_o_lists_every_option() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("set -o globstar").unwrap();
+        let listing = run_output(&mut shell, "set -o");
+        assert!(listing.contains("globstar       \ton\n"), "{}", listing);
+        assert!(listing.contains("vi             \toff\n"), "{}", listing);
+        let commands = run_output(&mut shell, "set +o");
+        let names: Vec<&str> = shell.options.by_name().map(|(name, _)| name).to_vec();
+        assert_eq!(commands.lines().count(), names.len());
+        for name in names {
+            assert!(commands.contains(&format!("o {}\n", name)), "{}", commands);
+        }
+        assert!(commands.contains("set -o globstar\n"));
+        // the commands restore the state they were printed in
+        let mut restored = Shell::new(false).unwrap();
+        restored.run_str("set -o vi; set -o posix").unwrap();
+        restored.run_str(&commands).unwrap();
+        assert_eq!(run_output(&mut restored, "set +o"), commands);
+    }
+
+    #[test]
+    fn test_set_editing_mode() {
+        let mut shell = Shell::new(false).unwrap();
+        assert!(shell.options.emacs && !shell.options.vi);
+        shell.run_str("set -o vi a b").unwrap();
+        assert!(!shell.options.emacs && shell.options.vi);
+        assert_eq!(shell.positional, ["a", "b"]);
+        shell.run_str("set -o emacs").unwrap();
+        assert!(shell.options.emacs && !shell.options.vi);
+        assert_eq!(shell.positional, ["a", "b"]);
+        shell.run_str("set +o emacs --").unwrap();
+        assert!(!shell.options.emacs && !shell.options.vi);
+        assert!(shell.positional.is_empty());
+        shell.run_str("set -o nothing 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_line_editor_keys() {
+        let mut editor = LineEditor::new("\n$ ");
+        editor.insert("echo one two");
+        editor.kill_word();
+        assert_eq!((editor.line.as_str(), editor.cursor), ("echo one ", 9));
+        editor.cursor = 5;
+        editor.kill_to_end();
+        assert_eq!(editor.line, "echo ");
+        editor.insert("héllo");
+        editor.left();
+        assert_eq!(editor.redraw(), "\r$ echo héllo\x1b[K\x1b[1D");
+        editor.kill_to_start();
+        assert_eq!((editor.line.as_str(), editor.cursor), ("o", 0));
+
+        // a line wrapping in a terminal ten columns wide
+        let mut editor = LineEditor::new("$ ");
+        editor.columns = 10;
+        editor.insert("abcdefghijkl");
+        assert_eq!(editor.redraw(), "\r$ abcdefghijkl\x1b[J\r\x1b[4C");
+        editor.cursor = 0;
+        assert_eq!(
+            editor.redraw(),
+            "\x1b[1A\r$ abcdefghijkl\x1b[J\x1b[1A\r\x1b[2C"
+        );
+        editor.line.truncate(8);
+        editor.cursor = 8;
+        assert_eq!(editor.redraw(), "\r$ abcdefgh\r\n\x1b[J\r");
+        assert_eq!(editor.cursor_row, 1);
+
+        // vi command mode
+        let mut editor = LineEditor::new("$ ");
+        editor.insert("echo abc def");
+        editor.vi_command = true;
+        editor.clamp();
+        for key in "bbx".chars() {
+            editor.vi_key(key);
+        }
+        assert_eq!((editor.line.as_str(), editor.cursor), ("echo bc def", 5));
+        editor.vi_key('w');
+        assert_eq!(editor.cursor, 8);
+        editor.vi_key('$');
+        assert_eq!(editor.cursor, 10);
+        editor.vi_key('a');
+        assert!(!editor.vi_command);
+        assert_eq!(editor.cursor, 11);
+        editor.vi_command = true;
+        editor.vi_key('d');
+        assert_eq!(editor.line, "echo bc def");
+        editor.vi_key('d');
+        assert_eq!((editor.line.as_str(), editor.cursor), ("", 0));
+    }
+
+    #[test]
+    fn test_last_status_after_every_command() {
+        let mut shell = Shell::new(false).unwrap();
+        let cases = [
+            ("true", 0),
+            ("false", 1),
+            ("trash-no-such-command 2> /dev/null", 127),
+            ("/ 2> /dev/null", 126),
+            ("sh -c 'kill -TERM $$'", 143),
+            ("cd /trash-no-such-dir 2> /dev/null", 1),
+            ("false; X=1", 0),
+            ("false; > /dev/null", 0),
+            ("false; echo > /trash-no-such-dir/file 2> /dev/null", 1),
+            ("false; f() { false; }", 0),
+            ("f", 1),
+            ("! true", 1),
+            ("false | X=1", 0),
+            ("true | false", 1),
+            ("false; if false; then true; fi", 0),
+            ("false; true &", 0),
+            ("echo hi >&- 2> /dev/null", 1),
+            ("read X <&- 2> /dev/null", 1),
+            ("[[ a == b ]]", 1),
+            ("false; ;; 2> /dev/null", 2),
+        ];
+        for (command, status) in cases {
+            shell.run_str(command).unwrap();
+            assert_eq!(shell.last_status, status, "{}", command);
+        }
+        shell.run_str("wait").unwrap();
+    }
+
+    #[test]
+    fn test_tilde_expansion_follows_home() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("HOME=/first").unwrap();
+        assert_eq!(
+            run_output(&mut shell, "echo ~ ~/a \"~\" \\~ ~\"x\" a~"),
+            "/first /first/a ~ ~ ~x a~\n"
+        );
+        shell.run_str("HOME=/second; X=~/bin").unwrap();
+        assert_eq!(shell.get_var("X"), Some("/second/bin"));
+        assert_eq!(run_output(&mut shell, "echo ~root/x"), "/root/x\n");
+        // without $HOME the password database is asked, once
+        shell.variables.remove("HOME");
+        let home = User::from_uid(getuid()).unwrap().unwrap().dir;
+        assert_eq!(shell.home_of(""), Some(home.to_string_lossy().into_owned()));
+        assert!(shell.passwd_home.get().is_some());
+        shell.run_str("HOME=/third").unwrap();
+        assert_eq!(shell.home_of(""), Some("/third".to_string()));
+    }
+
+    #[test]
+    fn test_brace_expansion() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "echo a{b,c}d {a,{b,c}}x x{,y} {1..3} {3..1} {08..10}"
+            ),
+            "abd acd ax bx cx x xy 1 2 3 3 2 1 08 09 10\n"
+        );
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "echo {a..e..2} {x} {a,b \\{a,b} '{a,b}' {1..2}{a,b}"
+            ),
+            "a c e {x} {a,b {a,b} {a,b} 1a 1b 2a 2b\n"
+        );
+        shell.run_str("X=1").unwrap();
+        assert_eq!(run_output(&mut shell, "echo {$X,\"$X\"}"), "1 1\n");
+    }
+
+    #[test]
+    fn test_posix_mode_turns_off_extensions() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("[[ a == a ]]").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("set -o posix").unwrap();
+        assert!(shell.options.posix);
+        assert_eq!(
+            run_output(&mut shell, "echo a{b,c} {1..2}"),
+            "a{b,c} {1..2}\n"
+        );
+        shell.run_str("[[ a == a ]] 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 127);
+        shell.run_str("set +o posix").unwrap();
+        assert_eq!(run_output(&mut shell, "echo a{b,c}"), "ab ac\n");
+    }
+
+    #[test]
+    fn test_transformation_operators() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str(r#"v="it's a \"b c\" \$x""#).unwrap();
+        shell.run_str("quoted=${v@Q}").unwrap();
+        let quoted = shell.get_var("quoted").unwrap().to_string();
+        assert_eq!(quoted, r#"'it'\''s a "b c" $x'"#);
+        shell.run_str(&format!("again={}", quoted)).unwrap();
+        assert_eq!(shell.get_var("again"), shell.get_var("v"));
+        shell
+            .run_str("w=hello; upper=${w@U}; lower=${upper@L} first=${w@u} none=${unset@Q}")
+            .unwrap();
+        assert_eq!(shell.get_var("upper"), Some("HELLO"));
+        assert_eq!(shell.get_var("lower"), Some("hello"));
+        assert_eq!(shell.get_var("first"), Some("Hello"));
+        assert_eq!(shell.get_var("none"), Some(""));
+    }
+
+    #[test]
+    fn test_noexec_only_parses() {
+        let path = temp_path("noexec");
+        let mut shell = Shell::new(false).unwrap();
+        // every way of running trash -n sets the option up through the invocation
+        let args = ["trash", "-n", "--norc"].map(String::from);
+        let invocation = Invocation::parse(args, false).ok().unwrap();
+        invocation.start(&mut shell, None);
+        assert!(shell.options.noexec);
+        shell
+            .run_str(&format!("echo ran > {}", path.display()))
+            .unwrap();
+        assert!(!path.exists());
+        shell
+            .run_str(&format!("set -n; echo ran > {}", path.display()))
+            .unwrap();
+        assert!(!path.exists());
+        let script = format!("echo ran > {}\nfalse\n", path.display());
+        shell.run_script(&script).unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert!(!path.exists());
+        // a syntax error anywhere leaves a failing status
+        shell.run_script("if true; then\nfi )\ntrue\n").unwrap();
+        assert_eq!(shell.last_status, 2);
+        assert!(shell.options.noexec);
+    }
+
+    #[test]
+    fn test_default_and_substring_expansion() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("v=abcdef; empty=").unwrap();
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "echo ${v:-3} ${unset:-3} ${empty:-$v} \"${v: -3}\" ${v:1:-1} ${v:2:2}"
+            ),
+            "abcdef 3 abcdef def bcde cd\n"
+        );
+        assert_eq!(
+            run_output(&mut shell, "echo [${v:9}] [${v: -9}] [${v:4:-3}]"),
+            "[] [] []\n"
+        );
+        // the word runs to the matching brace and is expanded in turn
+        shell.run_str("n=2").unwrap();
+        assert_eq!(
+            run_output(
+                &mut shell,
+                "echo ${unset:-${v}} \"${unset:-<${empty:-${v:1:$n}}>}\" ${v:${n}:1}"
+            ),
+            "abcdef <bc> c\n"
+        );
+    }
+
+    #[test]
+    fn test_indirect_expansion() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("a=b; b=hi; me=me; set -- x y").unwrap();
+        assert_eq!(
+            run_output(&mut shell, "echo ${a} ${!a} ${b}x \"${!me}\" ${!#} ${00}"),
+            "b hi hix me y trash\n"
+        );
+        shell.run_str("pre_one=1; pre_two=2").unwrap();
+        assert_eq!(
+            run_output(&mut shell, "echo ${!pre*} \"${!pre_t@}\" '${!a}'"),
+            "pre_one pre_two pre_two ${!a}\n"
+        );
+        // a value that isn't a name expands to nothing
+        shell.run_str("bad='x y'").unwrap();
+        assert_eq!(run_output(&mut shell, "echo [${!bad}]"), "[]\n");
+    }
+
+    #[test]
+    fn test_history_ignores_space_and_duplicates() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("HISTCONTROL=ignorespace").unwrap();
+        for line in ["ls\n", " secret\n", "ls\n"] {
+            shell.add_history(line);
+        }
+        assert_eq!(shell.history, ["ls", "ls"]);
+        shell.run_str("set -o histignoredups").unwrap();
+        shell.add_history("ls\n");
+        shell.add_history("pwd\n");
+        assert_eq!(shell.history, ["ls", "ls", "pwd"]);
+        shell
+            .run_str("set +o histignoredups; HISTCONTROL=ignoreboth")
+            .unwrap();
+        shell.add_history("pwd\n");
+        shell.add_history(" pwd\n");
+        assert_eq!(shell.history, ["ls", "ls", "pwd"]);
+    }
+
+    #[test]
+    fn test_timing_option() {
+        let path = temp_path("timing");
+        let mut shell = Shell::new(false).unwrap();
+        let file = std::fs::File::create(&path).unwrap();
+        shell.fds = shell.fds.layer(vec![Redirection::File(2, file.into())]);
+        shell.run_str("set -o timing").unwrap();
+        shell.run_str("{ true; sleep 0.1 | cat; }").unwrap();
+        shell.options.timing = false;
+        let output = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<(f64, &str)> = output
+            .lines()
+            .map(|line| {
+                let (seconds, command) = line.split_once("s\t").unwrap();
+                assert_eq!(seconds.split_once('.').unwrap().1.len(), 3);
+                (seconds.parse().unwrap(), command)
+            })
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].1, "true");
+        assert_eq!(lines[1].1, "sleep 0.1 | cat");
+        assert!(lines[1].0 >= 0.1);
+        assert!(lines[2].1.starts_with("{ true; sleep 0.1 | cat; }"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_time_reports_to_stderr() {
+        let output = temp_path("time-output");
+        let errors = temp_path("time-errors");
+        let mut shell = Shell::new(false).unwrap();
+        let script = format!(
+            "{{ time -p echo hi | cat; time sleep 0.1; }} > {} 2> {}",
+            output.display(),
+            errors.display()
+        );
+        shell.run_str(&script).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hi\n");
+        let report = std::fs::read_to_string(&errors).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 7);
+        for (line, name) in lines[..3].iter().zip(["real", "user", "sys"]) {
+            let (label, seconds) = line.split_once(' ').unwrap();
+            assert_eq!(label, name);
+            assert_eq!(seconds.split_once('.').unwrap().1.len(), 2);
+        }
+        assert_eq!(lines[3], "");
+        let real = lines[4].strip_prefix("real\t0m").unwrap();
+        assert!(real.strip_suffix('s').unwrap().parse::<f64>().unwrap() >= 0.1);
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&errors).unwrap();
+    }
+
+    #[test]
+    fn test_cd_with_empty_home() {
+        let path = temp_path("cd-empty-home");
+        let status = run_forked(|shell| {
+            let script = format!("HOME=\"\"; cd; pwd > {}", path.display());
+            shell.run_str(&script).unwrap();
+            exit(shell.last_status);
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        let home = User::from_uid(getuid()).unwrap().unwrap().dir;
+        let pwd = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            pwd.trim_end(),
+            home.canonicalize().unwrap().to_str().unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cd_keeps_pwd_absolute() {
+        let dir = temp_path("cd-pwd");
+        let output = temp_path("cd-pwd-output");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("deep/er/est")).unwrap();
+        std::os::unix::fs::symlink("deep/er/est", dir.join("link")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let status = run_forked(|shell| {
+            let script = format!(
+                "{{ cd {0}/deep/er/est; cd ../..; echo $PWD; cd ./er//est/; cd ..; echo $PWD; \
+                 cd -; cd {0}/link; echo $PWD; cd ..; echo $PWD; \
+                 cd -P link; echo $PWD; cd -L ..; echo $PWD; }} > {1}",
+                dir.display(),
+                output.display()
+            );
+            shell.run_str(&script).unwrap();
+            exit(shell.last_status);
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        let d = dir.display();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            format!(
+                "{d}/deep\n{d}/deep/er\n{d}/deep/er/est\n{d}/link\n{d}\n{d}/deep/er/est\n\
+                 {d}/deep/er\n"
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
 
-struct ExternalCommand {
-    // TODO: look into OsString for POSIX compatibility
-    cmd: String,
-    args: Vec<String>,
-    // redirects: Vec<Redirect>,
-    // background: bool,
-}
+    #[test]
+    fn test_cd_reports_permission_denied() {
+        let locked = temp_path("cd-locked");
+        let errors = temp_path("cd-errors");
+        let _ = std::fs::remove_dir(&locked);
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::set_permissions(&locked, std::os::unix::fs::PermissionsExt::from_mode(0o600))
+            .unwrap();
+        std::fs::write(&errors, "").unwrap();
+        std::fs::set_permissions(&errors, std::os::unix::fs::PermissionsExt::from_mode(0o666))
+            .unwrap();
+        let status = run_forked(|shell| {
+            // root may enter any directory
+            if getuid().is_root() {
+                nix::unistd::setuid(nix::unistd::Uid::from_raw(65534)).unwrap();
+            }
+            let script = format!(
+                "{{ cd {0}; echo $?; cd {0}-missing; echo $?; }} >> {1} 2>&1",
+                locked.display(),
+                errors.display()
+            );
+            shell.run_str(&script).unwrap();
+            exit(0);
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        assert_eq!(
+            std::fs::read_to_string(&errors).unwrap(),
+            format!(
+                "trash: cd: {0}: Permission denied\n1\n\
+                 trash: cd: {0}-missing: No such file or directory\n1\n",
+                locked.display()
+            )
+        );
+        std::fs::remove_dir(&locked).unwrap();
+        std::fs::remove_file(&errors).unwrap();
+    }
 
-impl ExternalCommand {
-    fn new(cmd: String, args: Vec<String>) -> Self {
-        Self { cmd, args }
+    #[test]
+    fn test_prompt_job_count() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(shell.prompt(), "\n$ ");
+        shell.run_str("PS1='[\\j jobs]\\n\\\\ $ '").unwrap();
+        assert_eq!(shell.prompt(), "[0 jobs]\n\\ $ ");
+        // the pids are never waited for, only the table is looked at
+        let stopped = JobState::Stopped(Signal::SIGTSTP);
+        for state in [JobState::Running, stopped, JobState::Done(0)] {
+            shell.add_job(&[Pid::from_raw(i32::MAX)], "sleep 1".to_string());
+            shell.jobs.last_mut().unwrap().state = state;
+        }
+        assert_eq!(shell.prompt(), "[2 jobs]\n\\ $ ");
     }
 
-    fn cmd_as_cstring(&self) -> CString {
-        CString::new(self.cmd.as_str()).unwrap()
+    #[test]
+    fn test_function_sees_status_before_call() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("f() { echo $?; }").unwrap();
+        assert_eq!(run_output(&mut shell, "false; f"), "1\n");
+        assert_eq!(run_output(&mut shell, "true; f"), "0\n");
     }
 
-    fn args_as_cstring(&self) -> Vec<CString> {
-        self.args
-            .iter()
-            .map(|arg| CString::new(arg.as_str()).unwrap())
-            .collect()
+    #[test]
+    fn test_function_status_after_call() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("f() { false; }; f").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell
+            .run_str("g() { return 3; echo unreachable; }")
+            .unwrap();
+        assert_eq!(run_output(&mut shell, "g"), "");
+        assert_eq!(shell.last_status, 3);
+        // a bare return keeps the status of the last command
+        shell.run_str("h() { false; return; }; true; h").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("k() { true; return; }; false; k").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell
+            .run_str("l() { while true; do if true; then return 4; fi; done; }; l; S=$?")
+            .unwrap();
+        assert_eq!(shell.get_var("S"), Some("4"));
     }
-}
 
-fn main() {
-    let mut shell = Shell::new().expect("Failed to spawn shell");
-    shell.run().expect("Failed to run shell");
-}
+    #[test]
+    fn test_function_restores_positional_parameters() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("set -- a b; f() { echo $# $1; }").unwrap();
+        assert_eq!(run_output(&mut shell, "f x"), "1 x\n");
+        assert_eq!(run_output(&mut shell, "echo $# $1"), "2 a\n");
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_local_variables() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("X=global; f() { local X=local Y=new; echo $X $Y; }")
+            .unwrap();
+        assert_eq!(run_output(&mut shell, "f"), "local new\n");
+        assert_eq!(shell.get_var("X"), Some("global"));
+        assert_eq!(shell.get_var("Y"), None);
+        shell.run_str("local X=1 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("return 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
 
     #[test]
-    fn test_simple_words() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo hello world");
+    fn test_caller_reports_call_sites() {
+        let path = temp_path("caller.sh");
+        let script = "f() {\n\
+                        caller\n\
+                        g\n\
+                      }\n\
+                      g() {\n\
+                        caller 0; caller 1\n\
+                        caller 2 || echo none\n\
+                      }\n\
+                      \n\
+                      echo $LINENO\n\
+                      f\n";
+        std::fs::write(&path, script).unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        let file = path.display();
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hello".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("world".into(), Quoting::Unquoted),
-            ]
+            run_output(&mut shell, &format!("source {}", file)),
+            format!("10\n11 {0}\n3 f {0}\n11 main {0}\nnone\n", file)
         );
+        shell.run_str("caller").unwrap();
+        assert_eq!(shell.last_status, 1);
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn test_simple_single_quotes() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo 'hello world'");
+    fn test_lineno_in_function_bodies() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("f() {\n  echo $LINENO\n}").unwrap();
+        assert_eq!(run_output(&mut shell, "f"), "2\n");
+        // here-doc bodies count as lines too, a function keeps the lines it was defined on
+        let path = temp_path("lineno.sh");
+        let script = "g() {\n\
+                        cat <<EOF\n\
+                      body\n\
+                      EOF\n\
+                        echo $LINENO\n\
+                        h\n\
+                      }\n\
+                      h() { echo $LINENO; }\n\
+                      g\n\
+                      echo $LINENO\n";
+        std::fs::write(&path, script).unwrap();
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hello world".into(), Quoting::SingleQuoted),
-            ]
+            run_output(&mut shell, &format!("source {}", path.display())),
+            "body\n5\n8\n10\n"
         );
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn test_simple_double_quotes() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo \"hello world\"");
-        assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hello world".into(), Quoting::DoubleQuoted),
-            ]
-        );
+    fn test_local_lists_innermost_scope() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("inner() { local B=2 A='one two'; local; }; outer() { local C=3; inner; }")
+            .unwrap();
+        assert_eq!(run_output(&mut shell, "outer"), "A=one two\nB=2\n");
+        shell.run_str("local 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
     }
 
     #[test]
-    fn test_single_and_double_quotes_with_space() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo 'hello' \"world\"");
+    fn test_groups_subshells_and_negation() {
+        let mut shell = Shell::new(false).unwrap();
+        assert_eq!(run_output(&mut shell, "{ echo a; echo b; }"), "a\nb\n");
+        shell.run_str("(X=inner; false)").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.get_var("X"), None);
+        shell.run_str("! false").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("! true | true").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("function f { return 5; }; f").unwrap();
+        assert_eq!(shell.last_status, 5);
+        shell.run_str("f() echo").unwrap();
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_globstar_matches_at_any_depth() {
+        let dir = temp_path("globstar");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src/parser/lexer")).unwrap();
+        for name in [
+            "main.rs",
+            "src/lib.rs",
+            "src/parser/lexer/token.rs",
+            "src/notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let mut shell = Shell::new(false).unwrap();
+        let d = dir.display();
+        // without the option ** is just *
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hello".into(), Quoting::SingleQuoted),
-                Token::Whitespace,
-                Token::Word("world".into(), Quoting::DoubleQuoted),
-            ]
+            run_output(&mut shell, &format!("echo {}/**/*.rs", d)),
+            format!("{d}/src/lib.rs\n")
+        );
+        assert_eq!(
+            run_output(&mut shell, "shopt globstar"),
+            "globstar       \toff\n"
+        );
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("shopt -s globstar").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_output(&mut shell, "shopt globstar"),
+            "globstar       \ton\n"
+        );
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_output(&mut shell, &format!("echo {}/**/*.rs", d)),
+            format!("{d}/main.rs {d}/src/lib.rs {d}/src/parser/lexer/token.rs\n")
+        );
+        assert_eq!(
+            run_output(&mut shell, &format!("echo {}/*", d)),
+            format!("{d}/main.rs {d}/src\n")
+        );
+        assert_eq!(
+            run_output(&mut shell, &format!("echo {}/src/**", d)),
+            format!(
+                "{d}/src/lib.rs {d}/src/notes.txt {d}/src/parser {d}/src/parser/lexer \
+                 {d}/src/parser/lexer/token.rs\n"
+            )
         );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_single_and_double_quotes_without_space() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo 'hello'\"world\"");
+    fn test_glob_sorted_byte_order() {
+        let dir = temp_path("glob-order");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["b.txt", "a.txt", "C.txt", "_x.txt", "a.log", ".hidden.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let mut shell = Shell::new(false).unwrap();
+        let d = dir.display();
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hello".into(), Quoting::SingleQuoted),
-                Token::Word("world".into(), Quoting::DoubleQuoted),
-            ]
+            run_output(&mut shell, &format!("echo {}/*.txt", d)),
+            format!("{d}/C.txt {d}/_x.txt {d}/a.txt {d}/b.txt\n")
         );
+        assert_eq!(
+            run_output(&mut shell, &format!("echo {}/a.*", d)),
+            format!("{d}/a.log {d}/a.txt\n")
+        );
+        assert_eq!(
+            run_output(&mut shell, &format!("echo {}/.h*", d)),
+            format!("{d}/.hidden.txt\n")
+        );
+        // quoted parts are literal and a pattern without matches is left alone
+        assert_eq!(
+            run_output(&mut shell, &format!("echo '{}/*'.txt {}/*.none", d, d)),
+            format!("{d}/*.txt {d}/*.none\n")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_single_and_double_inside_eachother() {
+    fn test_escaped_metacharacters_stay_quoted() {
         let parser = Parser::new();
-        let tokens = parser.tokenize("echo 'he\"llo' \"w'orld\"");
         assert_eq!(
-            tokens,
+            parser.tokenize("echo a\\*b \"x\\$y\""),
             vec![
                 Token::Word("echo".into(), Quoting::Unquoted),
                 Token::Whitespace,
-                Token::Word("he\"llo".into(), Quoting::SingleQuoted),
+                Token::Word("a".into(), Quoting::Unquoted),
+                Token::Word("*".into(), Quoting::SingleQuoted),
+                Token::Word("b".into(), Quoting::Unquoted),
                 Token::Whitespace,
-                Token::Word("w'orld".into(), Quoting::DoubleQuoted),
+                Token::Word("x".into(), Quoting::DoubleQuoted),
+                Token::Word("$".into(), Quoting::SingleQuoted),
+                Token::Word("y".into(), Quoting::DoubleQuoted),
             ]
         );
     }
 
     #[test]
-    fn test_simple_semicolon() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo hi;");
+    fn test_escaped_glob_is_literal() {
+        let dir = temp_path("glob-escaped");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        let d = dir.display();
+        assert_eq!(run_output(&mut shell, "echo \\* '*' \\?"), "* * ?\n");
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hi".into(), Quoting::Unquoted),
-                Token::Operator(Operator::Semicolon),
-            ]
+            run_output(&mut shell, &format!("echo {d}/\\* {d}/f\\[i]le {d}/*")),
+            format!("{d}/* {d}/f[i]le {d}/file\n")
+        );
+        // a literal backslash in front of a pattern doesn't escape it
+        std::fs::write(format!("{}/\\x", d), "").unwrap();
+        assert_eq!(
+            run_output(&mut shell, &format!("echo {d}/\\\\*")),
+            format!("{d}/\\x\n")
         );
+        shell.run_str("X=value").unwrap();
+        assert_eq!(run_output(&mut shell, "echo \\$X \"\\$X\""), "$X $X\n");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_simple_and() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("hello && world");
+    fn test_help_lists_reserved_words_and_operators() {
+        let mut shell = Shell::new(false).unwrap();
+        let output = run_output(&mut shell, "help");
+        for (name, _) in RESERVED_WORDS {
+            assert!(output.contains(&format!("\n  {:<10}", name)), "{}", name);
+        }
+        for operator in Operator::ALL {
+            assert!(output.contains(&format!("\n  {:<10}", operator.as_str())));
+        }
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("hello".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Operator(Operator::And),
-                Token::Whitespace,
-                Token::Word("world".into(), Quoting::Unquoted),
-            ]
+            run_output(&mut shell, "help while '&&'"),
+            "reserved words:\n  while     repeat the body as long as the condition list succeeds\n\
+             operators:\n  &&        run the next pipeline only if the previous one succeeded\n"
         );
+        shell.run_str("help nothing 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
     }
 
     #[test]
-    fn test_simple_andpercent() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("ls &");
+    fn test_trap_list_and_reset() {
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("trap 'echo it'\\''s' usr1; trap 'echo bye' 0")
+            .unwrap();
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("ls".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Operator(Operator::Andpercent),
-            ]
+            run_output(&mut shell, "trap"),
+            "trap -- 'echo bye' EXIT\ntrap -- 'echo it'\\''s' USR1\n"
+        );
+        assert_eq!(
+            run_output(&mut shell, "trap -p USR1 SIGINT"),
+            "trap -- 'echo it'\\''s' USR1\n"
         );
+        // the listing sets the same traps again when sourced
+        let path = temp_path("trap-listing");
+        shell
+            .run_str(&format!("trap -p > {}", path.display()))
+            .unwrap();
+        shell.run_str("trap - USR1 EXIT").unwrap();
+        shell
+            .run_str(&format!("source {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.traps["USR1"], "echo it's");
+        assert_eq!(shell.traps["EXIT"], "echo bye");
+        std::fs::remove_file(&path).unwrap();
+        shell.run_str("trap - SIGUSR1").unwrap();
+        shell.run_str("trap EXIT").unwrap();
+        assert_eq!(run_output(&mut shell, "trap"), "");
+        shell.run_str("trap true BOGUS 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
     }
 
     #[test]
-    fn test_simple_pipe() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo 'hi' | cat");
+    fn test_read_splits_fields() {
+        let path = temp_path("read-fields");
+        std::fs::write(&path, "one  two three  \na\\ b c\\\nd\n").unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!("{{ read A B; read X Y; }} < {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("A"), Some("one"));
+        assert_eq!(shell.get_var("B"), Some("two three"));
+        assert_eq!(shell.get_var("X"), Some("a b"));
+        assert_eq!(shell.get_var("Y"), Some("cd"));
+        shell
+            .run_str(&format!("read -r X Y < {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.get_var("X"), Some("one"));
+        shell.run_str("read < /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.get_var("REPLY"), Some(""));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_character_count() {
+        let path = temp_path("read-count");
+        std::fs::write(&path, "héllo\nworld").unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!("read -n 2 A < {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.get_var("A"), Some("hé"));
+        shell
+            .run_str(&format!("read -n 10 A < {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.get_var("A"), Some("héllo"));
+        // -N reads through the newline, running out of input first is a failure
+        shell
+            .run_str(&format!("read -N 8 A < {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(shell.get_var("A"), Some("héllo\nwo"));
+        shell
+            .run_str(&format!("read -rN20 A < {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.get_var("A"), Some("héllo\nworld"));
+        shell.run_str("read -n many A 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_timeout_leaves_variable() {
+        // the write end stays open so no input and no end of file ever arrives
+        let (reader, _writer) = nix::unistd::pipe().unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!(
+                "FOO=keep; read -t 0.05 FOO <&{}",
+                reader.as_raw_fd()
+            ))
+            .unwrap();
+        assert_eq!(shell.last_status, 142);
+        assert_eq!(shell.get_var("FOO"), Some("keep"));
+        shell
+            .run_str(&format!("read -t0 FOO <&{}", reader.as_raw_fd()))
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell.run_str("read -t soon FOO 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+    }
+
+    // runs the shell in a forked child so exiting and signals don't end the test process
+    fn run_forked(script: impl FnOnce(&mut Shell)) -> WaitStatus {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let mut shell = Shell::new(false).unwrap();
+                script(&mut shell);
+                exit(100);
+            }
+            ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_command_skips_functions_and_uses_default_path() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("true() { return 3; }; true").unwrap();
+        assert_eq!(shell.last_status, 3);
+        shell.run_str("command true").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("PATH=").unwrap();
+        shell.run_str("command ls / > /dev/null 2>&1").unwrap();
+        assert_eq!(shell.last_status, 127);
+        assert_eq!(run_output(&mut shell, "command -p ls -d /"), "/\n");
+        assert_eq!(shell.last_status, 0);
+        shell
+            .run_str("command -p no-such-command 2> /dev/null")
+            .unwrap();
+        assert_eq!(shell.last_status, 127);
+    }
+
+    #[test]
+    fn test_aliases_in_sourced_script() {
+        let path = temp_path("aliases.sh");
+        std::fs::write(&path, "greet world\n").unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str("greet() { GREETED=\"$*\"; }; alias greet='greet hello '")
+            .unwrap();
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hi".into(), Quoting::SingleQuoted),
-                Token::Whitespace,
-                Token::Operator(Operator::Pipe),
-                Token::Whitespace,
-                Token::Word("cat".into(), Quoting::Unquoted),
-            ]
+            run_output(&mut shell, "alias"),
+            "alias greet='greet hello '\n"
         );
+        // a script only expands aliases once asked to
+        shell
+            .run_str(&format!("source {}", path.display()))
+            .unwrap();
+        assert_eq!(shell.get_var("GREETED"), Some("world"));
+        shell
+            .run_str(&format!(
+                "set -o expand_aliases; alias world=there; source {}",
+                path.display()
+            ))
+            .unwrap();
+        assert_eq!(shell.get_var("GREETED"), Some("hello there"));
+        shell.run_str("unalias greet world").unwrap();
+        assert_eq!(shell.last_status, 0);
+        assert!(shell.aliases.is_empty());
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn test_simple_or() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("no || yes");
+    fn test_hash_follows_moved_executable() {
+        let dirs = [temp_path("hash-first"), temp_path("hash-second")];
+        for dir in &dirs {
+            let _ = std::fs::remove_dir_all(dir);
+            std::fs::create_dir(dir).unwrap();
+        }
+        // a link rather than a fresh file, which a child forked meanwhile could hold open
+        let program = dirs[0].join("hashed-program");
+        std::os::unix::fs::symlink("/bin/false", &program).unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell
+            .run_str(&format!("PATH={}:{}", dirs[0].display(), dirs[1].display()))
+            .unwrap();
+        shell.run_str("hashed-program").unwrap();
+        assert_eq!(shell.last_status, 1);
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("no".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Operator(Operator::Or),
-                Token::Whitespace,
-                Token::Word("yes".into(), Quoting::Unquoted),
-            ]
+            run_output(&mut shell, "hash -l"),
+            format!("builtin hash -p {} hashed-program\n", program.display())
         );
+        // moved to the next directory in $PATH, the remembered path is gone
+        let moved = dirs[1].join("hashed-program");
+        std::fs::rename(&program, &moved).unwrap();
+        shell.run_str("hashed-program").unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(shell.hashed["hashed-program"].0, moved);
+        // and found again by a pipeline stage, which can't update the table
+        std::fs::rename(&moved, &program).unwrap();
+        shell.run_str("hashed-program | hashed-program").unwrap();
+        assert_eq!(shell.get_var("PIPESTATUS"), Some("1 1"));
+        shell.run_str("hash -r").unwrap();
+        assert!(shell.hashed.is_empty());
+        for dir in &dirs {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
     }
 
     #[test]
-    fn test_multiple_whitespace() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo  hi");
+    fn test_enable_disables_builtins() {
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str("enable -n [[ read").unwrap();
+        assert_eq!(shell.last_status, 0);
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("hi".into(), Quoting::Unquoted),
-            ]
+            run_output(&mut shell, "enable -n"),
+            "enable -n [[\nenable -n read\n"
         );
+        let enabled = run_output(&mut shell, "enable");
+        assert!(enabled.lines().any(|line| line == "enable cd"));
+        assert!(!enabled.lines().any(|line| line == "enable read"));
+        // the names are now looked up as programs, of which there's no [[
+        shell.run_str("[[ a == a ]] 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 127);
+        shell.run_str("enable [[; [[ a == a ]]").unwrap();
+        assert_eq!(shell.last_status, 0);
+        shell.run_str("enable -n exit 2> /dev/null").unwrap();
+        assert_eq!(shell.last_status, 1);
+        shell
+            .run_str("enable -n no-such-builtin 2> /dev/null")
+            .unwrap();
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run_output(&mut shell, "enable -n"), "enable -n read\n");
     }
 
     #[test]
-    fn test_escaping_double_quotes() {
-        let parser = Parser::new();
-        let tokens = parser.tokenize("echo \\\"hi\\\"");
+    fn test_exec_passes_only_intended_fds() {
+        let path = temp_path("exec-fds");
+        let status = run_forked(|shell| {
+            // a descriptor the program must not inherit
+            unsafe { libc::dup(2) };
+            shell.run_str("exec 4< /dev/null 6> /dev/null").unwrap();
+            shell.run_str("exec 6>&-").unwrap();
+            let exec = format!("exec ls /proc/self/fd 7>&4 > {}", path.display());
+            shell.run_str(&exec).unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        // 3 is the directory ls itself lists
+        let fds = std::fs::read_to_string(&path).unwrap();
         assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("\"hi\"".into(), Quoting::Unquoted),
-            ]
+            fds.lines().collect::<Vec<_>>(),
+            ["0", "1", "2", "3", "4", "7"]
         );
+        std::fs::remove_file(&path).unwrap();
+
+        let status = run_forked(|shell| {
+            shell.run_str("exec no-such-command 2> /dev/null").unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 127)));
     }
 
     #[test]
-    fn test_escaping_backslash() {
+    fn test_startup_files() {
+        let home = temp_path("startup-home");
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir(&home).unwrap();
+        std::fs::write(home.join(".trash_profile"), "read=profile\n").unwrap();
+        std::fs::write(home.join(".trashrc"), "read=rc\n").unwrap();
+        std::fs::write(home.join("other"), "read=other\n").unwrap();
+        let mut shell = Shell::new(false).unwrap();
+        shell.run_str(&format!("HOME={}", home.display())).unwrap();
+        // a non-interactive shell reads nothing unless it's a login shell
+        shell.read_startup_files(None);
+        assert_eq!(shell.get_var("read"), None);
+        shell.interactive = true;
+        shell.read_startup_files(None);
+        assert_eq!(shell.get_var("read"), Some("rc"));
+        let other = home.join("other");
+        shell.read_startup_files(other.to_str());
+        assert_eq!(shell.get_var("read"), Some("other"));
+        shell.interactive = false;
+        shell.login = true;
+        shell.read_startup_files(None);
+        assert_eq!(shell.get_var("read"), Some("profile"));
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_login_shell_runs_logout_file_after_exit_trap() {
+        let home = temp_path("logout-home");
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir(&home).unwrap();
+        let log = home.join("log");
+        let logout = format!("echo logout >> {}\n", log.display());
+        std::fs::write(home.join(".trash_logout"), logout).unwrap();
+        let status = run_forked(|shell| {
+            shell.login = true;
+            shell.run_str(&format!("HOME={}", home.display())).unwrap();
+            let trap = format!("trap 'echo trap >> {}' EXIT", log.display());
+            shell.run_str(&trap).unwrap();
+            shell.run_str("logout 4").unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 4)));
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "trap\nlogout\n");
+
+        // only a login shell can log out, and reads the file
+        std::fs::remove_file(&log).unwrap();
+        let status = run_forked(|shell| {
+            shell.run_str(&format!("HOME={}", home.display())).unwrap();
+            shell.run_str("logout 2> /dev/null").unwrap();
+            assert_eq!(shell.last_status, 1);
+            shell.run_str("exit 5").unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 5)));
+        assert!(!log.exists());
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_exit_trap_runs_on_exit() {
+        let path = temp_path("exit-trap");
+        let status = run_forked(|shell| {
+            let trap = format!("trap 'echo bye > {}' EXIT", path.display());
+            shell.run_str(&trap).unwrap();
+            shell.run_str("exit 259").unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 3)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "bye\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit_writes_all_output_to_a_pipe() {
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let status = run_forked(|shell| {
+            duplicate_fd(write_end.as_raw_fd(), 1).unwrap();
+            shell.run_str("echo hi; printf tail; exit 4").unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 4)));
+        drop(write_end);
+        let mut output = String::new();
+        std::fs::File::from(read_end)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "hi\ntail");
+    }
+
+    #[test]
+    fn test_commands_from_stdin_with_arguments() {
+        let (input, script) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        std::fs::File::from(script)
+            .write_all(b"echo \"$1 and $2\"\nf() {\n  echo $#\n}\nf $2 $1\n")
+            .unwrap();
+        let status = run_forked(|shell| {
+            duplicate_fd(input.as_raw_fd(), 0).unwrap();
+            duplicate_fd(write_end.as_raw_fd(), 1).unwrap();
+            shell.positional = vec!["foo".to_string(), "bar".to_string()];
+            shell.run().unwrap();
+        });
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        drop((input, write_end));
+        let mut output = String::new();
+        std::fs::File::from(read_end)
+            .read_to_string(&mut output)
+            .unwrap();
+        // no prompts or goodbyes in between
+        assert_eq!(output, "foo and bar\n2\n");
+    }
+
+    #[test]
+    fn test_exit_and_return_wrap_to_eight_bits() {
+        for (code, expected) in [("256", 0), ("257", 1), ("-1", 255)] {
+            let status = run_forked(|shell| {
+                shell.run_str(&format!("exit {}", code)).unwrap();
+            });
+            assert!(matches!(status, WaitStatus::Exited(_, status) if status == expected));
+            let mut shell = Shell::new(false).unwrap();
+            shell
+                .run_str(&format!("f() {{ return {}; }}; f", code))
+                .unwrap();
+            assert_eq!(shell.last_status, expected);
+        }
+    }
+
+    #[test]
+    fn test_exit_trap_runs_on_terminating_signal() {
+        let path = temp_path("exit-trap-signal");
+        let status = run_forked(|shell| {
+            shell.install_signal_handlers().unwrap();
+            let trap = format!("trap 'echo bye > {}' EXIT", path.display());
+            shell.run_str(&trap).unwrap();
+            raise(Signal::SIGTERM).unwrap();
+            shell.run_str("true").unwrap();
+        });
+        assert!(matches!(
+            status,
+            WaitStatus::Signaled(_, Signal::SIGTERM, _)
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "bye\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bench_tokenize_allocations_per_word() {
         let parser = Parser::new();
-        let tokens = parser.tokenize("echo \\\\");
-        assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("\\".into(), Quoting::Unquoted),
-            ]
-        );
+        let input = "/usr/share/some/file ".repeat(100_000);
+        let (tokens, allocations) = count_allocations(|| parser.tokenize(&input));
+        let words = tokens
+            .iter()
+            .filter(|token| matches!(token, Token::Word(..)))
+            .count();
+        println!("{} allocations for {} words", allocations, words);
+        assert_eq!(words, 100_000);
+        // one allocation per word plus growing the token vector
+        assert!(allocations < words + 100);
     }
 
+    // run with `cargo test --release -- --ignored --nocapture bench_`
     #[test]
-    fn test_escaping_newline() {
+    #[ignore]
+    fn bench_tokenize_100k_words() {
         let parser = Parser::new();
-        let tokens = parser.tokenize("echo \"\\n\"");
-        assert_eq!(
-            tokens,
-            vec![
-                Token::Word("echo".into(), Quoting::Unquoted),
-                Token::Whitespace,
-                Token::Word("\n".into(), Quoting::DoubleQuoted),
-            ]
-        );
+        let input = "word 'quoted word' \"double $HOME\" ".repeat(100_000 / 3);
+        let start = std::time::Instant::now();
+        let tokens = parser.tokenize(&input);
+        let elapsed = start.elapsed();
+        println!("tokenized {} tokens in {:?}", tokens.len(), elapsed);
     }
 }